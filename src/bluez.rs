@@ -0,0 +1,254 @@
+use crate::controller::ControllerType;
+use crate::l2cap::AsyncL2CAPStream;
+use crate::mgmt::{adapter_index, Discoverable, Mgmt};
+use crate::profile::HidProfile;
+use crate::relay::Channel;
+use crate::sdp::SdpRecord;
+use crate::BtAddr;
+
+use blurz::{BluetoothAdapter, BluetoothDevice, BluetoothDiscoverySession, BluetoothSession};
+use smol::Timer;
+use tracing::{debug, info};
+
+use std::error::Error;
+use std::time::Duration;
+
+/// How long to try connecting to a channel for before giving up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A local bluetooth adapter.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Adapter {
+    /// The `N` in `hciN`.
+    pub index: u16,
+    pub address: BtAddr,
+}
+
+impl Adapter {
+    pub fn name(&self) -> String {
+        format!("hci{}", self.index)
+    }
+
+    /// Where BlueZ exports the adapter, and the devices seen through it.
+    pub fn dbus_path(&self) -> String {
+        format!("/org/bluez/{}", self.name())
+    }
+}
+
+impl std::fmt::Display for Adapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name(), self.address)
+    }
+}
+
+/// Picks an adapter by name, like `hci1`, or by address. Without either, the first adapter the
+/// kernel lists is used.
+pub fn find_adapter(selector: Option<&str>) -> Result<Adapter, Box<dyn Error>> {
+    let mut mgmt = Mgmt::open()?;
+
+    let mut adapters = Vec::new();
+
+    for index in mgmt.read_index_list()? {
+        let address = mgmt.read_info(index)?.address;
+        adapters.push(Adapter { index, address });
+    }
+
+    let adapter = match selector {
+        None => adapters.first(),
+        Some(selector) => match (adapter_index(selector), selector.parse::<BtAddr>()) {
+            (Some(index), _) => adapters.iter().find(|a| a.index == index),
+            (None, Ok(address)) => adapters.iter().find(|a| a.address == address),
+            (None, Err(_)) => {
+                return Err(format!("'{}' isn't an adapter name or address", selector).into())
+            }
+        },
+    };
+
+    match adapter {
+        Some(adapter) => Ok(*adapter),
+        None => Err(format!("no adapter {}", selector.unwrap_or("found")).into()),
+    }
+}
+
+/// Opens `adapter` through BlueZ's D-Bus API.
+pub fn open_adapter<'a>(
+    session: &'a BluetoothSession,
+    adapter: &Adapter,
+) -> Result<BluetoothAdapter<'a>, Box<dyn Error>> {
+    BluetoothAdapter::create_adapter(session, adapter.dbus_path())
+        .map_err(|e| format!("could not open adapter {}: {}", adapter, e).into())
+}
+
+/// Scans until a controller shows up. If `address` is given only that device will do, otherwise
+/// any device whose alias is one of [`ControllerType`]'s names.
+///
+/// This can wait forever, so run it under [`CtrlC::until`](crate::ctrl_c::CtrlC::until). If it's
+/// dropped halfway, bluetoothd stops the discovery itself once we exit.
+pub async fn scan_for_bluetooth_controller<'a>(
+    session: &'a BluetoothSession,
+    adapter: &'a BluetoothAdapter<'_>,
+    address: Option<BtAddr>,
+) -> Result<BluetoothDevice<'a>, Box<dyn Error>> {
+    let discovery = BluetoothDiscoverySession::create_session(session, adapter.get_id())?;
+    discovery.start_discovery()?;
+
+    info!("Scanning for controllers");
+
+    let bt_controller = 'outer_loop: loop {
+        let devices = adapter.get_device_list()?;
+
+        'device_loop: for device in devices {
+            let bt_device = blurz::bluetooth_device::BluetoothDevice::new(session, device);
+
+            let id = bt_device.get_id();
+            let rssi = bt_device.get_rssi();
+            let alias = bt_device.get_alias().unwrap_or_default();
+
+            if rssi.is_ok() {
+                debug!(%alias, %id, "Found a device");
+            } else {
+                continue 'device_loop;
+            }
+
+            let wanted = match address {
+                Some(address) => device_address(&bt_device) == Some(address),
+                None => ControllerType::from_name(&alias).is_some(),
+            };
+
+            if wanted {
+                info!(%alias, "Found the controller");
+
+                discovery.stop_discovery()?;
+
+                break 'outer_loop bt_device;
+            }
+        }
+
+        Timer::after(Duration::from_secs(5)).await;
+    };
+
+    Ok(bt_controller)
+}
+
+/// Scans for `duration` and returns every controller seen, by alias, along with its address.
+pub fn scan_for_controllers(
+    session: &BluetoothSession,
+    adapter: &BluetoothAdapter,
+    duration: Duration,
+) -> Result<Vec<(BtAddr, ControllerType)>, Box<dyn Error>> {
+    let discovery = BluetoothDiscoverySession::create_session(session, adapter.get_id())?;
+    discovery.start_discovery()?;
+
+    std::thread::sleep(duration);
+
+    let mut controllers = Vec::new();
+
+    for device in adapter.get_device_list()? {
+        let bt_device = BluetoothDevice::new(session, device);
+
+        if bt_device.get_rssi().is_err() {
+            continue;
+        }
+
+        let controller = ControllerType::from_name(&bt_device.get_alias().unwrap_or_default());
+
+        if let (Some(address), Some(controller)) = (device_address(&bt_device), controller) {
+            controllers.push((address, controller));
+        }
+    }
+
+    discovery.stop_discovery()?;
+
+    Ok(controllers)
+}
+
+fn device_address(device: &BluetoothDevice) -> Option<BtAddr> {
+    device.get_address().ok()?.parse().ok()
+}
+
+/// Connects to both HID channels of the device at `address`, through `adapter`. Returns the ctl
+/// and itr streams, in that order.
+pub async fn connect_hid(
+    adapter: &Adapter,
+    address: BtAddr,
+) -> std::io::Result<(AsyncL2CAPStream, AsyncL2CAPStream)> {
+    let ctl = AsyncL2CAPStream::connect(
+        adapter.address,
+        address,
+        Channel::Ctl.psm(),
+        CONNECT_TIMEOUT,
+    )
+    .await?;
+
+    let itr = AsyncL2CAPStream::connect(
+        adapter.address,
+        address,
+        Channel::Itr.psm(),
+        CONNECT_TIMEOUT,
+    )
+    .await?;
+
+    Ok((ctl, itr))
+}
+
+/// Connects to a Switch we've already paired with, the way a real controller reconnects, so
+/// nobody has to open the "Change Grip/Order" menu. bluetoothd still has the link key from
+/// pairing, so the adapter needs no setting up. Returns the ctl and itr streams, in that order.
+pub async fn reconnect_to_switch(
+    adapter: &Adapter,
+    switch: BtAddr,
+) -> Result<(AsyncL2CAPStream, AsyncL2CAPStream), Box<dyn Error>> {
+    info!(%switch, "Reconnecting to the Switch");
+
+    match connect_hid(adapter, switch).await {
+        Ok(streams) => {
+            info!(%switch, "Connected to the Switch");
+            Ok(streams)
+        }
+        Err(e) => Err(format!("could not reconnect to switch at {}: {}", switch, e).into()),
+    }
+}
+
+/// Advertises ourselves as `name`, with the SDP record `record`, on `adapter` and waits for
+/// the Switch to connect to both channels. Returns the ctl and itr streams, in that order, along
+/// with the profile BlueZ handed them to us through, which has to be kept around for BlueZ to be
+/// able to ask us to disconnect.
+pub async fn wait_for_switch(
+    adapter: &Adapter,
+    name: &str,
+    record: &SdpRecord,
+) -> Result<(AsyncL2CAPStream, AsyncL2CAPStream, HidProfile), Box<dyn Error>> {
+    let index = adapter.index;
+
+    info!(%name, "Changing name and class");
+
+    let mut mgmt = Mgmt::open()?;
+
+    mgmt.set_powered(index, true)?;
+    mgmt.set_local_name(index, name)?;
+    // Peripheral, gamepad
+    mgmt.set_device_class(index, 0x05, 0x08)?;
+
+    info!("Advertising the Bluetooth SDP record");
+
+    let mut profile = HidProfile::register(record, adapter)?;
+
+    mgmt.set_connectable(index, true)?;
+    mgmt.set_bondable(index, true)?;
+    mgmt.set_discoverable(index, Discoverable::General, 0)?;
+
+    info!("Waiting for the Switch. Please open the \"Change Grip/Order\" menu.");
+
+    let (address, switch_ctl_l2cap, switch_itr_l2cap) = profile
+        .accept()
+        .await
+        .ok_or("BlueZ released the HID profile before the Switch connected")?;
+
+    info!(switch = %address, "Connected to the Switch");
+
+    Ok((
+        AsyncL2CAPStream::new(switch_ctl_l2cap)?,
+        AsyncL2CAPStream::new(switch_itr_l2cap)?,
+        profile,
+    ))
+}
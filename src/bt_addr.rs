@@ -0,0 +1,77 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A bluetooth device address, in the order it's displayed in (most significant byte first).
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct BtAddr(pub [u8; 6]);
+
+impl BtAddr {
+    /// Stands for every local adapter when binding.
+    pub const ANY: BtAddr = BtAddr([0; 6]);
+
+    /// Linux lower-layers actually hold the address in native byte-order
+    /// althrough they are always displayed in network byte-order
+    #[inline(always)]
+    #[cfg(target_endian = "little")]
+    pub fn convert_host_byteorder(mut self) -> BtAddr {
+        {
+            let (value_1, value_2) = self.0.split_at_mut(3);
+            std::mem::swap(&mut value_1[0], &mut value_2[2]);
+            std::mem::swap(&mut value_1[1], &mut value_2[1]);
+            std::mem::swap(&mut value_1[2], &mut value_2[0]);
+        }
+
+        self
+    }
+
+    #[inline(always)]
+    #[cfg(target_endian = "big")]
+    pub fn convert_host_byteorder(self) -> BtAddr {
+        // Public address structure contents are always big-endian
+        self
+    }
+}
+
+/// Returned when an address isn't of the form `XX:XX:XX:XX:XX:XX`.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseBtAddrError(String);
+
+impl fmt::Display for ParseBtAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid bluetooth address '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseBtAddrError {}
+
+impl FromStr for BtAddr {
+    type Err = ParseBtAddrError;
+
+    fn from_str(addr_str: &str) -> Result<BtAddr, ParseBtAddrError> {
+        let error = || ParseBtAddrError(addr_str.to_string());
+
+        let mut addr = [0; 6];
+        let mut parts = addr_str.split(':');
+
+        for byte in addr.iter_mut() {
+            let part = parts.next().filter(|p| p.len() == 2).ok_or_else(error)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| error())?;
+        }
+
+        if parts.next().is_some() {
+            return Err(error());
+        }
+
+        Ok(BtAddr(addr))
+    }
+}
+
+impl fmt::Display for BtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
+        )
+    }
+}
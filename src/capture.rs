@@ -0,0 +1,491 @@
+//! Records every packet on the HID channels to a btsnoop file, which Wireshark opens as HCI H4
+//! traffic.
+//!
+//! We never see the HCI or L2CAP layers below the channels, so they're made up: both channels
+//! share one ACL link, and the file starts with the L2CAP signalling that would've opened them.
+//! That's all Wireshark needs to know that PSM 17 and 19 carry HID, and decode them as such.
+//!
+//! [`load`] reads our own captures back, for replaying them.
+
+use crate::relay::{Channel, Direction};
+
+use tracing::error;
+
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BTSNOOP_MAGIC: &[u8; 8] = b"btsnoop\0";
+const BTSNOOP_VERSION: u32 = 1;
+/// HCI UART (H4), every packet starts with its type.
+const BTSNOOP_DATALINK_H4: u32 = 1002;
+/// btsnoop timestamps count microseconds from midnight, January 1st, 0 AD.
+const BTSNOOP_EPOCH_OFFSET: u64 = 0x00dc_ddb3_0f2f_8000;
+
+/// Set in the record flags for packets the controller received, that is, from the Switch.
+const FLAG_RECEIVED: u32 = 0x01;
+
+const H4_ACL_DATA: u8 = 0x02;
+/// The made-up ACL link everything goes over.
+const ACL_HANDLE: u16 = 0x0001;
+/// Packet boundary flag for a complete, automatically flushable L2CAP packet.
+const ACL_START: u16 = 0x2000;
+
+const L2CAP_SIGNALLING_CID: u16 = 0x0001;
+const L2CAP_CONNECTION_REQUEST: u8 = 0x02;
+const L2CAP_CONNECTION_RESPONSE: u8 = 0x03;
+
+/// A packet read back from a capture.
+#[derive(Debug, Clone)]
+pub struct CapturedPacket {
+    /// How long after the first packet on either channel this was recorded. The signalling
+    /// before it is written when the capture starts, which can be long before anything connects.
+    pub time: Duration,
+    pub channel: Channel,
+    pub direction: Direction,
+    /// HIDP header and all.
+    pub packet: Vec<u8>,
+}
+
+/// A capture file that's written to from every channel at once. Clones write to the same file,
+/// which is flushed once the last clone is dropped.
+#[derive(Clone)]
+pub struct Capture {
+    inner: Arc<Mutex<CaptureWriter>>,
+}
+
+struct CaptureWriter {
+    writer: Box<dyn Write + Send>,
+    /// Set once a write fails, after which the capture is given up on.
+    failed: bool,
+}
+
+impl Capture {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Capture> {
+        Capture::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Starts a capture on `writer`, with the file header and the signalling that opens both
+    /// channels.
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Result<Capture> {
+        writer.write_all(BTSNOOP_MAGIC)?;
+        writer.write_all(&BTSNOOP_VERSION.to_be_bytes())?;
+        writer.write_all(&BTSNOOP_DATALINK_H4.to_be_bytes())?;
+
+        let mut capture = CaptureWriter {
+            writer: Box::new(writer),
+            failed: false,
+        };
+
+        // The Switch opens both channels, as it does when it pairs
+        for (identifier, &channel) in [Channel::Ctl, Channel::Itr].iter().enumerate() {
+            let identifier = identifier as u8 + 1;
+            let cid = channel_id(channel);
+
+            let mut request = vec![L2CAP_CONNECTION_REQUEST, identifier, 4, 0];
+            request.extend_from_slice(&channel.psm().to_le_bytes());
+            request.extend_from_slice(&cid.to_le_bytes());
+
+            let mut response = vec![L2CAP_CONNECTION_RESPONSE, identifier, 8, 0];
+            // Destination and source channel, then a successful result and no status
+            response.extend_from_slice(&cid.to_le_bytes());
+            response.extend_from_slice(&cid.to_le_bytes());
+            response.extend_from_slice(&[0, 0, 0, 0]);
+
+            capture.write_record(
+                L2CAP_SIGNALLING_CID,
+                Direction::SwitchToController,
+                &request,
+            )?;
+            capture.write_record(
+                L2CAP_SIGNALLING_CID,
+                Direction::ControllerToSwitch,
+                &response,
+            )?;
+        }
+
+        Ok(Capture {
+            inner: Arc::new(Mutex::new(capture)),
+        })
+    }
+
+    /// Adds `packet`, HIDP header and all, as seen on `channel` right now.
+    pub fn record(&self, channel: Channel, direction: Direction, packet: &[u8]) {
+        let mut capture = self.inner.lock().unwrap();
+
+        if capture.failed {
+            return;
+        }
+
+        if let Err(e) = capture.write_record(channel_id(channel), direction, packet) {
+            error!(error = %e, "Could not write to the capture, no longer capturing");
+            capture.failed = true;
+        }
+    }
+}
+
+impl CaptureWriter {
+    fn write_record(&mut self, cid: u16, direction: Direction, payload: &[u8]) -> Result<()> {
+        let l2cap_len = payload.len() as u16;
+        let acl_len = l2cap_len + 4;
+
+        let mut packet = Vec::with_capacity(9 + payload.len());
+        packet.push(H4_ACL_DATA);
+        packet.extend_from_slice(&(ACL_HANDLE | ACL_START).to_le_bytes());
+        packet.extend_from_slice(&acl_len.to_le_bytes());
+        packet.extend_from_slice(&l2cap_len.to_le_bytes());
+        packet.extend_from_slice(&cid.to_le_bytes());
+        packet.extend_from_slice(payload);
+
+        let flags = match direction {
+            Direction::SwitchToController => FLAG_RECEIVED,
+            Direction::ControllerToSwitch => 0,
+        };
+
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+
+        let len = packet.len() as u32;
+
+        // Original and included length, flags, and no dropped packets
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&len.to_be_bytes())?;
+        self.writer.write_all(&flags.to_be_bytes())?;
+        self.writer.write_all(&0u32.to_be_bytes())?;
+        self.writer
+            .write_all(&(since_unix + BTSNOOP_EPOCH_OFFSET).to_be_bytes())?;
+        self.writer.write_all(&packet)
+    }
+}
+
+/// Reads back every packet on either channel from a capture written by [`Capture`], in the order
+/// they were recorded. Anything else in the file, like the signalling, is skipped.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<CapturedPacket>> {
+    read(File::open(path)?)
+}
+
+/// Like [`load`], from anything a capture was written to.
+pub fn read<R: Read>(mut reader: R) -> Result<Vec<CapturedPacket>> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+
+    if data.len() < 16 || &data[..8] != BTSNOOP_MAGIC {
+        return Err(invalid_capture("not a btsnoop file"));
+    }
+
+    if be_u32(&data[12..16]) != BTSNOOP_DATALINK_H4 {
+        return Err(invalid_capture("not an HCI H4 capture"));
+    }
+
+    let mut packets = Vec::new();
+    let mut first_timestamp = None;
+    let mut records = &data[16..];
+
+    while !records.is_empty() {
+        if records.len() < 24 {
+            return Err(invalid_capture("last record is cut short"));
+        }
+
+        let included_len = be_u32(&records[4..8]) as usize;
+        let flags = be_u32(&records[8..12]);
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&records[16..24]);
+        let timestamp = u64::from_be_bytes(timestamp);
+
+        let packet = records
+            .get(24..24 + included_len)
+            .ok_or_else(|| invalid_capture("last record is cut short"))?;
+        records = &records[24 + included_len..];
+
+        // H4 type, ACL header, then the L2CAP header
+        if packet.len() < 9 || packet[0] != H4_ACL_DATA {
+            continue;
+        }
+
+        let channel = match le_u16(&packet[7..9]) {
+            cid if cid == channel_id(Channel::Ctl) => Channel::Ctl,
+            cid if cid == channel_id(Channel::Itr) => Channel::Itr,
+            _ => continue,
+        };
+
+        let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+
+        let direction = if flags & FLAG_RECEIVED != 0 {
+            Direction::SwitchToController
+        } else {
+            Direction::ControllerToSwitch
+        };
+
+        packets.push(CapturedPacket {
+            time: Duration::from_micros(timestamp.saturating_sub(first_timestamp)),
+            channel,
+            direction,
+            packet: packet[9..].to_vec(),
+        });
+    }
+
+    Ok(packets)
+}
+
+fn invalid_capture(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn le_u16(bytes: &[u8]) -> u16 {
+    u16::from_le_bytes([bytes[0], bytes[1]])
+}
+
+/// The L2CAP channel id both ends use for `channel`, in the first range that's free to use.
+fn channel_id(channel: Channel) -> u16 {
+    match channel {
+        Channel::Ctl => 0x0040,
+        Channel::Itr => 0x0041,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Somewhere to write a capture and still get at it afterwards.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A capture of `packets`, recorded in order.
+    fn capture(packets: &[(Channel, Direction, &[u8])]) -> Vec<u8> {
+        let buffer = SharedBuffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+
+        for &(channel, direction, packet) in packets {
+            capture.record(channel, direction, packet);
+        }
+
+        let data = buffer.0.lock().unwrap().clone();
+        data
+    }
+
+    /// The flags and H4 packet of every record, skipping the file header.
+    fn records(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut records = Vec::new();
+        let mut rest = &data[16..];
+
+        while !rest.is_empty() {
+            let len = be_u32(&rest[4..8]) as usize;
+            records.push((be_u32(&rest[8..12]), rest[24..24 + len].to_vec()));
+            rest = &rest[24 + len..];
+        }
+
+        records
+    }
+
+    fn error_message(data: &[u8]) -> String {
+        let error = read(data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        error.to_string()
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let packets: &[(Channel, Direction, &[u8])] = &[
+            (Channel::Ctl, Direction::SwitchToController, &[0x71]),
+            (Channel::Ctl, Direction::ControllerToSwitch, &[0x00]),
+            (
+                Channel::Itr,
+                Direction::SwitchToController,
+                &[0xA2, 0x01, 0x00],
+            ),
+            (
+                Channel::Itr,
+                Direction::ControllerToSwitch,
+                &[0xA1, 0x21, 0x05],
+            ),
+            (Channel::Itr, Direction::ControllerToSwitch, &[]),
+        ];
+
+        let read_back = read(&capture(packets)[..]).unwrap();
+
+        assert_eq!(read_back.len(), packets.len());
+        assert_eq!(read_back[0].time, Duration::from_secs(0));
+
+        for (read_back, &(channel, direction, packet)) in read_back.iter().zip(packets) {
+            assert_eq!(read_back.channel, channel);
+            assert_eq!(read_back.direction, direction);
+            assert_eq!(read_back.packet, packet);
+        }
+    }
+
+    #[test]
+    fn writes_the_file_header_and_signalling() {
+        let data = capture(&[]);
+
+        assert_eq!(&data[..8], BTSNOOP_MAGIC);
+        assert_eq!(be_u32(&data[8..12]), BTSNOOP_VERSION);
+        assert_eq!(be_u32(&data[12..16]), BTSNOOP_DATALINK_H4);
+
+        // Over the signalling channel, the Switch asks to open ctl and then itr
+        let acl_header = |len: u8| {
+            vec![
+                H4_ACL_DATA,
+                0x01,
+                0x20,
+                len + 4,
+                0x00,
+                len,
+                0x00,
+                0x01,
+                0x00,
+            ]
+        };
+        let signalling = |payload: &[u8]| {
+            let mut packet = acl_header(payload.len() as u8);
+            packet.extend_from_slice(payload);
+            packet
+        };
+
+        assert_eq!(
+            records(&data),
+            vec![
+                (
+                    FLAG_RECEIVED,
+                    signalling(&[0x02, 0x01, 0x04, 0x00, 0x11, 0x00, 0x40, 0x00])
+                ),
+                (
+                    0,
+                    signalling(&[
+                        0x03, 0x01, 0x08, 0x00, 0x40, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00
+                    ])
+                ),
+                (
+                    FLAG_RECEIVED,
+                    signalling(&[0x02, 0x02, 0x04, 0x00, 0x13, 0x00, 0x41, 0x00])
+                ),
+                (
+                    0,
+                    signalling(&[
+                        0x03, 0x02, 0x08, 0x00, 0x41, 0x00, 0x41, 0x00, 0x00, 0x00, 0x00, 0x00
+                    ])
+                ),
+            ]
+        );
+
+        // None of which is read back
+        assert!(read(&data[..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn flags_packets_from_the_switch_as_received() {
+        let data = capture(&[
+            (Channel::Itr, Direction::SwitchToController, &[0xA2, 0x10]),
+            (Channel::Ctl, Direction::ControllerToSwitch, &[0x00]),
+        ]);
+        let records = records(&data);
+
+        assert_eq!(
+            records[4],
+            (
+                FLAG_RECEIVED,
+                vec![
+                    H4_ACL_DATA,
+                    0x01,
+                    0x20,
+                    0x06,
+                    0x00,
+                    0x02,
+                    0x00,
+                    0x41,
+                    0x00,
+                    0xA2,
+                    0x10
+                ]
+            )
+        );
+        assert_eq!(
+            records[5],
+            (
+                0,
+                vec![
+                    H4_ACL_DATA,
+                    0x01,
+                    0x20,
+                    0x05,
+                    0x00,
+                    0x01,
+                    0x00,
+                    0x40,
+                    0x00,
+                    0x00
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn skips_records_on_other_channels() {
+        let mut data = capture(&[(Channel::Itr, Direction::ControllerToSwitch, &[0xA1, 0x30])]);
+
+        // An HCI event, and ACL data on a channel we never use
+        for packet in &[
+            &[0x04, 0x0E, 0x00][..],
+            &[H4_ACL_DATA, 0x01, 0x20, 0x04, 0x00, 0x00, 0x00, 0x42, 0x00],
+        ] {
+            let len = packet.len() as u32;
+            data.extend_from_slice(&len.to_be_bytes());
+            data.extend_from_slice(&len.to_be_bytes());
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(packet);
+        }
+
+        let packets = read(&data[..]).unwrap();
+        assert_eq!(packets.len(), 1);
+        assert_eq!(packets[0].packet, vec![0xA1, 0x30]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(error_message(&[]), "not a btsnoop file");
+        assert_eq!(error_message(b"btsnoop\0"), "not a btsnoop file");
+        assert_eq!(error_message(&[0; 64]), "not a btsnoop file");
+
+        let mut data = capture(&[]);
+        data[12..16].copy_from_slice(&1001u32.to_be_bytes());
+        assert_eq!(error_message(&data), "not an HCI H4 capture");
+    }
+
+    #[test]
+    fn rejects_captures_cut_short() {
+        let data = capture(&[(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &[0xA1, 0x30, 0x00],
+        )]);
+
+        // In the middle of the last packet, and of its record header
+        assert_eq!(
+            error_message(&data[..data.len() - 1]),
+            "last record is cut short"
+        );
+        assert_eq!(
+            error_message(&data[..data.len() - 20]),
+            "last record is cut short"
+        );
+    }
+}
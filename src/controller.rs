@@ -0,0 +1,87 @@
+/// The kinds of controllers the Switch knows about.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ControllerType {
+    JoyConL,
+    JoyConR,
+    ProController,
+}
+
+impl ControllerType {
+    pub const ALL: [ControllerType; 3] = [
+        ControllerType::ProController,
+        ControllerType::JoyConL,
+        ControllerType::JoyConR,
+    ];
+
+    /// The bluetooth alias the real controller advertises itself with.
+    pub fn name(self) -> &'static str {
+        match self {
+            ControllerType::JoyConL => "Joy-Con (L)",
+            ControllerType::JoyConR => "Joy-Con (R)",
+            ControllerType::ProController => "Pro Controller",
+        }
+    }
+
+    /// The controller type byte sent in the device info reply and stored in SPI flash.
+    pub fn id(self) -> u8 {
+        match self {
+            ControllerType::JoyConL => 0x01,
+            ControllerType::JoyConR => 0x02,
+            ControllerType::ProController => 0x03,
+        }
+    }
+
+    /// Short name used on the command line.
+    pub fn short_name(self) -> &'static str {
+        match self {
+            ControllerType::JoyConL => "joycon-l",
+            ControllerType::JoyConR => "joycon-r",
+            ControllerType::ProController => "pro",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ControllerType> {
+        ControllerType::ALL
+            .iter()
+            .copied()
+            .find(|c| c.name() == name)
+    }
+
+    pub fn from_id(id: u8) -> Option<ControllerType> {
+        ControllerType::ALL.iter().copied().find(|c| c.id() == id)
+    }
+}
+
+/// Returned when a string isn't one of [`ControllerType::short_name`]'s.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseControllerTypeError(String);
+
+impl std::fmt::Display for ParseControllerTypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unknown controller type '{}', expected pro, joycon-l or joycon-r",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for ParseControllerTypeError {}
+
+impl std::str::FromStr for ControllerType {
+    type Err = ParseControllerTypeError;
+
+    fn from_str(s: &str) -> Result<ControllerType, ParseControllerTypeError> {
+        ControllerType::ALL
+            .iter()
+            .copied()
+            .find(|c| c.short_name() == s)
+            .ok_or_else(|| ParseControllerTypeError(s.to_string()))
+    }
+}
+
+impl std::fmt::Display for ControllerType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
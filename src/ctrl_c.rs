@@ -0,0 +1,150 @@
+//! Ctrl-C as something to wait on, alongside whatever else we're waiting on.
+//!
+//! The signal handler writes a byte to one end of a socket pair, and waiting reads it from the
+//! other end, which is about all a signal handler can safely do.
+
+use crate::smol_fd::libc_check_error;
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use smol::Async;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Where the handler writes to, or -1 when nothing is catching Ctrl-C.
+static SIGNAL_FD: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    let fd = SIGNAL_FD.load(Ordering::Relaxed);
+
+    if fd >= 0 {
+        unsafe { libc::write(fd, [0u8].as_ptr() as *const libc::c_void, 1) };
+    }
+}
+
+/// Returned by [`CtrlC::until`] when Ctrl-C is pressed.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "interrupted by Ctrl-C")
+    }
+}
+
+impl Error for Interrupted {}
+
+/// Catches Ctrl-C for as long as it's alive, instead of letting it kill the process. Only one
+/// should be alive at a time.
+pub struct CtrlC {
+    receiver: Async<UnixStream>,
+    sender: UnixStream,
+}
+
+impl CtrlC {
+    pub fn catch() -> io::Result<CtrlC> {
+        let (sender, receiver) = UnixStream::pair()?;
+
+        // The handler mustn't block if Ctrl-C is pressed a few times before anyone waits
+        sender.set_nonblocking(true)?;
+
+        SIGNAL_FD.store(sender.as_raw_fd(), Ordering::Relaxed);
+        set_handler(on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t)?;
+
+        Ok(CtrlC {
+            receiver: Async::new(receiver)?,
+            sender,
+        })
+    }
+
+    /// Waits for the next Ctrl-C.
+    pub async fn wait(&self) -> io::Result<()> {
+        let mut byte = [0u8];
+        (&self.receiver).read_exact(&mut byte).await
+    }
+
+    /// Runs `future` to the end, unless Ctrl-C is pressed first, in which case it's dropped and
+    /// [`Interrupted`] is returned instead.
+    pub async fn until<F, T>(&self, future: F) -> Result<T, Box<dyn Error>>
+    where
+        F: Future<Output = Result<T, Box<dyn Error>>>,
+    {
+        futures::pin_mut!(future);
+
+        let interrupted = self.wait();
+        futures::pin_mut!(interrupted);
+
+        match future::select(future, interrupted).await {
+            Either::Left((result, _)) => result,
+            Either::Right((Ok(()), _)) => Err(Interrupted.into()),
+            Either::Right((Err(e), _)) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for CtrlC {
+    fn drop(&mut self) {
+        let _ = set_handler(libc::SIG_DFL);
+
+        // Only stop the handler if it's still writing to us
+        let _ = SIGNAL_FD.compare_exchange(
+            self.sender.as_raw_fd(),
+            -1,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Whether `error` is what [`CtrlC::until`] returns on Ctrl-C.
+pub fn is_interrupted(error: &(dyn Error + 'static)) -> bool {
+    error.is::<Interrupted>()
+}
+
+fn set_handler(handler: libc::sighandler_t) -> io::Result<()> {
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handler;
+    // Anything blocking in another thread, like D-Bus, carries on as if nothing happened
+    action.sa_flags = libc::SA_RESTART;
+
+    libc_check_error(unsafe { libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut()) })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_ctrl_c_counts_as_interrupted() {
+        let interrupted: Box<dyn Error> = Interrupted.into();
+        assert!(is_interrupted(&*interrupted));
+
+        // Like a read cut short by some other signal
+        let io_error: Box<dyn Error> = io::Error::from(io::ErrorKind::Interrupted).into();
+        assert!(!is_interrupted(&*io_error));
+    }
+
+    // One test, since only one CtrlC should be alive at a time
+    #[test]
+    fn until_stops_at_ctrl_c_and_only_then() {
+        let ctrl_c = CtrlC::catch().unwrap();
+
+        let result = smol::run(ctrl_c.until(async { Ok(5) }));
+        assert_eq!(result.unwrap(), 5);
+
+        let result = smol::run(ctrl_c.until(async { Err::<(), _>("failed".into()) }));
+        assert!(!is_interrupted(&*result.unwrap_err()));
+
+        unsafe { libc::raise(libc::SIGINT) };
+
+        let result = smol::run(ctrl_c.until(future::pending::<Result<(), Box<dyn Error>>>()));
+        assert!(is_interrupted(&*result.unwrap_err()));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::capture::Capture;
+use crate::controller::ControllerType;
+use crate::hid_descriptor::{DescriptorError, ReportDescriptor, ReportKind};
+use crate::input_report::{
+    Buttons, ImuFrame, InputReport, ReportBody, StandardInputReport, StickData, STANDARD_REPORT_LEN,
+};
+use crate::output_report::{OutputReport, OUTPUT_REPORT_LEN};
+use crate::relay::{Channel, Direction};
+use crate::spi_flash::SpiFlash;
+use crate::subcommand::{Subcommand, SubcommandReply, SPI_MAX_TRANSFER};
+use crate::BtAddr;
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use smol::Timer;
+use tracing::{debug, info, warn};
+
+use std::io::Result;
+use std::time::Duration;
+
+/// Once the Switch asks for standard full reports we send them at 60Hz.
+const FULL_REPORT_PERIOD: Duration = Duration::from_micros(16_666);
+/// Until then a report every second is enough to let the Switch know we're alive.
+const IDLE_REPORT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Every report the emulator sends or understands, with its length not counting the report id.
+pub const REPORTS: &[(ReportKind, u8, usize)] = &[
+    (ReportKind::Input, 0x21, STANDARD_REPORT_LEN - 1),
+    (ReportKind::Input, 0x30, STANDARD_REPORT_LEN - 1),
+    (ReportKind::Output, 0x01, OUTPUT_REPORT_LEN - 1),
+    (ReportKind::Output, 0x10, OUTPUT_REPORT_LEN - 1),
+];
+
+/// Pretends to be a controller, answering the Switch on the itr channel.
+pub struct Emulator {
+    controller: ControllerType,
+    address: BtAddr,
+    spi_flash: SpiFlash,
+    timer: u8,
+    buttons: Buttons,
+    left_stick: StickData,
+    right_stick: StickData,
+    input_mode: Option<u8>,
+    player_lights: u8,
+    imu_enabled: bool,
+    vibration_enabled: bool,
+    capture: Option<Capture>,
+}
+
+impl Emulator {
+    /// `address` is the local adapter's address, which is what we report as the controller's.
+    pub fn new(controller: ControllerType, address: BtAddr, spi_flash: SpiFlash) -> Emulator {
+        Emulator {
+            controller,
+            address,
+            spi_flash,
+            timer: 0,
+            buttons: Buttons::default(),
+            left_stick: StickData::CENTER,
+            right_stick: StickData::CENTER,
+            input_mode: None,
+            player_lights: 0,
+            imu_enabled: false,
+            vibration_enabled: false,
+            capture: None,
+        }
+    }
+
+    /// Checks that `descriptor` declares every report in [`REPORTS`], long enough to hold them.
+    /// The Switch ignores reports that don't fit what's advertised, which otherwise shows up as
+    /// the emulator being silently ignored.
+    pub fn check_descriptor(
+        descriptor: &ReportDescriptor,
+    ) -> std::result::Result<(), DescriptorError> {
+        for &(kind, id, len) in REPORTS {
+            descriptor.check_report(kind, id, len)?;
+        }
+
+        Ok(())
+    }
+
+    /// Starts off in `mode` as if the Switch had asked for it, for when it won't.
+    pub fn set_input_mode(&mut self, mode: u8) {
+        self.input_mode = Some(mode);
+    }
+
+    /// Records every packet on the itr channel to `capture`.
+    pub fn set_capture(&mut self, capture: Capture) {
+        self.capture = Some(capture);
+    }
+
+    /// Saves whatever the Switch wrote to the SPI flash back to the dump file it was loaded from,
+    /// if any. Rewriting all 512KiB on every write would hold up the reports, so this is left
+    /// for when we're done.
+    pub fn save_spi_flash(&mut self) -> Result<()> {
+        self.spi_flash.flush()
+    }
+
+    /// Answers the Switch on the itr channel until it closes the connection.
+    pub async fn run<R, W>(&mut self, mut itr_r: R, mut itr_w: W) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut switch_incoming = [0u8; 128];
+
+        let mut sw_r = itr_r.read(&mut switch_incoming);
+        let mut next_report = Timer::after(IDLE_REPORT_PERIOD);
+
+        loop {
+            match future::select(sw_r, next_report).await {
+                // Read successfully from switch
+                Either::Left((Ok(n), old_next_report)) => {
+                    if n == 0 {
+                        info!("Switch closed itr");
+                        break;
+                    }
+
+                    self.record(Direction::SwitchToController, &switch_incoming[..n]);
+
+                    let period = self.report_period();
+
+                    if let Some(reply) = self.handle_output_report(&switch_incoming[..n]) {
+                        self.record(Direction::ControllerToSwitch, &reply);
+                        itr_w.write_all(&reply).await?;
+                    }
+
+                    // Don't sit out the rest of an idle period once full reports are asked for
+                    next_report = if self.report_period() == period {
+                        old_next_report
+                    } else {
+                        Timer::after(self.report_period())
+                    };
+
+                    sw_r = itr_r.read(&mut switch_incoming);
+                }
+
+                // Read failed from switch
+                Either::Left((Err(e), _old_next_report)) => {
+                    warn!(error = %e, "Read from switch failed");
+                    return Err(e);
+                }
+
+                // Time to send another input report
+                Either::Right((_, old_sw_r)) => {
+                    let imu = ReportBody::Imu {
+                        frames: [ImuFrame::default(); 3],
+                        extra: vec![],
+                    };
+
+                    let report = self.input_report(0x30, imu);
+                    self.record(Direction::ControllerToSwitch, &report);
+                    itr_w.write_all(&report).await?;
+
+                    sw_r = old_sw_r;
+                    next_report = Timer::after(self.report_period());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, direction: Direction, packet: &[u8]) {
+        if let Some(capture) = &self.capture {
+            capture.record(Channel::Itr, direction, packet);
+        }
+    }
+
+    fn report_period(&self) -> Duration {
+        if self.input_mode == Some(0x30) {
+            FULL_REPORT_PERIOD
+        } else {
+            IDLE_REPORT_PERIOD
+        }
+    }
+
+    fn handle_output_report(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        match OutputReport::parse_packet(packet) {
+            Ok(OutputReport::RumbleAndSubcommand { subcommand, .. }) => {
+                debug!(?subcommand, "Switch sent a subcommand");
+
+                let reply = self.handle_subcommand(&subcommand);
+
+                Some(self.input_report(0x21, ReportBody::SubcommandReply(reply)))
+            }
+            // Rumble only, or something we don't support. Neither needs a reply.
+            Ok(_) => None,
+            Err(e) => {
+                warn!(error = %e, "Could not parse output report");
+                None
+            }
+        }
+    }
+
+    /// Builds an input report, HIDP header included, with the current controller state.
+    fn input_report(&mut self, id: u8, body: ReportBody) -> Vec<u8> {
+        let report = InputReport::Standard(StandardInputReport {
+            id,
+            timer: self.timer,
+            // Full battery, powered by the Switch
+            battery_connection: 0x8E,
+            buttons: self.buttons,
+            left_stick: self.left_stick,
+            right_stick: self.right_stick,
+            vibrator_report: 0x80,
+            body,
+        });
+
+        self.timer = self.timer.wrapping_add(1);
+
+        report.encode_packet()
+    }
+
+    fn handle_subcommand(&mut self, subcommand: &Subcommand) -> SubcommandReply {
+        let id = subcommand.id();
+
+        match *subcommand {
+            Subcommand::BluetoothManualPairing(_) => {
+                SubcommandReply::with_data(0x81, id, vec![0x03])
+            }
+
+            Subcommand::RequestDeviceInfo => {
+                SubcommandReply::device_info(self.controller, self.address)
+            }
+
+            Subcommand::SetInputReportMode(mode) => {
+                info!("Switch set input report mode to {:#04x}", mode);
+                self.input_mode = Some(mode);
+                SubcommandReply::ack(id)
+            }
+
+            Subcommand::TriggerButtonsElapsedTime => SubcommandReply::with_data(0x83, id, vec![]),
+
+            Subcommand::SpiFlashRead { address, length } => {
+                SubcommandReply::spi_flash_read(&self.spi_flash, address, length)
+            }
+
+            Subcommand::SpiFlashWrite { address, ref data } => {
+                let written =
+                    data.len() <= SPI_MAX_TRANSFER as usize && self.spi_flash.write(address, data);
+
+                self.spi_write_reply(id, written)
+            }
+
+            Subcommand::SpiSectorErase { address } => {
+                let erased = self.spi_flash.erase_sector(address);
+
+                self.spi_write_reply(id, erased)
+            }
+
+            Subcommand::SetNfcIrMcuConfig(_) => SubcommandReply::with_data(
+                0xA0,
+                id,
+                vec![0x01, 0x00, 0xFF, 0x00, 0x03, 0x00, 0x05, 0x01],
+            ),
+
+            Subcommand::SetPlayerLights(lights) => {
+                info!("Switch set player lights to {:08b}", lights);
+                self.player_lights = lights;
+                SubcommandReply::ack(id)
+            }
+
+            Subcommand::EnableImu(enabled) => {
+                self.imu_enabled = enabled;
+                SubcommandReply::ack(id)
+            }
+
+            Subcommand::EnableVibration(enabled) => {
+                self.vibration_enabled = enabled;
+                // Answered with 0x82 rather than a plain ACK, as joycontrol and nxbt do
+                SubcommandReply::with_data(0x82, id, vec![])
+            }
+
+            Subcommand::Unknown { id, .. } => {
+                warn!("Unknown subcommand {:#04x}, replying with a plain ACK", id);
+                SubcommandReply::ack(id)
+            }
+
+            // Shipment state, HCI state, MCU state, home light and IMU sensitivity
+            // have nothing to report back
+            _ => SubcommandReply::ack(id),
+        }
+    }
+
+    /// Tells the Switch whether the write went through. It's only saved to the dump file by
+    /// [`Emulator::save_spi_flash`].
+    fn spi_write_reply(&self, id: u8, success: bool) -> SubcommandReply {
+        let status = if success { 0x00 } else { 0x01 };
+
+        SubcommandReply::with_data(0x80, id, vec![status])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulator() -> Emulator {
+        let controller = ControllerType::ProController;
+        Emulator::new(controller, BtAddr([0; 6]), SpiFlash::new(controller))
+    }
+
+    #[test]
+    fn enabling_vibration_is_acked_with_0x82() {
+        let mut emulator = emulator();
+
+        let reply = emulator.handle_subcommand(&Subcommand::EnableVibration(true));
+
+        assert_eq!(reply, SubcommandReply::with_data(0x82, 0x48, vec![]));
+        assert!(emulator.vibration_enabled);
+    }
+}
@@ -0,0 +1,460 @@
+//! HID report descriptors, as advertised in the SDP record.
+//!
+//! A descriptor is a list of items. Global items (report id, size, count, ...) set state that
+//! sticks around, local items (usages) only last until the next main item, and main items
+//! (input, output, feature) declare the fields of a report using whatever state is current.
+//!
+//! The emulator checks its descriptor at startup against the reports it sends and understands.
+//! That leaves out 0x3F and 0x81: the Switch's own descriptor doesn't declare 0x3F at all,
+//! though controllers send it before pairing anyway, and the emulator never sends either.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Prefix of a long item, which no Switch controller uses but is still valid.
+const LONG_ITEM_PREFIX: u8 = 0xFE;
+
+/// One item of a report descriptor. Data is kept as given, sign extended where the HID spec says
+/// the value is signed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Item {
+    // Main items
+    Input(u32),
+    Output(u32),
+    Feature(u32),
+    Collection(u32),
+    EndCollection,
+
+    // Global items
+    UsagePage(u32),
+    LogicalMinimum(i32),
+    LogicalMaximum(i32),
+    PhysicalMinimum(i32),
+    PhysicalMaximum(i32),
+    UnitExponent(i32),
+    Unit(u32),
+    ReportSize(u32),
+    ReportId(u32),
+    ReportCount(u32),
+    Push,
+    Pop,
+
+    // Local items
+    Usage(u32),
+    UsageMinimum(u32),
+    UsageMaximum(u32),
+
+    /// Any other short item, by its prefix with the size bits cleared.
+    Other {
+        prefix: u8,
+        data: u32,
+    },
+    Long {
+        tag: u8,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DescriptorError {
+    /// An item claims more data than is left at `offset`.
+    Truncated { offset: usize },
+    /// A Pop without a matching Push, at `offset`.
+    UnbalancedPop { offset: usize },
+    /// A report id of 0, which is reserved, at `offset`.
+    ZeroReportId { offset: usize },
+    /// A report id that doesn't fit in the byte reports start with, at `offset`.
+    ReportIdTooLarge { offset: usize, id: u32 },
+    /// We send a report that isn't declared.
+    UndeclaredReport { kind: ReportKind, id: u8 },
+    /// We send a report that's longer than declared. Lengths are in bytes, without the id.
+    ReportTooLong {
+        kind: ReportKind,
+        id: u8,
+        len: usize,
+        declared: usize,
+    },
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::Truncated { offset } => {
+                write!(f, "item at offset {} runs past the end", offset)
+            }
+            DescriptorError::UnbalancedPop { offset } => {
+                write!(f, "pop without a push at offset {}", offset)
+            }
+            DescriptorError::ZeroReportId { offset } => {
+                write!(f, "report id 0 at offset {}", offset)
+            }
+            DescriptorError::ReportIdTooLarge { offset, id } => {
+                write!(f, "report id {:#x} at offset {} is over 0xff", id, offset)
+            }
+            DescriptorError::UndeclaredReport { kind, id } => {
+                write!(f, "{} report {:#04x} isn't in the descriptor", kind, id)
+            }
+            DescriptorError::ReportTooLong {
+                kind,
+                id,
+                len,
+                declared,
+            } => write!(
+                f,
+                "{} report {:#04x} is {} bytes, but the descriptor only allows {}",
+                kind, id, len, declared
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DescriptorError {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ReportKind {
+    Input,
+    Output,
+    Feature,
+}
+
+impl fmt::Display for ReportKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportKind::Input => f.write_str("input"),
+            ReportKind::Output => f.write_str("output"),
+            ReportKind::Feature => f.write_str("feature"),
+        }
+    }
+}
+
+/// The global state main items are declared with.
+#[derive(Debug, Copy, Clone, Default)]
+struct GlobalState {
+    report_id: u8,
+    report_size: u32,
+    report_count: u32,
+}
+
+/// A parsed report descriptor, along with the length of every report it declares.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ReportDescriptor {
+    pub items: Vec<Item>,
+    /// Length in bits of each report, not counting the report id. Descriptors without report ids
+    /// declare their one report of each kind as id 0.
+    pub reports: BTreeMap<(ReportKind, u8), u32>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(descriptor: &[u8]) -> Result<ReportDescriptor, DescriptorError> {
+        let mut items = Vec::new();
+        let mut reports = BTreeMap::new();
+
+        let mut state = GlobalState::default();
+        let mut stack = Vec::new();
+
+        let mut offset = 0;
+
+        while offset < descriptor.len() {
+            let prefix = descriptor[offset];
+
+            if prefix == LONG_ITEM_PREFIX {
+                let header = descriptor
+                    .get(offset + 1..offset + 3)
+                    .ok_or(DescriptorError::Truncated { offset })?;
+                let (len, tag) = (header[0] as usize, header[1]);
+
+                let data = descriptor
+                    .get(offset + 3..offset + 3 + len)
+                    .ok_or(DescriptorError::Truncated { offset })?;
+
+                items.push(Item::Long {
+                    tag,
+                    data: data.to_vec(),
+                });
+
+                offset += 3 + len;
+                continue;
+            }
+
+            let len = match prefix & 0x03 {
+                3 => 4,
+                len => len as usize,
+            };
+
+            let data = descriptor
+                .get(offset + 1..offset + 1 + len)
+                .ok_or(DescriptorError::Truncated { offset })?;
+
+            let item = short_item(prefix & 0xFC, data);
+
+            match item {
+                Item::Input(_) | Item::Output(_) | Item::Feature(_) => {
+                    let kind = match item {
+                        Item::Input(_) => ReportKind::Input,
+                        Item::Output(_) => ReportKind::Output,
+                        _ => ReportKind::Feature,
+                    };
+
+                    *reports.entry((kind, state.report_id)).or_insert(0) +=
+                        state.report_size * state.report_count;
+                }
+                Item::ReportSize(size) => state.report_size = size,
+                Item::ReportCount(count) => state.report_count = count,
+                Item::ReportId(0) => return Err(DescriptorError::ZeroReportId { offset }),
+                Item::ReportId(id) if id > 0xFF => {
+                    return Err(DescriptorError::ReportIdTooLarge { offset, id })
+                }
+                Item::ReportId(id) => state.report_id = id as u8,
+                Item::Push => stack.push(state),
+                Item::Pop => {
+                    state = stack
+                        .pop()
+                        .ok_or(DescriptorError::UnbalancedPop { offset })?
+                }
+                _ => {}
+            }
+
+            items.push(item);
+            offset += 1 + len;
+        }
+
+        Ok(ReportDescriptor { items, reports })
+    }
+
+    /// Length in bytes of report `id`, not counting the id, if it's declared.
+    pub fn report_len(&self, kind: ReportKind, id: u8) -> Option<usize> {
+        self.reports
+            .get(&(kind, id))
+            .map(|bits| (*bits as usize).div_ceil(8))
+    }
+
+    /// Checks that a report we send fits what's declared. `len` is in bytes and doesn't count
+    /// the id. Sending less than declared is fine, the Switch's own controllers do it all the
+    /// time.
+    pub fn check_report(
+        &self,
+        kind: ReportKind,
+        id: u8,
+        len: usize,
+    ) -> Result<(), DescriptorError> {
+        match self.report_len(kind, id) {
+            None => Err(DescriptorError::UndeclaredReport { kind, id }),
+            Some(declared) if len > declared => Err(DescriptorError::ReportTooLong {
+                kind,
+                id,
+                len,
+                declared,
+            }),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+fn short_item(prefix: u8, data: &[u8]) -> Item {
+    let mut bytes = [0u8; 4];
+    bytes[..data.len()].copy_from_slice(data);
+    let unsigned = u32::from_le_bytes(bytes);
+
+    let signed = match data.len() {
+        1 => data[0] as i8 as i32,
+        2 => i16::from_le_bytes([data[0], data[1]]) as i32,
+        _ => unsigned as i32,
+    };
+
+    match prefix {
+        0x80 => Item::Input(unsigned),
+        0x90 => Item::Output(unsigned),
+        0xB0 => Item::Feature(unsigned),
+        0xA0 => Item::Collection(unsigned),
+        0xC0 => Item::EndCollection,
+
+        0x04 => Item::UsagePage(unsigned),
+        0x14 => Item::LogicalMinimum(signed),
+        0x24 => Item::LogicalMaximum(signed),
+        0x34 => Item::PhysicalMinimum(signed),
+        0x44 => Item::PhysicalMaximum(signed),
+        0x54 => Item::UnitExponent(signed),
+        0x64 => Item::Unit(unsigned),
+        0x74 => Item::ReportSize(unsigned),
+        0x84 => Item::ReportId(unsigned),
+        0x94 => Item::ReportCount(unsigned),
+        0xA4 => Item::Push,
+        0xB4 => Item::Pop,
+
+        0x08 => Item::Usage(unsigned),
+        0x18 => Item::UsageMinimum(unsigned),
+        0x28 => Item::UsageMaximum(unsigned),
+
+        prefix => Item::Other {
+            prefix,
+            data: unsigned,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::sdp::SWITCH_REPORT_DESCRIPTOR;
+
+    #[test]
+    fn parses_short_items() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x05, 0x01, // Usage page (generic desktop)
+            0x09, 0x05, // Usage (gamepad)
+            0xA1, 0x01, // Collection (application)
+            0x15, 0x81, // Logical minimum (-127)
+            0x26, 0xFF, 0x00, // Logical maximum (255)
+            0x85, 0x01, // Report id (1)
+            0x75, 0x08, // Report size (8)
+            0x95, 0x03, // Report count (3)
+            0x81, 0x02, // Input (data, variable, absolute)
+            0xC0, // End collection
+        ])
+        .unwrap();
+
+        assert_eq!(
+            descriptor.items,
+            vec![
+                Item::UsagePage(0x01),
+                Item::Usage(0x05),
+                Item::Collection(0x01),
+                Item::LogicalMinimum(-127),
+                Item::LogicalMaximum(255),
+                Item::ReportId(1),
+                Item::ReportSize(8),
+                Item::ReportCount(3),
+                Item::Input(0x02),
+                Item::EndCollection,
+            ]
+        );
+        assert_eq!(descriptor.report_len(ReportKind::Input, 1), Some(3));
+    }
+
+    #[test]
+    fn skips_long_items() {
+        let descriptor = ReportDescriptor::parse(&[
+            0xFE, 0x02, 0x10, 0xAA, 0xBB, // Long item, tag 0x10
+            0x85, 0x02, 0x75, 0x04, 0x95, 0x03, 0x91, 0x02,
+        ])
+        .unwrap();
+
+        assert_eq!(
+            descriptor.items[0],
+            Item::Long {
+                tag: 0x10,
+                data: vec![0xAA, 0xBB],
+            }
+        );
+        // 12 bits round up to 2 bytes
+        assert_eq!(descriptor.report_len(ReportKind::Output, 2), Some(2));
+    }
+
+    #[test]
+    fn push_and_pop_restore_global_state() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x85, 0x01, 0x75, 0x08, 0x95, 0x02, // Report 1, 2 bytes
+            0xA4, // Push
+            0x85, 0x02, 0x95, 0x04, 0x81, 0x02, // Report 2, 4 bytes
+            0xB4, // Pop
+            0x81, 0x02, // Back to report 1
+        ])
+        .unwrap();
+
+        assert_eq!(descriptor.report_len(ReportKind::Input, 1), Some(2));
+        assert_eq!(descriptor.report_len(ReportKind::Input, 2), Some(4));
+        assert_eq!(descriptor.report_len(ReportKind::Output, 1), None);
+    }
+
+    #[test]
+    fn fields_add_up() {
+        let descriptor = ReportDescriptor::parse(&[
+            0x75, 0x01, 0x95, 0x0A, 0x81, 0x02, // 10 bits
+            0x95, 0x06, 0x81, 0x03, // 6 bits of padding
+            0x75, 0x10, 0x95, 0x02, 0xB1, 0x02, // A feature, not part of the input
+        ])
+        .unwrap();
+
+        // No report ids, so everything is report 0
+        assert_eq!(descriptor.reports[&(ReportKind::Input, 0)], 16);
+        assert_eq!(descriptor.report_len(ReportKind::Input, 0), Some(2));
+        assert_eq!(descriptor.report_len(ReportKind::Feature, 0), Some(4));
+    }
+
+    #[test]
+    fn rejects_bad_descriptors() {
+        assert_eq!(
+            ReportDescriptor::parse(&[0x05, 0x01, 0x26, 0xFF]),
+            Err(DescriptorError::Truncated { offset: 2 })
+        );
+        assert_eq!(
+            ReportDescriptor::parse(&[0xFE, 0x04, 0x10, 0xAA]),
+            Err(DescriptorError::Truncated { offset: 0 })
+        );
+        assert_eq!(
+            ReportDescriptor::parse(&[0xA4, 0xB4, 0xB4]),
+            Err(DescriptorError::UnbalancedPop { offset: 2 })
+        );
+        assert_eq!(
+            ReportDescriptor::parse(&[0x85, 0x00]),
+            Err(DescriptorError::ZeroReportId { offset: 0 })
+        );
+        assert_eq!(
+            ReportDescriptor::parse(&[0x05, 0x01, 0x86, 0x01, 0x01]),
+            Err(DescriptorError::ReportIdTooLarge {
+                offset: 2,
+                id: 0x101
+            })
+        );
+    }
+
+    #[test]
+    fn checks_report_lengths() {
+        let descriptor =
+            ReportDescriptor::parse(&[0x85, 0x30, 0x75, 0x08, 0x95, 0x10, 0x81, 0x02]).unwrap();
+
+        assert_eq!(
+            descriptor.check_report(ReportKind::Input, 0x30, 0x10),
+            Ok(())
+        );
+        assert_eq!(
+            descriptor.check_report(ReportKind::Input, 0x30, 0x08),
+            Ok(())
+        );
+        assert_eq!(
+            descriptor.check_report(ReportKind::Input, 0x30, 0x11),
+            Err(DescriptorError::ReportTooLong {
+                kind: ReportKind::Input,
+                id: 0x30,
+                len: 0x11,
+                declared: 0x10,
+            })
+        );
+        assert_eq!(
+            descriptor.check_report(ReportKind::Output, 0x30, 1),
+            Err(DescriptorError::UndeclaredReport {
+                kind: ReportKind::Output,
+                id: 0x30,
+            })
+        );
+    }
+
+    #[test]
+    fn switch_descriptor_declares_every_report() {
+        let descriptor = ReportDescriptor::parse(SWITCH_REPORT_DESCRIPTOR).unwrap();
+
+        for &id in &[0x21, 0x30, 0x81] {
+            assert_eq!(descriptor.report_len(ReportKind::Input, id), Some(63));
+        }
+
+        for &id in &[0x01, 0x10, 0x80, 0x82] {
+            assert_eq!(descriptor.report_len(ReportKind::Output, id), Some(63));
+        }
+
+        // See the module docs
+        assert_eq!(descriptor.report_len(ReportKind::Input, 0x3F), None);
+
+        assert_eq!(Emulator::check_descriptor(&descriptor), Ok(()));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::input_report::{InputReport, ReportBody};
+use crate::output_report::{OutputReport, RumbleData};
+use crate::spi_flash::{SpiFlash, SPI_FLASH_SIZE};
+use crate::subcommand::{Subcommand, SubcommandReply, SPI_MAX_TRANSFER};
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use smol::Timer;
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::Duration;
+
+/// How long to wait for a subcommand reply before sending the subcommand again.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+/// How many times a subcommand is sent before giving up on it.
+const MAX_ATTEMPTS: usize = 5;
+
+/// Talks to a controller on the itr channel the way the Switch does.
+pub struct Host<R, W> {
+    itr_r: R,
+    itr_w: W,
+    packet_counter: u8,
+}
+
+impl<R, W> Host<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(itr_r: R, itr_w: W) -> Host<R, W> {
+        Host {
+            itr_r,
+            itr_w,
+            packet_counter: 0,
+        }
+    }
+
+    /// Sends `subcommand` and waits for the reply accepted by `is_reply`. Input reports that
+    /// aren't the reply are skipped, and the subcommand is sent again if no reply shows up in
+    /// time, since controllers drop the odd one.
+    pub async fn send_subcommand_matching<F>(
+        &mut self,
+        subcommand: &Subcommand,
+        is_reply: F,
+    ) -> Result<SubcommandReply>
+    where
+        F: Fn(&SubcommandReply) -> bool,
+    {
+        let mut controller_incoming = [0u8; 128];
+
+        for _ in 0..MAX_ATTEMPTS {
+            let report = OutputReport::RumbleAndSubcommand {
+                packet_counter: self.packet_counter,
+                rumble: RumbleData::NEUTRAL,
+                subcommand: subcommand.clone(),
+            };
+
+            self.packet_counter = (self.packet_counter + 1) & 0x0F;
+            self.itr_w.write_all(&report.encode_packet()).await?;
+
+            let mut timeout = Timer::after(REPLY_TIMEOUT);
+
+            loop {
+                let read = self.itr_r.read(&mut controller_incoming);
+
+                let n = match future::select(read, timeout).await {
+                    Either::Left((Ok(0), _)) => {
+                        return Err(Error::new(
+                            ErrorKind::UnexpectedEof,
+                            "controller closed the connection",
+                        ))
+                    }
+                    Either::Left((Ok(n), old_timeout)) => {
+                        timeout = old_timeout;
+                        n
+                    }
+                    Either::Left((Err(e), _)) => return Err(e),
+                    // Try again
+                    Either::Right(_) => break,
+                };
+
+                if let Ok(InputReport::Standard(report)) =
+                    InputReport::parse_packet(&controller_incoming[..n])
+                {
+                    match report.body {
+                        ReportBody::SubcommandReply(reply)
+                            if reply.id == subcommand.id() && is_reply(&reply) =>
+                        {
+                            return Ok(reply)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            format!("no reply to subcommand {:#04x}", subcommand.id()),
+        ))
+    }
+
+    /// Waits for the controller's next input report.
+    pub async fn read_input_report(&mut self) -> Result<InputReport> {
+        let mut controller_incoming = [0u8; 128];
+
+        let n = self.itr_r.read(&mut controller_incoming).await?;
+
+        if n == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "controller closed the connection",
+            ));
+        }
+
+        InputReport::parse_packet(&controller_incoming[..n])
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Sends `subcommand` and waits for its reply.
+    pub async fn send_subcommand(&mut self, subcommand: &Subcommand) -> Result<SubcommandReply> {
+        self.send_subcommand_matching(subcommand, |_| true).await
+    }
+
+    /// Reads up to [`SPI_MAX_TRANSFER`] bytes of the controller's SPI flash.
+    pub async fn read_spi(&mut self, address: u32, length: u8) -> Result<Vec<u8>> {
+        let length = length.min(SPI_MAX_TRANSFER);
+        let subcommand = Subcommand::SpiFlashRead { address, length };
+
+        // A retried read can have its first reply show up late, so make sure it's for this address
+        let reply = self
+            .send_subcommand_matching(&subcommand, |reply| {
+                reply.ack == 0x00 || reply.data.get(0..4) == Some(&address.to_le_bytes()[..])
+            })
+            .await?;
+
+        if reply.ack == 0x00 {
+            return Err(Error::other(format!(
+                "controller refused to read SPI flash at {:#07x}",
+                address
+            )));
+        }
+
+        match reply.data.get(5..5 + length as usize) {
+            Some(data) => Ok(data.to_vec()),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("short SPI flash read at {:#07x}", address),
+            )),
+        }
+    }
+
+    /// Reads the controller's whole SPI flash. `progress` is called with the number of bytes
+    /// read so far after every read.
+    pub async fn dump_spi_flash<F: FnMut(usize)>(&mut self, mut progress: F) -> Result<SpiFlash> {
+        let mut data = Vec::with_capacity(SPI_FLASH_SIZE);
+
+        while data.len() < SPI_FLASH_SIZE {
+            let length = (SPI_FLASH_SIZE - data.len()).min(SPI_MAX_TRANSFER as usize);
+            let bytes = self.read_spi(data.len() as u32, length as u8).await?;
+
+            data.extend_from_slice(&bytes);
+            progress(data.len());
+        }
+
+        SpiFlash::from_bytes(data)
+    }
+}
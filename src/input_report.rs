@@ -0,0 +1,615 @@
+use crate::report::{check_len, ReportError, HIDP_DATA_INPUT};
+use crate::subcommand::SubcommandReply;
+
+/// Standard input reports are this long, not counting the HIDP header.
+pub const STANDARD_REPORT_LEN: usize = 49;
+/// Timer, battery, buttons, sticks and vibrator report are common to every standard report.
+const STANDARD_HEADER_LEN: usize = 13;
+const IMU_FRAME_LEN: usize = 12;
+const SIMPLE_HID_REPORT_LEN: usize = 12;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Button {
+    Y,
+    X,
+    B,
+    A,
+    RightSR,
+    RightSL,
+    R,
+    ZR,
+    Minus,
+    Plus,
+    RightStick,
+    LeftStick,
+    Home,
+    Capture,
+    ChargingGrip,
+    Down,
+    Up,
+    Right,
+    Left,
+    LeftSR,
+    LeftSL,
+    L,
+    ZL,
+}
+
+impl Button {
+    pub const ALL: [Button; 23] = [
+        Button::Y,
+        Button::X,
+        Button::B,
+        Button::A,
+        Button::RightSR,
+        Button::RightSL,
+        Button::R,
+        Button::ZR,
+        Button::Minus,
+        Button::Plus,
+        Button::RightStick,
+        Button::LeftStick,
+        Button::Home,
+        Button::Capture,
+        Button::ChargingGrip,
+        Button::Down,
+        Button::Up,
+        Button::Right,
+        Button::Left,
+        Button::LeftSR,
+        Button::LeftSL,
+        Button::L,
+        Button::ZL,
+    ];
+
+    /// The byte (right, shared, left) and bit that hold this button.
+    fn position(self) -> (usize, u8) {
+        match self {
+            Button::Y => (0, 0),
+            Button::X => (0, 1),
+            Button::B => (0, 2),
+            Button::A => (0, 3),
+            Button::RightSR => (0, 4),
+            Button::RightSL => (0, 5),
+            Button::R => (0, 6),
+            Button::ZR => (0, 7),
+            Button::Minus => (1, 0),
+            Button::Plus => (1, 1),
+            Button::RightStick => (1, 2),
+            Button::LeftStick => (1, 3),
+            Button::Home => (1, 4),
+            Button::Capture => (1, 5),
+            Button::ChargingGrip => (1, 7),
+            Button::Down => (2, 0),
+            Button::Up => (2, 1),
+            Button::Right => (2, 2),
+            Button::Left => (2, 3),
+            Button::LeftSR => (2, 4),
+            Button::LeftSL => (2, 5),
+            Button::L => (2, 6),
+            Button::ZL => (2, 7),
+        }
+    }
+}
+
+/// Returned when a string isn't the name of a [`Button`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseButtonError(String);
+
+impl std::fmt::Display for ParseButtonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown button '{}'", self.0)
+    }
+}
+
+impl std::error::Error for ParseButtonError {}
+
+impl std::str::FromStr for Button {
+    type Err = ParseButtonError;
+
+    /// Parses a button by its variant name, ignoring case, like `zl` or `RightStick`.
+    fn from_str(s: &str) -> Result<Button, ParseButtonError> {
+        Button::ALL
+            .iter()
+            .copied()
+            .find(|b| format!("{:?}", b).eq_ignore_ascii_case(s))
+            .ok_or_else(|| ParseButtonError(s.to_string()))
+    }
+}
+
+/// The three button bytes of a standard input report: right, shared and left.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Buttons(pub [u8; 3]);
+
+impl Buttons {
+    pub fn is_pressed(&self, button: Button) -> bool {
+        let (byte, bit) = button.position();
+        self.0[byte] & (1 << bit) != 0
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        let (byte, bit) = button.position();
+
+        if pressed {
+            self.0[byte] |= 1 << bit;
+        } else {
+            self.0[byte] &= !(1 << bit);
+        }
+    }
+
+    pub fn pressed(self) -> impl Iterator<Item = Button> {
+        Button::ALL
+            .iter()
+            .copied()
+            .filter(move |&b| self.is_pressed(b))
+    }
+}
+
+/// A stick position, as two 12-bit values packed into 3 bytes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StickData {
+    pub horizontal: u16,
+    pub vertical: u16,
+}
+
+impl StickData {
+    pub const CENTER: StickData = StickData {
+        horizontal: 0x800,
+        vertical: 0x800,
+    };
+
+    pub fn from_bytes(bytes: [u8; 3]) -> StickData {
+        StickData {
+            horizontal: bytes[0] as u16 | ((bytes[1] as u16 & 0x0F) << 8),
+            vertical: (bytes[1] as u16 >> 4) | ((bytes[2] as u16) << 4),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 3] {
+        [
+            (self.horizontal & 0xFF) as u8,
+            ((self.horizontal >> 8) & 0x0F) as u8 | ((self.vertical & 0x0F) << 4) as u8,
+            (self.vertical >> 4) as u8,
+        ]
+    }
+}
+
+impl Default for StickData {
+    fn default() -> StickData {
+        StickData::CENTER
+    }
+}
+
+/// One accelerometer and gyroscope sample. Standard full reports carry three, 5ms apart.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ImuFrame {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+impl ImuFrame {
+    fn from_bytes(bytes: &[u8]) -> ImuFrame {
+        let value = |i: usize| i16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+
+        ImuFrame {
+            accel: [value(0), value(1), value(2)],
+            gyro: [value(3), value(4), value(5)],
+        }
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        for value in self.accel.iter().chain(self.gyro.iter()) {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+/// Everything after the vibrator report, which depends on the report id.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ReportBody {
+    /// Report 0x21.
+    SubcommandReply(SubcommandReply),
+    /// Reports 0x30 to 0x33. `extra` holds the NFC/IR data of 0x31 and anything else that
+    /// follows the IMU frames.
+    Imu {
+        frames: [ImuFrame; 3],
+        extra: Vec<u8>,
+    },
+    /// Anything we can't make sense of.
+    Raw(Vec<u8>),
+}
+
+/// Reports 0x21 and 0x30 to 0x33, which all start with the same controller state.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StandardInputReport {
+    pub id: u8,
+    pub timer: u8,
+    /// Battery level in the high nibble, connection info in the low nibble.
+    pub battery_connection: u8,
+    pub buttons: Buttons,
+    pub left_stick: StickData,
+    pub right_stick: StickData,
+    pub vibrator_report: u8,
+    pub body: ReportBody,
+}
+
+impl StandardInputReport {
+    /// From 0 (empty) to 4 (full).
+    pub fn battery_level(&self) -> u8 {
+        self.battery_connection >> 5
+    }
+
+    pub fn is_charging(&self) -> bool {
+        self.battery_connection & 0x10 != 0
+    }
+
+    fn parse(report: &[u8]) -> Result<StandardInputReport, ReportError> {
+        check_len(report, STANDARD_HEADER_LEN)?;
+
+        let id = report[0];
+        let rest = &report[STANDARD_HEADER_LEN..];
+
+        let body = match id {
+            0x21 if rest.len() >= 2 => ReportBody::SubcommandReply(SubcommandReply {
+                ack: rest[0],
+                id: rest[1],
+                data: rest[2..].to_vec(),
+            }),
+            0x30..=0x33 if rest.len() >= IMU_FRAME_LEN * 3 => {
+                let frame = |i: usize| ImuFrame::from_bytes(&rest[i * IMU_FRAME_LEN..]);
+
+                ReportBody::Imu {
+                    frames: [frame(0), frame(1), frame(2)],
+                    extra: rest[IMU_FRAME_LEN * 3..].to_vec(),
+                }
+            }
+            _ => ReportBody::Raw(rest.to_vec()),
+        };
+
+        Ok(StandardInputReport {
+            id,
+            timer: report[1],
+            battery_connection: report[2],
+            buttons: Buttons([report[3], report[4], report[5]]),
+            left_stick: StickData::from_bytes([report[6], report[7], report[8]]),
+            right_stick: StickData::from_bytes([report[9], report[10], report[11]]),
+            vibrator_report: report[12],
+            body,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(STANDARD_REPORT_LEN);
+
+        out.extend_from_slice(&[self.id, self.timer, self.battery_connection]);
+        out.extend_from_slice(&self.buttons.0);
+        out.extend_from_slice(&self.left_stick.to_bytes());
+        out.extend_from_slice(&self.right_stick.to_bytes());
+        out.push(self.vibrator_report);
+
+        match &self.body {
+            ReportBody::SubcommandReply(reply) => {
+                out.extend_from_slice(&[reply.ack, reply.id]);
+                out.extend_from_slice(&reply.data);
+            }
+            ReportBody::Imu { frames, extra } => {
+                for frame in frames {
+                    frame.write_bytes(&mut out);
+                }
+                out.extend_from_slice(extra);
+            }
+            ReportBody::Raw(data) => out.extend_from_slice(data),
+        }
+
+        if out.len() < STANDARD_REPORT_LEN {
+            out.resize(STANDARD_REPORT_LEN, 0);
+        }
+
+        out
+    }
+}
+
+/// Report 0x3F, sent before the Switch asks for anything else. It's what a generic HID host sees.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SimpleHidReport {
+    pub buttons: [u8; 2],
+    pub hat: u8,
+    /// Left horizontal, left vertical, right horizontal, right vertical.
+    pub sticks: [u16; 4],
+    pub extra: Vec<u8>,
+}
+
+impl SimpleHidReport {
+    fn parse(report: &[u8]) -> Result<SimpleHidReport, ReportError> {
+        check_len(report, SIMPLE_HID_REPORT_LEN)?;
+
+        let stick = |i: usize| u16::from_le_bytes([report[4 + i * 2], report[5 + i * 2]]);
+
+        Ok(SimpleHidReport {
+            buttons: [report[1], report[2]],
+            hat: report[3],
+            sticks: [stick(0), stick(1), stick(2), stick(3)],
+            extra: report[SIMPLE_HID_REPORT_LEN..].to_vec(),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(SIMPLE_HID_REPORT_LEN + self.extra.len());
+
+        out.extend_from_slice(&[0x3F, self.buttons[0], self.buttons[1], self.hat]);
+        for stick in &self.sticks {
+            out.extend_from_slice(&stick.to_le_bytes());
+        }
+        out.extend_from_slice(&self.extra);
+
+        out
+    }
+}
+
+/// A report sent from the controller to the Switch.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InputReport {
+    Standard(StandardInputReport),
+    SimpleHid(SimpleHidReport),
+    Other { id: u8, data: Vec<u8> },
+}
+
+impl InputReport {
+    /// Parses a report, starting at the report id. The HIDP header must already be stripped.
+    /// Anything parsed from real traffic encodes back to the same bytes, except that standard
+    /// reports shorter than [`STANDARD_REPORT_LEN`] come back padded out to it with zeros.
+    pub fn parse(report: &[u8]) -> Result<InputReport, ReportError> {
+        match report.first() {
+            None => Err(ReportError::Empty),
+            Some(0x21) | Some(0x30..=0x33) => {
+                StandardInputReport::parse(report).map(InputReport::Standard)
+            }
+            Some(0x3F) => SimpleHidReport::parse(report).map(InputReport::SimpleHid),
+            Some(&id) => Ok(InputReport::Other {
+                id,
+                data: report[1..].to_vec(),
+            }),
+        }
+    }
+
+    /// Parses a packet as read from the itr channel, HIDP header included.
+    pub fn parse_packet(packet: &[u8]) -> Result<InputReport, ReportError> {
+        match packet.split_first() {
+            Some((&HIDP_DATA_INPUT, report)) => InputReport::parse(report),
+            Some((&header, _)) => Err(ReportError::BadHeader(header)),
+            None => Err(ReportError::Empty),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            InputReport::Standard(report) => report.id,
+            InputReport::SimpleHid(_) => 0x3F,
+            InputReport::Other { id, .. } => *id,
+        }
+    }
+
+    /// Encodes the report without the HIDP header. Standard reports are padded to
+    /// [`STANDARD_REPORT_LEN`] if their body is shorter.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            InputReport::Standard(report) => report.encode(),
+            InputReport::SimpleHid(report) => report.encode(),
+            InputReport::Other { id, data } => {
+                let mut out = Vec::with_capacity(data.len() + 1);
+                out.push(*id);
+                out.extend_from_slice(data);
+                out
+            }
+        }
+    }
+
+    /// Encodes the report with the HIDP header, ready to be sent on the itr channel.
+    pub fn encode_packet(&self) -> Vec<u8> {
+        let mut packet = vec![HIDP_DATA_INPUT];
+        packet.extend(self.encode());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A Pro Controller's device info reply, padded out like it sends it.
+    fn device_info_reply() -> Vec<u8> {
+        let mut report = vec![
+            0x21, 0x0a, 0x8e, 0x00, 0x00, 0x00, 0x0b, 0x78, 0x7d, 0x4e, 0xc8, 0x77, 0x0c, 0x82,
+            0x02, 0x03, 0x8b, 0x03, 0x02, 0x98, 0xb6, 0xe9, 0x0a, 0x0b, 0x0c, 0x01, 0x01,
+        ];
+        report.resize(STANDARD_REPORT_LEN, 0);
+        report
+    }
+
+    /// A full report with A, Plus and L held and the sticks moved.
+    fn full_report() -> Vec<u8> {
+        let mut report = vec![
+            0x30, 0x42, 0x8e, 0x08, 0x02, 0x40, 0x23, 0xc1, 0xab, 0x00, 0x08, 0x80, 0x0c,
+        ];
+
+        for i in 0..IMU_FRAME_LEN as u8 * 3 {
+            report.push(i.wrapping_mul(37));
+        }
+
+        report
+    }
+
+    #[test]
+    fn subcommand_reply_round_trips() {
+        let bytes = device_info_reply();
+        let report = InputReport::parse(&bytes).unwrap();
+
+        let standard = match &report {
+            InputReport::Standard(report) => report,
+            other => panic!("expected a standard report, got {:?}", other),
+        };
+
+        assert_eq!(standard.timer, 0x0a);
+        assert_eq!(standard.battery_level(), 4);
+        assert!(!standard.is_charging());
+
+        match &standard.body {
+            ReportBody::SubcommandReply(reply) => {
+                assert_eq!(reply.ack, 0x82);
+                assert_eq!(reply.id, 0x02);
+                assert_eq!(&reply.data[4..10], &[0x98, 0xb6, 0xe9, 0x0a, 0x0b, 0x0c]);
+            }
+            other => panic!("expected a subcommand reply, got {:?}", other),
+        }
+
+        assert_eq!(report.encode(), bytes);
+    }
+
+    #[test]
+    fn full_report_round_trips() {
+        let bytes = full_report();
+        let report = InputReport::parse(&bytes).unwrap();
+
+        let standard = match &report {
+            InputReport::Standard(report) => report,
+            other => panic!("expected a standard report, got {:?}", other),
+        };
+
+        assert_eq!(
+            standard.buttons.pressed().collect::<Vec<_>>(),
+            vec![Button::A, Button::Plus, Button::L]
+        );
+        assert_eq!(
+            standard.left_stick,
+            StickData {
+                horizontal: 0x123,
+                vertical: 0xabc
+            }
+        );
+        assert_eq!(standard.right_stick, StickData::CENTER);
+
+        match &standard.body {
+            ReportBody::Imu { frames, extra } => {
+                assert_eq!(frames[0].accel[0], i16::from_le_bytes([0, 37]));
+                assert!(extra.is_empty());
+            }
+            other => panic!("expected IMU data, got {:?}", other),
+        }
+
+        assert_eq!(report.encode(), bytes);
+        assert_eq!(
+            InputReport::parse_packet(&report.encode_packet()).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn simple_hid_report_round_trips() {
+        let bytes = vec![
+            0x3f, 0x01, 0x20, 0x08, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x55,
+        ];
+        let report = InputReport::parse(&bytes).unwrap();
+
+        assert_eq!(
+            report,
+            InputReport::SimpleHid(SimpleHidReport {
+                buttons: [0x01, 0x20],
+                hat: 0x08,
+                sticks: [0x8000; 4],
+                extra: vec![0x55],
+            })
+        );
+        assert_eq!(report.encode(), bytes);
+    }
+
+    #[test]
+    fn other_reports_round_trip() {
+        let bytes = vec![0x81, 0x01, 0x02];
+        let report = InputReport::parse(&bytes).unwrap();
+
+        assert_eq!(report.id(), 0x81);
+        assert_eq!(report.encode(), bytes);
+    }
+
+    #[test]
+    fn short_standard_reports_encode_padded() {
+        for bytes in &[&device_info_reply()[..16], &full_report()[..20]] {
+            let encoded = InputReport::parse(bytes).unwrap().encode();
+
+            assert_eq!(encoded.len(), STANDARD_REPORT_LEN);
+            assert_eq!(&encoded[..bytes.len()], *bytes);
+            assert!(encoded[bytes.len()..].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn rejects_bad_packets() {
+        assert_eq!(InputReport::parse(&[]), Err(ReportError::Empty));
+        assert!(InputReport::parse(&[0x30, 0x00]).is_err());
+        assert_eq!(
+            InputReport::parse_packet(&[0xa2, 0x30]),
+            Err(ReportError::BadHeader(0xa2))
+        );
+    }
+
+    #[test]
+    fn button_bits() {
+        let cases: &[(Button, [u8; 3])] = &[
+            (Button::Y, [0x01, 0x00, 0x00]),
+            (Button::A, [0x08, 0x00, 0x00]),
+            (Button::ZR, [0x80, 0x00, 0x00]),
+            (Button::Minus, [0x00, 0x01, 0x00]),
+            (Button::Home, [0x00, 0x10, 0x00]),
+            (Button::Capture, [0x00, 0x20, 0x00]),
+            (Button::ChargingGrip, [0x00, 0x80, 0x00]),
+            (Button::Down, [0x00, 0x00, 0x01]),
+            (Button::Left, [0x00, 0x00, 0x08]),
+            (Button::ZL, [0x00, 0x00, 0x80]),
+        ];
+
+        for &(button, bytes) in cases {
+            let mut buttons = Buttons::default();
+            buttons.set(button, true);
+            assert_eq!(buttons.0, bytes, "{:?}", button);
+            assert_eq!(Buttons(bytes).pressed().collect::<Vec<_>>(), vec![button]);
+
+            buttons.set(button, false);
+            assert_eq!(buttons, Buttons::default());
+        }
+
+        // Every button has a bit of its own
+        let mut all = Buttons::default();
+        for &button in Button::ALL.iter() {
+            assert!(!all.is_pressed(button), "{:?} shares a bit", button);
+            all.set(button, true);
+        }
+        assert_eq!(all.pressed().count(), Button::ALL.len());
+    }
+
+    #[test]
+    fn stick_packing() {
+        let cases: &[(u16, u16, [u8; 3])] = &[
+            (0x800, 0x800, [0x00, 0x08, 0x80]),
+            (0x123, 0xabc, [0x23, 0xc1, 0xab]),
+            (0x000, 0xfff, [0x00, 0xf0, 0xff]),
+            (0xfff, 0x000, [0xff, 0x0f, 0x00]),
+        ];
+
+        for &(horizontal, vertical, bytes) in cases {
+            let stick = StickData {
+                horizontal,
+                vertical,
+            };
+
+            assert_eq!(stick.to_bytes(), bytes);
+            assert_eq!(StickData::from_bytes(bytes), stick);
+        }
+    }
+
+    #[test]
+    fn parses_button_names() {
+        assert_eq!("a".parse::<Button>(), Ok(Button::A));
+        assert_eq!("RIGHTSTICK".parse::<Button>(), Ok(Button::RightStick));
+        assert!("start".parse::<Button>().is_err());
+    }
+}
@@ -1,51 +1,241 @@
 use crate::smol_fd::{libc_check_error, SmolFd};
-use libbluetooth::bluetooth::bdaddr_t;
-use std::io::{Read, Result, Write};
+use crate::BtAddr;
+use libbluetooth::bluetooth::{bdaddr_t, bt_security};
+use libbluetooth::l2cap::l2cap_options;
+
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use smol::{Async, Timer};
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::mem::{size_of, MaybeUninit};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 type L2CAPSocketAddr = libbluetooth::l2cap::sockaddr_l2;
 
 const SOCKADDR_L2_LEN: usize = size_of::<L2CAPSocketAddr>();
 
+/// How much a connection has to be secured before data goes over it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum SecurityLevel {
+    /// Nothing at all, only meant for SDP.
+    Sdp,
+    /// No authentication, no encryption.
+    Low,
+    /// Encrypted, with an unauthenticated link key.
+    Medium,
+    /// Encrypted, with an authenticated link key.
+    High,
+    /// High, with Secure Connections only.
+    Fips,
+}
+
+impl SecurityLevel {
+    fn from_raw(level: u8) -> SecurityLevel {
+        match level as i32 {
+            libbluetooth::bluetooth::BT_SECURITY_SDP => SecurityLevel::Sdp,
+            libbluetooth::bluetooth::BT_SECURITY_LOW => SecurityLevel::Low,
+            libbluetooth::bluetooth::BT_SECURITY_MEDIUM => SecurityLevel::Medium,
+            libbluetooth::bluetooth::BT_SECURITY_HIGH => SecurityLevel::High,
+            _ => SecurityLevel::Fips,
+        }
+    }
+
+    fn to_raw(self) -> u8 {
+        let level = match self {
+            SecurityLevel::Sdp => libbluetooth::bluetooth::BT_SECURITY_SDP,
+            SecurityLevel::Low => libbluetooth::bluetooth::BT_SECURITY_LOW,
+            SecurityLevel::Medium => libbluetooth::bluetooth::BT_SECURITY_MEDIUM,
+            SecurityLevel::High => libbluetooth::bluetooth::BT_SECURITY_HIGH,
+            SecurityLevel::Fips => libbluetooth::bluetooth::BT_SECURITY_FIPS,
+        };
+
+        level as u8
+    }
+}
+
+/// The `L2CAP_OPTIONS` of a socket. On BR/EDR, which is all the Switch speaks, this is where
+/// the MTUs are set, since `BT_RCVMTU` and `BT_SNDMTU` only work on LE sockets.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct L2CAPOptions {
+    /// Largest packet we send.
+    pub omtu: u16,
+    /// Largest packet we accept.
+    pub imtu: u16,
+    /// Flush timeout in milliseconds, 0xFFFF for never.
+    pub flush_to: u16,
+    pub mode: u8,
+    pub fcs: u8,
+    pub max_tx: u8,
+    pub txwin_size: u16,
+}
+
+/// Socket options and addresses both socket types have.
+macro_rules! impl_socket_options {
+    ($socket:ty) => {
+        impl $socket {
+            pub fn options(&self) -> Result<L2CAPOptions> {
+                let raw: l2cap_options = get_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_L2CAP,
+                    libbluetooth::l2cap::L2CAP_OPTIONS,
+                )?;
+
+                Ok(L2CAPOptions {
+                    omtu: raw.omtu,
+                    imtu: raw.imtu,
+                    flush_to: raw.flush_to,
+                    mode: raw.mode,
+                    fcs: raw.fcs,
+                    max_tx: raw.max_tx,
+                    txwin_size: raw.txwin_size,
+                })
+            }
+
+            /// Only works before connecting. Anything left as it was in [`options`](Self::options)
+            /// stays the same.
+            pub fn set_options(&self, options: &L2CAPOptions) -> Result<()> {
+                let raw = l2cap_options {
+                    omtu: options.omtu,
+                    imtu: options.imtu,
+                    flush_to: options.flush_to,
+                    mode: options.mode,
+                    fcs: options.fcs,
+                    max_tx: options.max_tx,
+                    txwin_size: options.txwin_size,
+                };
+
+                set_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_L2CAP,
+                    libbluetooth::l2cap::L2CAP_OPTIONS,
+                    &raw,
+                )
+            }
+
+            /// Largest packet we accept. LE only.
+            pub fn receive_mtu(&self) -> Result<u16> {
+                get_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_RCVMTU,
+                )
+            }
+
+            /// LE only, use [`set_options`](Self::set_options) for BR/EDR.
+            pub fn set_receive_mtu(&self, mtu: u16) -> Result<()> {
+                set_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_RCVMTU,
+                    &mtu,
+                )
+            }
+
+            /// Largest packet we can send. LE only, and it's up to the other side, so it can't
+            /// be set.
+            pub fn send_mtu(&self) -> Result<u16> {
+                get_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_SNDMTU,
+                )
+            }
+
+            pub fn security(&self) -> Result<SecurityLevel> {
+                let raw: bt_security = get_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_SECURITY,
+                )?;
+
+                Ok(SecurityLevel::from_raw(raw.level))
+            }
+
+            pub fn set_security(&self, level: SecurityLevel) -> Result<()> {
+                let raw = bt_security {
+                    level: level.to_raw(),
+                    key_size: 0,
+                };
+
+                set_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_SECURITY,
+                    &raw,
+                )
+            }
+
+            /// Whether packets that are taking too long may be dropped by the controller.
+            pub fn flushable(&self) -> Result<bool> {
+                let flushable: u32 = get_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_FLUSHABLE,
+                )?;
+
+                Ok(flushable != 0)
+            }
+
+            pub fn set_flushable(&self, flushable: bool) -> Result<()> {
+                let flushable = if flushable {
+                    libbluetooth::bluetooth::BT_FLUSHABLE_ON
+                } else {
+                    libbluetooth::bluetooth::BT_FLUSHABLE_OFF
+                } as u32;
+
+                set_option(
+                    self.fd.raw,
+                    libbluetooth::bluetooth::SOL_BLUETOOTH,
+                    libbluetooth::bluetooth::BT_FLUSHABLE,
+                    &flushable,
+                )
+            }
+
+            /// Size of the kernel's send buffer in bytes. The kernel doubles what it's given to
+            /// leave room for its own bookkeeping, and this is the doubled size.
+            pub fn send_buffer_size(&self) -> Result<usize> {
+                let size: libc::c_int = get_option(self.fd.raw, libc::SOL_SOCKET, libc::SO_SNDBUF)?;
+
+                Ok(size as usize)
+            }
+
+            /// A small buffer keeps reports from queueing up behind each other when the link
+            /// can't keep up.
+            pub fn set_send_buffer_size(&self, size: usize) -> Result<()> {
+                let size = size as libc::c_int;
+
+                set_option(self.fd.raw, libc::SOL_SOCKET, libc::SO_SNDBUF, &size)
+            }
+
+            /// Address of the adapter the socket is bound to, or connected through.
+            pub fn local_address(&self) -> Result<BtAddr> {
+                socket_address(self.fd.raw, libc::getsockname)
+            }
+        }
+    };
+}
+
+/// A seqpacket L2CAP socket waiting for connections, like the Switch connecting to a controller.
+#[derive(Debug)]
 pub struct L2CAPListener {
     fd: SmolFd,
 }
 
 impl L2CAPListener {
     pub fn new() -> Result<L2CAPListener> {
-        let socket = libc_check_error(unsafe {
-            libc::socket(
-                libc::AF_BLUETOOTH,
-                libc::SOCK_SEQPACKET,
-                libbluetooth::bluetooth::BTPROTO_L2CAP,
-            )
-        })?;
-
         Ok(L2CAPListener {
-            fd: SmolFd::new(socket),
+            fd: SmolFd::new(l2cap_socket()?),
         })
     }
 
-    pub fn bind(&self, psm_port: u16) -> Result<()> {
-        let loc_addr = L2CAPSocketAddr {
-            l2_family: libbluetooth::bluetooth::AF_BLUETOOTH,
-            l2_psm: psm_port,
-            l2_bdaddr: libbluetooth::bluetooth::BDADDR_ANY,
-            l2_cid: 0,
-            l2_bdaddr_type: 0,
-        };
-
-        let res = unsafe {
-            libc::bind(
-                self.fd.raw,
-                std::mem::transmute(&loc_addr),
-                SOCKADDR_L2_LEN as u32,
-            )
-        };
-
-        libc_check_error(res)?;
-        Ok(())
+    /// Listens on `psm_port` of the adapter at `local`, or of every adapter with
+    /// [`BtAddr::ANY`].
+    pub fn bind(&self, local: BtAddr, psm_port: u16) -> Result<()> {
+        bind(self.fd.raw, local, psm_port)
     }
 
     pub fn listen(&self, mode: i32) -> Result<()> {
@@ -55,25 +245,29 @@ impl L2CAPListener {
         Ok(())
     }
 
-    pub fn accept(&mut self) -> Result<(L2CAPStream, L2CAPSocketAddr)> {
+    /// Accepts the next connection, along with the address it came from. The new socket starts
+    /// out with whatever options were set on this one.
+    pub fn accept(&self) -> Result<(L2CAPStream, BtAddr)> {
         let mut client_addr: MaybeUninit<L2CAPSocketAddr> = std::mem::MaybeUninit::uninit();
         let mut client_socklen = SOCKADDR_L2_LEN as u32;
 
         let client = unsafe {
             libc::accept(
                 self.fd.raw,
-                std::mem::transmute(&mut client_addr),
+                client_addr.as_mut_ptr() as *mut libc::sockaddr,
                 &mut client_socklen,
             )
         };
 
         let client_stream = unsafe { L2CAPStream::from_raw_fd(libc_check_error(client)?) };
-        let client_addr: L2CAPSocketAddr = unsafe { std::mem::transmute(client_addr) };
+        let client_addr: L2CAPSocketAddr = unsafe { client_addr.assume_init() };
 
-        Ok((client_stream, client_addr))
+        Ok((client_stream, bt_addr(&client_addr)))
     }
 }
 
+impl_socket_options!(L2CAPListener);
+
 impl Read for L2CAPListener {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.fd.read(buf)
@@ -106,42 +300,35 @@ impl AsRawFd for L2CAPListener {
 
 impl Drop for L2CAPListener {
     fn drop(&mut self) {
-        self.fd.close();
+        let _ = self.fd.close();
     }
 }
 
+/// A connected seqpacket L2CAP socket. Every read and write is a whole packet.
+#[derive(Debug)]
 pub struct L2CAPStream {
     fd: SmolFd,
 }
 
 impl L2CAPStream {
     pub fn new() -> Result<L2CAPStream> {
-        let socket = libc_check_error(unsafe {
-            libc::socket(
-                libc::AF_BLUETOOTH,
-                libc::SOCK_SEQPACKET,
-                libbluetooth::bluetooth::BTPROTO_L2CAP,
-            )
-        })?;
-
         Ok(L2CAPStream {
-            fd: SmolFd::new(socket),
+            fd: SmolFd::new(l2cap_socket()?),
         })
     }
 
-    pub fn connect(&mut self, bt_addr: [u8; 6], psm_port: u16) -> Result<()> {
-        let loc_addr = L2CAPSocketAddr {
-            l2_family: libbluetooth::bluetooth::AF_BLUETOOTH,
-            l2_psm: psm_port,
-            l2_bdaddr: bdaddr_t { b: bt_addr },
-            l2_cid: 0,
-            l2_bdaddr_type: 0,
-        };
+    /// Picks the local adapter, by its address, that the connection goes out through.
+    pub fn bind(&self, local: BtAddr) -> Result<()> {
+        bind(self.fd.raw, local, 0)
+    }
+
+    pub fn connect(&mut self, address: BtAddr, psm_port: u16) -> Result<()> {
+        let addr = socket_addr(address, psm_port);
 
         let res = unsafe {
             libc::connect(
                 self.fd.raw,
-                std::mem::transmute(&loc_addr),
+                &addr as *const L2CAPSocketAddr as *const libc::sockaddr,
                 SOCKADDR_L2_LEN as u32,
             )
         };
@@ -149,8 +336,36 @@ impl L2CAPStream {
         libc_check_error(res)?;
         Ok(())
     }
+
+    /// Address of the device on the other end.
+    pub fn peer_address(&self) -> Result<BtAddr> {
+        socket_address(self.fd.raw, libc::getpeername)
+    }
+
+    /// Whether a non-blocking connect is done, and how it went.
+    fn connect_finished(&self) -> Result<()> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd.raw,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+
+        libc_check_error(unsafe { libc::poll(&mut poll_fd, 1, 0) })?;
+
+        // The socket only becomes writable once it's connected, or failed to
+        if poll_fd.revents == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+
+        match get_option::<libc::c_int>(self.fd.raw, libc::SOL_SOCKET, libc::SO_ERROR)? {
+            0 => Ok(()),
+            error => Err(Error::from_raw_os_error(error)),
+        }
+    }
 }
 
+impl_socket_options!(L2CAPStream);
+
 impl Read for L2CAPStream {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         self.fd.read(buf)
@@ -183,6 +398,229 @@ impl AsRawFd for L2CAPStream {
 
 impl Drop for L2CAPStream {
     fn drop(&mut self) {
-        self.fd.close();
+        let _ = self.fd.close();
+    }
+}
+
+/// An [`L2CAPListener`] that accepts without blocking the thread.
+#[derive(Debug)]
+pub struct AsyncL2CAPListener {
+    io: Async<L2CAPListener>,
+}
+
+impl AsyncL2CAPListener {
+    /// Switches `listener` to non-blocking mode.
+    pub fn new(listener: L2CAPListener) -> Result<AsyncL2CAPListener> {
+        Ok(AsyncL2CAPListener {
+            io: Async::new(listener)?,
+        })
+    }
+
+    /// Listens on `psm_port` of the adapter at `local`, or of every adapter with
+    /// [`BtAddr::ANY`].
+    pub fn bind(local: BtAddr, psm_port: u16) -> Result<AsyncL2CAPListener> {
+        let listener = L2CAPListener::new()?;
+        listener.bind(local, psm_port)?;
+        listener.listen(1)?;
+
+        AsyncL2CAPListener::new(listener)
+    }
+
+    /// Waits for the next connection, along with the address it came from.
+    pub async fn accept(&self) -> Result<(AsyncL2CAPStream, BtAddr)> {
+        let (stream, address) = self.io.with(|listener| listener.accept()).await?;
+
+        Ok((AsyncL2CAPStream::new(stream)?, address))
+    }
+
+    pub fn get_ref(&self) -> &L2CAPListener {
+        self.io.get_ref()
+    }
+}
+
+/// An [`L2CAPStream`] that reads, writes and connects without blocking the thread.
+#[derive(Debug)]
+pub struct AsyncL2CAPStream {
+    io: Async<L2CAPStream>,
+}
+
+impl AsyncL2CAPStream {
+    /// Switches `stream` to non-blocking mode.
+    pub fn new(stream: L2CAPStream) -> Result<AsyncL2CAPStream> {
+        Ok(AsyncL2CAPStream {
+            io: Async::new(stream)?,
+        })
+    }
+
+    /// Connects to `psm_port` on the device at `address`, going out through the adapter at
+    /// `local`. Gives up with [`ErrorKind::TimedOut`] if that takes longer than `timeout`.
+    pub async fn connect(
+        local: BtAddr,
+        address: BtAddr,
+        psm_port: u16,
+        timeout: Duration,
+    ) -> Result<AsyncL2CAPStream> {
+        let stream = L2CAPStream::new()?;
+        stream.bind(local)?;
+
+        let mut stream = AsyncL2CAPStream::new(stream)?;
+
+        match stream.io.get_mut().connect(address, psm_port) {
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {}
+            result => result?,
+        }
+
+        let connected = {
+            let connected = stream.io.with(|stream| stream.connect_finished());
+            futures::pin_mut!(connected);
+
+            match future::select(connected, Timer::after(timeout)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("connecting to {} timed out", address),
+                )),
+            }
+        };
+
+        connected?;
+
+        Ok(stream)
+    }
+
+    pub fn get_ref(&self) -> &L2CAPStream {
+        self.io.get_ref()
+    }
+
+    /// Switches back to blocking mode.
+    pub fn into_inner(self) -> Result<L2CAPStream> {
+        // Async only stops polling the socket, it leaves it non-blocking
+        let stream = self.io.into_inner()?;
+        let fd = stream.as_raw_fd();
+
+        let flags = libc_check_error(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+        libc_check_error(unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) })?;
+
+        Ok(stream)
+    }
+}
+
+impl AsyncRead for AsyncL2CAPStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncL2CAPStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.io).poll_close(cx)
+    }
+}
+
+impl AsRawFd for AsyncL2CAPStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.io.as_raw_fd()
+    }
+}
+
+fn l2cap_socket() -> Result<RawFd> {
+    libc_check_error(unsafe {
+        libc::socket(
+            libc::AF_BLUETOOTH,
+            libc::SOCK_SEQPACKET,
+            libbluetooth::bluetooth::BTPROTO_L2CAP,
+        )
+    })
+}
+
+fn bind(fd: RawFd, local: BtAddr, psm_port: u16) -> Result<()> {
+    let addr = socket_addr(local, psm_port);
+
+    let res = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const L2CAPSocketAddr as *const libc::sockaddr,
+            SOCKADDR_L2_LEN as u32,
+        )
+    };
+
+    libc_check_error(res)?;
+    Ok(())
+}
+
+fn socket_addr(address: BtAddr, psm_port: u16) -> L2CAPSocketAddr {
+    L2CAPSocketAddr {
+        l2_family: libbluetooth::bluetooth::AF_BLUETOOTH,
+        l2_psm: psm_port.to_le(),
+        l2_bdaddr: bdaddr_t {
+            b: address.convert_host_byteorder().0,
+        },
+        l2_cid: 0,
+        l2_bdaddr_type: 0,
     }
 }
+
+fn bt_addr(addr: &L2CAPSocketAddr) -> BtAddr {
+    BtAddr(addr.l2_bdaddr.b).convert_host_byteorder()
+}
+
+/// Asks `getname`, which is either `getsockname` or `getpeername`, for one of the socket's
+/// addresses.
+fn socket_address(
+    fd: RawFd,
+    getname: unsafe extern "C" fn(RawFd, *mut libc::sockaddr, *mut libc::socklen_t) -> libc::c_int,
+) -> Result<BtAddr> {
+    let mut addr: MaybeUninit<L2CAPSocketAddr> = MaybeUninit::zeroed();
+    let mut len = SOCKADDR_L2_LEN as libc::socklen_t;
+
+    libc_check_error(unsafe { getname(fd, addr.as_mut_ptr() as *mut libc::sockaddr, &mut len) })?;
+
+    Ok(bt_addr(&unsafe { addr.assume_init() }))
+}
+
+fn get_option<T: Copy>(fd: RawFd, level: i32, name: i32) -> Result<T> {
+    let mut value: MaybeUninit<T> = MaybeUninit::zeroed();
+    let mut len = size_of::<T>() as libc::socklen_t;
+
+    libc_check_error(unsafe {
+        libc::getsockopt(
+            fd,
+            level,
+            name,
+            value.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    })?;
+
+    Ok(unsafe { value.assume_init() })
+}
+
+fn set_option<T>(fd: RawFd, level: i32, name: i32, value: &T) -> Result<()> {
+    libc_check_error(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            size_of::<T>() as libc::socklen_t,
+        )
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+//! Pretend to be a Nintendo Switch controller over bluetooth, either by relaying a real
+//! controller or by emulating one outright.
+//!
+//! - [`l2cap`] has the sockets both the Switch and controllers are reached over, addressed with
+//!   [`BtAddr`]. [`transport`] stands in for them without a radio.
+//! - [`sdp`] and [`bluez`] get BlueZ to advertise us as a controller and accept the Switch.
+//! - [`input_report`], [`output_report`], [`subcommand`] and [`rumble`] decode and encode what
+//!   goes over the wire.
+//! - [`relay`] forwards a real controller, [`emulator`] stands in for one, backed by
+//!   [`spi_flash`]. [`protocol_log`] decodes what goes past as it happens, and [`remap`]
+//!   rearranges a relayed controller's buttons and sticks.
+//! - [`host`] talks to a controller the way the Switch does, and [`virtual_switch`] builds a
+//!   whole stand-in Switch on top of it. [`virtual_controller`] is its counterpart.
+//! - [`capture`] records the traffic of either mode for Wireshark, and [`replay`] plays a
+//!   recorded controller back to the Switch.
+
+pub mod bluez;
+pub mod bt_addr;
+pub mod capture;
+pub mod controller;
+pub mod ctrl_c;
+pub mod dbus_profile_manager;
+pub mod emulator;
+pub mod hid_descriptor;
+pub mod host;
+pub mod input_report;
+pub mod l2cap;
+pub mod mgmt;
+pub mod output_report;
+pub mod profile;
+pub mod protocol_log;
+pub mod relay;
+pub mod remap;
+pub mod replay;
+pub mod report;
+pub mod rumble;
+pub mod sdp;
+mod smol_fd;
+pub mod spi_flash;
+pub mod subcommand;
+pub mod transport;
+pub mod virtual_controller;
+pub mod virtual_switch;
+
+pub use bt_addr::BtAddr;
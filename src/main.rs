@@ -1,426 +1,586 @@
-use blurz::{BluetoothAdapter, BluetoothDevice, BluetoothDiscoverySession, BluetoothSession};
+use blurz::BluetoothSession;
+use structopt::StructOpt;
+
+use joycontrolrs::bluez::{
+    connect_hid, find_adapter, open_adapter, reconnect_to_switch, scan_for_bluetooth_controller,
+    scan_for_controllers, wait_for_switch, Adapter,
+};
+use joycontrolrs::capture::{self, Capture};
+use joycontrolrs::controller::ControllerType;
+use joycontrolrs::ctrl_c::{is_interrupted, CtrlC};
+use joycontrolrs::emulator::Emulator;
+use joycontrolrs::hid_descriptor::ReportDescriptor;
+use joycontrolrs::host::Host;
+use joycontrolrs::l2cap::AsyncL2CAPStream;
+use joycontrolrs::profile::HidProfile;
+use joycontrolrs::protocol_log::{LogFilter, ProtocolLog};
+use joycontrolrs::relay::{relay_controller, RelayOptions};
+use joycontrolrs::remap::Remap;
+use joycontrolrs::replay::Replay;
+use joycontrolrs::sdp::HidRecord;
+use joycontrolrs::spi_flash::{SpiFlash, SPI_FLASH_SIZE};
+use joycontrolrs::transport::HidChannels;
+use joycontrolrs::BtAddr;
 
-use std::collections::HashMap;
 use std::error::Error;
-use std::process::Command;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
 
-mod dbus_profile_manager;
-mod l2cap;
-mod smol_fd;
+use futures::future::{self, Either};
+use futures::prelude::*;
+use tracing::{error, info, info_span, Instrument, Level};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Relays or emulates Nintendo Switch controllers over bluetooth")]
+struct Opt {
+    /// Adapter to use, by name (hciN) or address. Defaults to the first one
+    #[structopt(short, long, global = true)]
+    adapter: Option<String>,
+
+    /// Log more: once for debug events, twice for every packet. RUST_LOG overrides this
+    #[structopt(short, long, global = true, parse(from_occurrences))]
+    verbose: u8,
+
+    /// How to write logs: text or json
+    #[structopt(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
 
-use l2cap::{L2CAPListener, L2CAPStream};
+#[derive(Debug, Clone, Copy)]
+enum LogFormat {
+    Text,
+    Json,
+}
 
-use dbus::arg::{RefArg, Variant};
-use dbus::blocking::Connection;
-use dbus_profile_manager::OrgBluezProfileManager1;
-use std::num::ParseIntError;
+impl FromStr for LogFormat {
+    type Err = String;
 
-use futures::prelude::*;
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {:?}, expected text or json", s)),
+        }
+    }
+}
 
-const CONTROLLERS: [&str; 3] = ["Pro Controller", "Joy-Con (L)", "Joy-Con (R)"];
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Forward everything between a real controller and the Switch
+    Relay {
+        /// Address of the controller, instead of the first one found
+        #[structopt(short, long)]
+        controller: Option<BtAddr>,
+
+        /// Connect to this already paired Switch instead of waiting for it
+        #[structopt(short, long, value_name = "SWITCH_MAC")]
+        reconnect: Option<BtAddr>,
+
+        /// Record every packet on both channels to this btsnoop file, which Wireshark opens
+        #[structopt(long, parse(from_os_str))]
+        capture: Option<PathBuf>,
+
+        /// Print every packet as it's forwarded, decoded, one line each
+        #[structopt(long)]
+        decode: bool,
+
+        /// Only decode reports with this id, in hex. Can be given more than once
+        #[structopt(
+            long = "report",
+            value_name = "ID",
+            number_of_values = 1,
+            requires = "decode",
+            parse(try_from_str = parse_id)
+        )]
+        reports: Vec<u8>,
+
+        /// Only decode subcommands, and replies to them, with this id, in hex. Can be given more
+        /// than once
+        #[structopt(
+            long = "subcommand",
+            value_name = "ID",
+            number_of_values = 1,
+            requires = "decode",
+            parse(try_from_str = parse_id)
+        )]
+        subcommands: Vec<u8>,
+
+        /// Rearrange the controller's buttons and sticks with the rules in this file
+        #[structopt(long, parse(from_os_str))]
+        remap: Option<PathBuf>,
+    },
+
+    /// Answer the Switch ourselves, without a real controller
+    Emulate {
+        /// Controller to pretend to be: pro, joycon-l or joycon-r
+        #[structopt(short = "t", long, default_value = "pro")]
+        controller_type: ControllerType,
+
+        /// SPI flash dump to load, and save writes from the Switch back to
+        #[structopt(long, parse(from_os_str))]
+        spi_dump: Option<PathBuf>,
+
+        /// Connect to this already paired Switch instead of waiting for it
+        #[structopt(short, long, value_name = "SWITCH_MAC")]
+        reconnect: Option<BtAddr>,
+
+        /// Record every packet on both channels to this btsnoop file, which Wireshark opens
+        #[structopt(long, parse(from_os_str))]
+        capture: Option<PathBuf>,
+    },
+
+    /// Play the controller's side of a capture back to the Switch
+    Replay {
+        /// Capture to play back, from --capture
+        #[structopt(parse(from_os_str))]
+        capture: PathBuf,
+
+        /// How much faster than recorded to play it back
+        #[structopt(long, default_value = "1.0")]
+        speed: f64,
+
+        /// Controller to advertise as, instead of the one in the capture
+        #[structopt(short = "t", long)]
+        controller_type: Option<ControllerType>,
+
+        /// Connect to this already paired Switch instead of waiting for it
+        #[structopt(short, long, value_name = "SWITCH_MAC")]
+        reconnect: Option<BtAddr>,
+    },
+
+    /// List the controllers in pairing mode
+    Scan {
+        /// How long to scan for, in seconds
+        #[structopt(long, default_value = "10")]
+        timeout: u64,
+    },
+
+    /// Read a real controller's whole SPI flash into a file
+    DumpSpi {
+        /// Address of the controller, instead of the first one found
+        #[structopt(short, long)]
+        controller: Option<BtAddr>,
+
+        /// Where to save the dump
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+}
 
-macro_rules! insert {
-    ($map:ident, $key:expr, $val:expr) => {
-        $map.insert($key, Variant(Box::new($val) as Box<dyn RefArg>));
+fn main() -> Result<(), Box<dyn Error>> {
+    let opt = Opt::from_args();
+    init_logging(opt.verbose, opt.log_format);
+
+    let adapter = find_adapter(opt.adapter.as_deref())?;
+
+    info!(%adapter, "Using adapter");
+
+    let result = match opt.command {
+        Command::Relay {
+            controller,
+            reconnect,
+            ref capture,
+            decode,
+            ref reports,
+            ref subcommands,
+            ref remap,
+        } => {
+            let filter = LogFilter {
+                reports: reports.clone(),
+                subcommands: subcommands.clone(),
+            };
+
+            relay(
+                &adapter,
+                controller,
+                reconnect,
+                capture.as_ref(),
+                if decode { Some(filter) } else { None },
+                remap.as_ref(),
+            )
+        }
+        Command::Emulate {
+            controller_type,
+            ref spi_dump,
+            reconnect,
+            ref capture,
+        } => emulate(
+            &adapter,
+            controller_type,
+            spi_dump.as_ref(),
+            reconnect,
+            capture.as_ref(),
+        ),
+        Command::Replay {
+            ref capture,
+            speed,
+            controller_type,
+            reconnect,
+        } => replay(&adapter, capture, speed, controller_type, reconnect),
+        Command::Scan { timeout } => scan(&adapter, Duration::from_secs(timeout)),
+        Command::DumpSpi {
+            controller,
+            ref output,
+        } => dump_spi(&adapter, controller, output),
     };
-}
 
-fn scan_for_bluetooth_controller<'a>(
-    session: &'a BluetoothSession,
-    adapter: &'a BluetoothAdapter,
-) -> BluetoothDevice<'a> {
-    let discovery = BluetoothDiscoverySession::create_session(&session, adapter.get_id()).unwrap();
-    discovery.start_discovery().unwrap();
+    match result {
+        Err(e) if is_interrupted(&*e) => {
+            info!("Interrupted, closing everything");
+            Ok(())
+        }
+        result => result,
+    }
+}
 
-    println!("Will start to scan for controllers.");
+/// Answers the Switch ourselves, without a real controller behind us. If `spi_dump` is given the
+/// controller's SPI flash is loaded from, and saved back to, that file.
+fn emulate(
+    adapter: &Adapter,
+    controller: ControllerType,
+    spi_dump: Option<&PathBuf>,
+    reconnect: Option<BtAddr>,
+    capture: Option<&PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let spi_flash = match spi_dump {
+        Some(path) => {
+            info!(path = %path.display(), "Loading SPI flash");
+            SpiFlash::load(path)?
+        }
+        None => SpiFlash::new(controller),
+    };
 
-    let bt_controller = 'outer_loop: loop {
-        let devices = adapter.get_device_list().unwrap();
+    let record = hid_record(controller)?;
 
-        'device_loop: for device in devices {
-            let bt_device = blurz::bluetooth_device::BluetoothDevice::new(&session, device);
+    let mut emulator = Emulator::new(controller, adapter.address, spi_flash);
 
-            let id = bt_device.get_id();
-            let rssi = bt_device.get_rssi();
-            let alias = bt_device.get_alias().unwrap_or(String::new());
+    if let Some(capture) = create_capture(capture)? {
+        emulator.set_capture(capture);
+    }
 
-            if rssi.is_ok() {
-                println!("Found device: '{}' ({})", &alias, &id);
-            } else {
-                continue 'device_loop;
-            }
+    if reconnect.is_some() {
+        // The Switch set this up last time and won't always ask again
+        emulator.set_input_mode(0x30);
+    }
 
-            if CONTROLLERS.contains(&alias.as_str()) {
-                println!("Found {}. Will connect after restart.", &alias);
+    let ctrl_c = CtrlC::catch()?;
 
-                discovery.stop_discovery().unwrap();
+    let result = smol::run(ctrl_c.until(async {
+        // The Switch never needs anything from us on ctl, but the channel has to stay open.
+        let (_switch_ctl, switch_itr, _profile) =
+            connect_to_switch(adapter, &record, controller.name(), reconnect).await?;
 
-                break 'outer_loop bt_device;
-            }
-        }
+        info!(%controller, "Emulating");
 
-        std::thread::sleep(Duration::from_secs(5));
-    };
+        let (sw_itr_r, sw_itr_w) = switch_itr.split();
+        emulator.run(sw_itr_r, sw_itr_w).await?;
 
-    return bt_controller;
-}
+        Ok(())
+    }));
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-struct BtAddr(pub [u8; 6]);
+    // Even after Ctrl-C, which drops the emulator's future halfway through
+    emulator.save_spi_flash()?;
 
-impl BtAddr {
-    pub fn from_str(addr_str: &str) -> Result<BtAddr, ParseIntError> {
-        let mut addr = [0; 6];
+    result
+}
 
-        for i in 0..6 {
-            addr[i] = u8::from_str_radix(&addr_str[i * 3..i * 3 + 2], 16)?;
+/// Forwards everything between a real controller and the Switch. With `decode` every packet that
+/// gets past the filter is decoded as it's forwarded, and with `remap` the controller's input is
+/// rewritten by the rules in that file.
+fn relay(
+    adapter: &Adapter,
+    controller_addr: Option<BtAddr>,
+    reconnect: Option<BtAddr>,
+    capture: Option<&PathBuf>,
+    decode: Option<LogFilter>,
+    remap: Option<&PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    // Before connecting anything, so a mistake in the file doesn't cost a pairing
+    let remap = match remap {
+        Some(path) => {
+            info!(path = %path.display(), "Loading remap");
+            Some(Remap::load(path)?)
         }
+        None => None,
+    };
 
-        return Ok(BtAddr(addr));
-    }
-
-    /// Linux lower-layers actually hold the address in native byte-order
-    /// althrough they are always displayed in network byte-order
-    #[inline(always)]
-    #[cfg(target_endian = "little")]
-    pub fn convert_host_byteorder(mut self) -> BtAddr {
-        {
-            let (value_1, value_2) = (&mut self.0).split_at_mut(3);
-            std::mem::swap(&mut value_1[0], &mut value_2[2]);
-            std::mem::swap(&mut value_1[1], &mut value_2[1]);
-            std::mem::swap(&mut value_1[2], &mut value_2[0]);
+    let session = BluetoothSession::create_session(None)?;
+    let bt_adapter = open_adapter(&session, adapter)?;
+
+    let ctrl_c = CtrlC::catch()?;
+
+    let controller = smol::run(ctrl_c.until(scan_for_bluetooth_controller(
+        &session,
+        &bt_adapter,
+        controller_addr,
+    )))?;
+    let controller_name = controller.get_alias()?;
+    let controller_btaddr: BtAddr = controller.get_address()?.parse()?;
+
+    info!(name = %controller_name, address = %controller_btaddr, "Using controller");
+
+    // A controller picked by address may have been renamed
+    let controller_type =
+        ControllerType::from_name(&controller_name).unwrap_or(ControllerType::ProController);
+
+    let record = hid_record(controller_type)?;
+    let capture = create_capture(capture)?;
+
+    smol::run(ctrl_c.until(async {
+        let (mut controller_ctl, controller_itr) =
+            connect_to_controller(adapter, controller_btaddr).await?;
+
+        // The Switch can take a while, and there's no point waiting on it without a controller
+        let (switch_ctl, switch_itr, _profile) = {
+            let switch = connect_to_switch(adapter, &record, &controller_name, reconnect);
+            let controller_gone = disconnected(&mut controller_ctl);
+            futures::pin_mut!(switch, controller_gone);
+
+            match future::select(switch, controller_gone).await {
+                Either::Left((switch, _)) => switch?,
+                Either::Right(_) => {
+                    return Err("controller disconnected before the switch connected".into())
+                }
+            }
+        };
+
+        if reconnect.is_some() {
+            info!("Forwarding all data from controller to switch");
+        } else {
+            info!(
+                "Forwarding all data from controller to switch. \
+                 Exit the change grip menu even if it hasn't paired yet."
+            );
         }
 
-        self
-    }
+        let options = RelayOptions {
+            capture,
+            log: decode.map(ProtocolLog::new),
+            remap,
+        };
+
+        // Every event from the session carries both ends
+        let span = info_span!(
+            "relay",
+            controller = %controller_btaddr,
+            switch = %switch_itr.get_ref().peer_address()?,
+        );
+
+        relay_controller(
+            switch_ctl,
+            switch_itr,
+            controller_ctl,
+            controller_itr,
+            adapter.address,
+            options,
+        )
+        .instrument(span)
+        .await?;
 
-    #[inline(always)]
-    #[cfg(target_endian = "big")]
-    pub fn convert_host_byteorder(self) -> BtAddr {
-        // Public address structure contents are always big-endian
-        self
-    }
-}
+        // Everything is closed on drop
 
-impl std::fmt::Display for BtAddr {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
-            self.0[0], self.0[1], self.0[2], self.0[3], self.0[4], self.0[5]
-        )
-    }
+        Ok(())
+    }))
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let session = BluetoothSession::create_session(None).unwrap();
-    let adapter = BluetoothAdapter::init(&session)?;
-    let adapter_addr = BtAddr::from_str(&adapter.get_address().unwrap()).unwrap();
+/// Plays the controller's side of `capture` back to the Switch, `speed` times faster than it was
+/// recorded.
+fn replay(
+    adapter: &Adapter,
+    capture: &PathBuf,
+    speed: f64,
+    controller: Option<ControllerType>,
+    reconnect: Option<BtAddr>,
+) -> Result<(), Box<dyn Error>> {
+    info!(path = %capture.display(), "Loading capture");
+
+    let mut replay = Replay::new(capture::load(capture)?, adapter.address);
+    replay.set_speed(speed)?;
 
-    let controller = scan_for_bluetooth_controller(&session, &adapter);
-    let controller_addr = controller.get_address().unwrap();
-    let controller_name = controller.get_alias().unwrap();
+    let controller = controller
+        .or_else(|| replay.controller())
+        .unwrap_or(ControllerType::ProController);
 
-    println!("{}: {}", controller_name, controller_addr);
-    let controller_btaddr = BtAddr::from_str(&controller_addr).unwrap();
-    let converted_btaddr = controller_btaddr.convert_host_byteorder();
+    let record = hid_record(controller)?;
 
-    let mut ctl_server_l2cap = L2CAPListener::new()?;
-    let mut itr_server_l2cap = L2CAPListener::new()?;
+    let ctrl_c = CtrlC::catch()?;
 
-    println!("Restarting bluetooth service...");
+    smol::run(ctrl_c.until(async {
+        let (ctl, itr, _profile) =
+            connect_to_switch(adapter, &record, controller.name(), reconnect).await?;
 
-    let mut cmd = Command::new("systemctl");
-    cmd.arg("restart");
-    cmd.arg("bluetooth.service");
-    cmd.spawn().unwrap().wait().unwrap();
+        info!(%controller, "Replaying");
 
-    std::thread::sleep(Duration::from_secs(1));
+        replay.run(HidChannels { ctl, itr }).await?;
 
-    println!("Connecting to controller.");
+        Ok(())
+    }))
+}
+
+/// Lists the controllers that are in pairing mode.
+fn scan(adapter: &Adapter, timeout: Duration) -> Result<(), Box<dyn Error>> {
+    let session = BluetoothSession::create_session(None)?;
+    let bt_adapter = open_adapter(&session, adapter)?;
 
-    let mut controller_ctl_l2cap = L2CAPStream::new().unwrap();
-    let mut controller_itr_l2cap = L2CAPStream::new().unwrap();
+    info!(seconds = timeout.as_secs(), "Scanning");
 
-    if let Err(e) = controller_ctl_l2cap.connect(converted_btaddr.0, 17) {
-        println!("Could not connect to controller");
-        return Err(e)?;
+    let controllers = scan_for_controllers(&session, &bt_adapter, timeout)?;
+
+    if controllers.is_empty() {
+        println!("No controllers found. Hold the sync button to put one in pairing mode.");
     }
 
-    if let Err(e) = controller_itr_l2cap.connect(converted_btaddr.0, 19) {
-        println!("Could not connect to controller");
-        return Err(e)?;
+    for (address, controller) in controllers {
+        println!("{}: {}", address, controller);
     }
 
-    println!("Binding server to necessary ports. This will fail if we aren't root.");
+    Ok(())
+}
+
+/// Reads a real controller's whole SPI flash, for `emulate` to load later.
+fn dump_spi(
+    adapter: &Adapter,
+    controller_addr: Option<BtAddr>,
+    output: &PathBuf,
+) -> Result<(), Box<dyn Error>> {
+    let session = BluetoothSession::create_session(None)?;
+    let bt_adapter = open_adapter(&session, adapter)?;
 
-    ctl_server_l2cap.bind(17)?;
-    itr_server_l2cap.bind(19)?;
+    let ctrl_c = CtrlC::catch()?;
 
-    ctl_server_l2cap.listen(1)?;
-    itr_server_l2cap.listen(1)?;
+    let controller = smol::run(ctrl_c.until(scan_for_bluetooth_controller(
+        &session,
+        &bt_adapter,
+        controller_addr,
+    )))?;
+    let controller_btaddr: BtAddr = controller.get_address()?.parse()?;
 
-    println!("Changing name and class");
+    let spi_flash = smol::run(ctrl_c.until(async {
+        let (_controller_ctl, controller_itr) =
+            connect_to_controller(adapter, controller_btaddr).await?;
 
-    let session = BluetoothSession::create_session(None)?;
-    let adapter = BluetoothAdapter::init(&session)?;
-
-    adapter.set_alias(controller_name)?;
-
-    let mut cmd = Command::new("hciconfig");
-    cmd.arg("hci0");
-    cmd.arg("class");
-    cmd.arg("0x002508");
-    cmd.spawn().unwrap().wait().unwrap();
-
-    println!("Advertising the Bluetooth SDP record...");
-    println!("Please open the \"Change Grip/Order\" menu.");
-
-    const PROFILE_DATA: &str = include_str!("../sdp_record_hid.xml");
-    const HID_UUID: &str = "00001124-0000-1000-8000-00805f9b34fb";
-    const HID_PATH: &str = "/bluez/switch/hid";
-
-    let conn = Connection::new_system()?;
-    let proxy = conn.with_proxy("org.bluez", "/org/bluez", Duration::from_millis(5000));
-
-    let mut options = HashMap::new();
-    insert!(options, "ServiceRecord", PROFILE_DATA.to_string());
-    insert!(options, "Role", "server".to_string());
-    insert!(options, "Service", HID_UUID.to_string());
-    insert!(options, "RequireAuthentication", false);
-    insert!(options, "RequireAuthorization", false);
-
-    let my_uuid = uuid::Uuid::new_v4().to_string();
-    proxy.register_profile(dbus::Path::from(HID_PATH), &my_uuid, options)?;
-
-    adapter.set_pairable(true)?;
-    adapter.set_discoverable(true)?;
-
-    println!("Connecting with the Switch... Please open the \"Change Grip/Order\" menu.");
-
-    let (switch_ctl_l2cap, ctl_addr) = ctl_server_l2cap.accept()?;
-    let (switch_itr_l2cap, itr_addr) = itr_server_l2cap.accept()?;
-
-    assert_eq!(ctl_addr.l2_bdaddr.b, itr_addr.l2_bdaddr.b);
-
-    let address = BtAddr(ctl_addr.l2_bdaddr.b).convert_host_byteorder();
-
-    println!("Connected to switch at {}", address);
-
-    println!("Forwarding all data from controller to switch. Exit the change grip menu even if it hasn't paired yet.");
-
-    let switch_ctl = smol::Async::new(switch_ctl_l2cap).unwrap();
-    let switch_itr = smol::Async::new(switch_itr_l2cap).unwrap();
-    let controller_ctl = smol::Async::new(controller_ctl_l2cap).unwrap();
-    let controller_itr = smol::Async::new(controller_itr_l2cap).unwrap();
-
-    
-    // let ctl_relay = std::thread::spawn(move || {
-    //     let (mut sw_ctl_r, mut sw_ctl_w) = switch_ctl.split();
-    //     let (mut cn_ctl_r, mut cn_ctl_w) = controller_ctl.split();
-
-    //     smol::run(async {
-    //         let mut controller_incoming = [0u8; 512];
-    //         let mut switch_incoming = [0u8; 512];
-            
-    //         let mut sw_r = sw_ctl_r.read(&mut switch_incoming);
-    //         let mut cn_r = cn_ctl_r.read(&mut controller_incoming);
-            
-    //         let mut total_read_from_cn = 0;
-    //         let mut total_read_from_sw = 0;
-            
-    //         loop {
-    //             let both_r = futures::future::select(sw_r, cn_r);
-
-    //             match both_r.await {
-    //                 // Read successfully from switch
-    //                 future::Either::Left((Ok(n), old_cn_r)) => {
-    //                     total_read_from_sw += n;
-
-    //                     if n == 0 {
-    //                         println!("Read 0 bytes from switch ctl. Closing");
-    //                         break;
-    //                     }
-
-    //                     cn_ctl_w.write_all(&switch_incoming[0..n]).await.unwrap();
-                        
-    //                     cn_r = old_cn_r;
-    //                     sw_r = sw_ctl_r.read(&mut switch_incoming);
-    //                 },
-                    
-    //                 // Read successfully from controller
-    //                 future::Either::Right((Ok(n), old_sw_r)) => {
-    //                     total_read_from_cn += n;
-
-    //                     if n == 0 {
-    //                         println!("Read 0 bytes from controll itr. Closing");
-    //                         break;
-    //                     }
-
-    //                     // dbg!(n);
-
-    //                     sw_ctl_w.write_all(&controller_incoming[0..n]).await.unwrap();
-                        
-    //                     sw_r = old_sw_r;
-    //                     cn_r = cn_ctl_r.read(&mut controller_incoming);
-    //                 },
-                    
-    //                 // Read failed from switch
-    //                 future::Either::Left((Err(e), _old_cn_r)) => {
-    //                     println!("Read from switch failed: {}", e);
-    //                     break;
-    //                 },
-
-    //                 // Read failed from controller
-    //                 future::Either::Right((Err(e), _old_sw_r)) => {
-    //                     println!("Read from controller failed: {}", e);
-    //                     break;
-    //                 },
-    //             };
-    //         }
-            
-    //         println!("CTL finished.");
-    //         println!("Total bytes from from controller: {}", total_read_from_cn);
-    //         println!("Total bytes from from switch    : {}", total_read_from_sw);
-    //     });
-    // });
-
-
-    // let itr_relay = std::thread::spawn(move || {
-        let (mut sw_itr_r, mut sw_itr_w) = switch_itr.split();
-        let (mut cn_itr_r, mut cn_itr_w) = controller_itr.split();
-
-        smol::run(async {
-            let mut controller_incoming = [0u8; 128];
-            let mut switch_incoming = [0u8; 128];
-
-            let mut last_cn_len = 0;
-            let mut last_sw_len = 0;
-            
-            let mut sw_r = sw_itr_r.read(&mut switch_incoming);
-            let mut cn_r = cn_itr_r.read(&mut controller_incoming);
-
-            let mut total_read_from_cn = 0;
-            let mut total_read_from_sw = 0;
-
-            loop {
-                let both_r = futures::future::select(sw_r, cn_r);
-
-                match both_r.await {
-                    // Read successfully from switch
-                    future::Either::Left((Ok(n), old_cn_r)) => {
-                        total_read_from_sw += n;
-                        last_sw_len = n;
-
-                        if n == 0 {
-                            println!("Read 0 bytes from switch itr. Closing");
-                            break;
-                        }
-
-                        cn_itr_w.write_all(&switch_incoming[0..n]).await.unwrap();
-                        
-                        cn_r = old_cn_r;
-                        sw_r = sw_itr_r.read(&mut switch_incoming);
-                    },
-
-                    // Read successfully from controller
-                    future::Either::Right((Ok(n), old_sw_r)) => {
-                        total_read_from_cn += n;
-                        last_cn_len = n;
-
-                        if n == 0 {
-                            println!("Read 0 bytes from controller itr. Closing");
-                            break;
-                        }
-                        
-                        // dbg!(n);
-
-                        let hid_packet = &mut controller_incoming[1..n];
-
-                        if n == 50 && (hid_packet[0], hid_packet[14]) == (0x21, 0x02) {
-                            println!("Got a device info packet.");
-
-                            let mut old_addr = BtAddr([0; 6]);
-                            old_addr.0.copy_from_slice(&hid_packet[19..25]);
-                            
-                            println!("Old address: {}", old_addr);
-
-                            hid_packet[19..25].copy_from_slice(&adapter_addr.0[..]);
-                            
-                            println!("New address: {}", adapter_addr);
-                        }
-                        
-                        sw_itr_w.write_all(&controller_incoming[0..n]).await.unwrap();
-
-                        sw_r = old_sw_r;
-                        cn_r = cn_itr_r.read(&mut controller_incoming);
-                    },
-
-                    // Read failed from switch
-                    future::Either::Left((Err(e), _old_cn_r)) => {
-                        println!("Read from switch failed: {}", e);
-                        break;
-                    },
-
-                    // Read failed from controller
-                    future::Either::Right((Err(e), _old_sw_r)) => {
-                        println!("Read from controller failed: {}", e);
-                        break;
-                    },
-                };
-            }
+        let (cn_itr_r, cn_itr_w) = controller_itr.split();
+
+        info!("Reading SPI flash. This takes a few minutes.");
+
+        let mut host = Host::new(cn_itr_r, cn_itr_w);
+        let mut next_progress = 0;
 
-            println!("ITR finished.");
-            println!("Dumping last read from controller");
-            println!("{}", hexdump(&controller_incoming[..last_cn_len]));
-            
-            println!("Dumping last read from switch");
-            println!("{}", hexdump(&switch_incoming[..last_sw_len]));
+        let spi_flash = host
+            .dump_spi_flash(|read| {
+                if read >= next_progress {
+                    info!(read, total = SPI_FLASH_SIZE, "Reading SPI flash");
+                    next_progress += SPI_FLASH_SIZE / 16;
+                }
+            })
+            .await?;
 
-            println!("Total bytes from from controller: {}", total_read_from_cn);
-            println!("Total bytes from from switch    : {}", total_read_from_sw);
-        });
-    // });
+        Ok(spi_flash)
+    }))?;
 
-    // ctl_relay.join().unwrap();
-    // itr_relay.join().unwrap();
+    spi_flash.save(output)?;
 
-    // Everything is closed on drop
+    info!(path = %output.display(), "Saved SPI flash");
 
     Ok(())
 }
 
+/// Logs to stderr, as `format`, at info level or more with `verbose`, unless `RUST_LOG` says
+/// otherwise. Stdout is left to `scan`'s results.
+fn init_logging(verbose: u8, format: LogFormat) {
+    let level = match verbose {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
 
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(level.to_string().to_lowercase()));
 
-use std::fmt::Write as FmtWrite;
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
 
-fn hexdump(buf: &[u8]) -> String {
-    let mut out = String::with_capacity(buf.len() * 4);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
 
-    for chunk in buf.chunks(16) {
-        for byte in chunk {
-            write!(out, "{:02x} ", byte);
+/// Parses a report or subcommand id, in hex with or without the 0x.
+fn parse_id(id: &str) -> Result<u8, std::num::ParseIntError> {
+    u8::from_str_radix(id.trim_start_matches("0x"), 16)
+}
+
+/// Starts a capture at `path`, if there is one.
+fn create_capture(path: Option<&PathBuf>) -> Result<Option<Capture>, Box<dyn Error>> {
+    match path {
+        Some(path) => {
+            info!(path = %path.display(), "Capturing");
+            Ok(Some(Capture::create(path)?))
         }
-        
-        for _ in 0..16 - chunk.len() {
-            out.push_str("   ");
+        None => Ok(None),
+    }
+}
+
+/// The record to advertise as `controller`, checked against the reports we send and expect.
+fn hid_record(controller: ControllerType) -> Result<HidRecord, Box<dyn Error>> {
+    let record = HidRecord::for_controller(controller);
+    Emulator::check_descriptor(&ReportDescriptor::parse(&record.report_descriptor)?)?;
+
+    Ok(record)
+}
+
+/// Reconnects to the Switch at `reconnect`, or otherwise advertises ourselves as `name`, with
+/// `record`, and waits for the Switch to connect. Returns the ctl and itr streams, in that order,
+/// and the profile they came through, if any.
+async fn connect_to_switch(
+    adapter: &Adapter,
+    record: &HidRecord,
+    name: &str,
+    reconnect: Option<BtAddr>,
+) -> Result<(AsyncL2CAPStream, AsyncL2CAPStream, Option<HidProfile>), Box<dyn Error>> {
+    match reconnect {
+        Some(switch) => {
+            let (ctl, itr) = reconnect_to_switch(adapter, switch).await?;
+            Ok((ctl, itr, None))
         }
-        
-        out.push(' ');
-        
-        for byte in chunk {
-            let c = *byte as char;
-
-            if c.is_alphanumeric() {
-                out.push(c);
-            } else {
-                out.push('.');
-            }
+        None => {
+            let (ctl, itr, profile) = wait_for_switch(adapter, name, &record.to_record()).await?;
+            Ok((ctl, itr, Some(profile)))
         }
+    }
+}
+
+/// Connects to both of a controller's channels. Returns the ctl and itr streams, in that order.
+async fn connect_to_controller(
+    adapter: &Adapter,
+    address: BtAddr,
+) -> Result<(AsyncL2CAPStream, AsyncL2CAPStream), Box<dyn Error>> {
+    info!(%address, "Connecting to controller");
+
+    match connect_hid(adapter, address).await {
+        Ok(streams) => Ok(streams),
+        Err(e) => {
+            error!(%address, error = %e, "Could not connect to controller");
+            Err(e.into())
+        }
+    }
+}
+
+/// Waits for a controller to hang up on `ctl`. Nothing is sent on ctl until the Switch is talking
+/// to the controller, so anything read is thrown away.
+async fn disconnected(ctl: &mut AsyncL2CAPStream) {
+    let mut buf = [0u8; 64];
 
-        out.push('\n');
+    while let Ok(n) = ctl.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
     }
-    
-    out
-}
\ No newline at end of file
+}
@@ -0,0 +1,608 @@
+//! A client for the kernel's Bluetooth management API, the HCI control channel bluetoothd itself
+//! uses to configure adapters.
+//!
+//! Every packet, both ways, is a 6 byte header (opcode, adapter index and parameter length, all
+//! little endian) followed by the parameters. Each command is answered with a Command Complete or
+//! Command Status event for the same opcode and index, possibly after unrelated events.
+
+use crate::smol_fd::{libc_check_error, SmolFd};
+use crate::BtAddr;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+type HciSocketAddr = libbluetooth::hci::sockaddr_hci;
+
+const MGMT_HEADER_LEN: usize = 6;
+/// Big enough for any event the kernel sends.
+const MGMT_BUFFER_LEN: usize = 1024;
+
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+
+const MGMT_OP_READ_INDEX_LIST: u16 = 0x0003;
+const MGMT_OP_READ_INFO: u16 = 0x0004;
+const MGMT_OP_SET_POWERED: u16 = 0x0005;
+const MGMT_OP_SET_DISCOVERABLE: u16 = 0x0006;
+const MGMT_OP_SET_CONNECTABLE: u16 = 0x0007;
+const MGMT_OP_SET_BONDABLE: u16 = 0x0009;
+const MGMT_OP_SET_DEV_CLASS: u16 = 0x000E;
+const MGMT_OP_SET_LOCAL_NAME: u16 = 0x000F;
+
+/// Index for commands that aren't about any one adapter.
+const MGMT_INDEX_NONE: u16 = 0xFFFF;
+
+/// Length of a Read Controller Information reply.
+const MGMT_INFO_LEN: usize = 280;
+
+/// Including the terminating NUL.
+const MGMT_MAX_NAME_LENGTH: usize = 249;
+const MGMT_MAX_SHORT_NAME_LENGTH: usize = 11;
+
+#[derive(Debug)]
+pub enum MgmtError {
+    Io(io::Error),
+    /// The kernel answered the command with a non-zero status.
+    Failed {
+        opcode: u16,
+        status: u8,
+    },
+    /// The kernel sent something that isn't a valid event.
+    BadEvent(Vec<u8>),
+}
+
+impl MgmtError {
+    fn status_name(status: u8) -> &'static str {
+        match status {
+            0x01 => "unknown command",
+            0x02 => "not connected",
+            0x03 => "failed",
+            0x04 => "connect failed",
+            0x05 => "authentication failed",
+            0x06 => "not paired",
+            0x07 => "no resources",
+            0x08 => "timeout",
+            0x09 => "already connected",
+            0x0A => "busy",
+            0x0B => "rejected",
+            0x0C => "not supported",
+            0x0D => "invalid parameters",
+            0x0E => "disconnected",
+            0x0F => "not powered",
+            0x10 => "cancelled",
+            0x11 => "invalid index",
+            0x12 => "blocked through rfkill",
+            0x13 => "already paired",
+            0x14 => "permission denied",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for MgmtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MgmtError::Io(e) => write!(f, "mgmt socket error: {}", e),
+            MgmtError::Failed { opcode, status } => write!(
+                f,
+                "mgmt command {:#06x} failed: {} ({:#04x})",
+                opcode,
+                MgmtError::status_name(*status),
+                status
+            ),
+            MgmtError::BadEvent(event) => write!(f, "malformed mgmt event {:02x?}", event),
+        }
+    }
+}
+
+impl std::error::Error for MgmtError {}
+
+impl From<io::Error> for MgmtError {
+    fn from(e: io::Error) -> MgmtError {
+        MgmtError::Io(e)
+    }
+}
+
+/// Discoverable modes for [`Mgmt::set_discoverable`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Discoverable {
+    Off = 0x00,
+    General = 0x01,
+    Limited = 0x02,
+}
+
+/// An adapter's address, settings and names, as read with [`Mgmt::read_info`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ControllerInfo {
+    pub address: BtAddr,
+    pub bluetooth_version: u8,
+    pub manufacturer: u16,
+    /// Bitmasks of MGMT_SETTING_* flags.
+    pub supported_settings: u32,
+    pub current_settings: u32,
+    pub class: u32,
+    pub name: String,
+    pub short_name: String,
+}
+
+/// A raw socket bound to the HCI control channel. Every read and write is a whole packet.
+pub struct MgmtSocket {
+    fd: SmolFd,
+}
+
+impl MgmtSocket {
+    pub fn new() -> io::Result<MgmtSocket> {
+        let socket = libc_check_error(unsafe {
+            libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libbluetooth::bluetooth::BTPROTO_HCI,
+            )
+        })?;
+
+        let mgmt_socket = MgmtSocket {
+            fd: SmolFd::new(socket),
+        };
+
+        let addr = HciSocketAddr {
+            hci_family: libbluetooth::bluetooth::AF_BLUETOOTH,
+            hci_dev: libbluetooth::hci::HCI_DEV_NONE as u16,
+            hci_channel: libbluetooth::hci::HCI_CHANNEL_CONTROL as u16,
+        };
+
+        libc_check_error(unsafe {
+            libc::bind(
+                mgmt_socket.fd.raw,
+                &addr as *const HciSocketAddr as *const libc::sockaddr,
+                size_of::<HciSocketAddr>() as u32,
+            )
+        })?;
+
+        Ok(mgmt_socket)
+    }
+}
+
+impl Read for MgmtSocket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fd.read(buf)
+    }
+}
+
+impl Write for MgmtSocket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.fd.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.fd.flush()
+    }
+}
+
+impl FromRawFd for MgmtSocket {
+    unsafe fn from_raw_fd(fd: RawFd) -> MgmtSocket {
+        MgmtSocket {
+            fd: SmolFd::from_raw_fd(fd),
+        }
+    }
+}
+
+impl AsRawFd for MgmtSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Drop for MgmtSocket {
+    fn drop(&mut self) {
+        let _ = self.fd.close();
+    }
+}
+
+/// Sends management commands over `S`, which must keep packet boundaries like [`MgmtSocket`]
+/// does. Anything that does, like one end of a seqpacket socket pair, can stand in for the
+/// kernel.
+///
+/// Adapters are addressed by index, the `N` in `hciN`.
+pub struct Mgmt<S> {
+    socket: S,
+}
+
+impl Mgmt<MgmtSocket> {
+    /// Opens the kernel's control channel. Changing settings needs CAP_NET_ADMIN.
+    pub fn open() -> Result<Mgmt<MgmtSocket>, MgmtError> {
+        Ok(Mgmt::new(MgmtSocket::new()?))
+    }
+}
+
+impl<S: Read + Write> Mgmt<S> {
+    pub fn new(socket: S) -> Mgmt<S> {
+        Mgmt { socket }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    /// Sends a command and waits for its reply, returning the reply's parameters. Events for
+    /// other commands or adapters are skipped.
+    pub fn send_command(
+        &mut self,
+        opcode: u16,
+        index: u16,
+        params: &[u8],
+    ) -> Result<Vec<u8>, MgmtError> {
+        let mut packet = Vec::with_capacity(MGMT_HEADER_LEN + params.len());
+        packet.extend_from_slice(&opcode.to_le_bytes());
+        packet.extend_from_slice(&index.to_le_bytes());
+        packet.extend_from_slice(&(params.len() as u16).to_le_bytes());
+        packet.extend_from_slice(params);
+
+        self.socket.write_all(&packet)?;
+
+        let mut buf = [0u8; MGMT_BUFFER_LEN];
+
+        loop {
+            let n = self.socket.read(&mut buf)?;
+
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+
+            let event = &buf[..n];
+
+            if n < MGMT_HEADER_LEN {
+                return Err(MgmtError::BadEvent(event.to_vec()));
+            }
+
+            let event_code = u16::from_le_bytes([event[0], event[1]]);
+            let event_index = u16::from_le_bytes([event[2], event[3]]);
+            let event_params = &event[MGMT_HEADER_LEN..];
+
+            let is_reply = event_code == MGMT_EV_CMD_COMPLETE || event_code == MGMT_EV_CMD_STATUS;
+
+            if !is_reply || event_index != index {
+                continue;
+            }
+
+            // Both start with the opcode and status
+            if event_params.len() < 3 {
+                return Err(MgmtError::BadEvent(event.to_vec()));
+            }
+
+            if u16::from_le_bytes([event_params[0], event_params[1]]) != opcode {
+                continue;
+            }
+
+            return match event_params[2] {
+                0x00 => Ok(event_params[3..].to_vec()),
+                status => Err(MgmtError::Failed { opcode, status }),
+            };
+        }
+    }
+
+    /// Indexes of every adapter the kernel knows about.
+    pub fn read_index_list(&mut self) -> Result<Vec<u16>, MgmtError> {
+        let reply = self.send_command(MGMT_OP_READ_INDEX_LIST, MGMT_INDEX_NONE, &[])?;
+
+        let count = match reply.get(..2) {
+            Some(count) => u16::from_le_bytes([count[0], count[1]]) as usize,
+            None => return Err(MgmtError::BadEvent(reply)),
+        };
+
+        if reply.len() < 2 + count * 2 {
+            return Err(MgmtError::BadEvent(reply));
+        }
+
+        Ok(reply[2..2 + count * 2]
+            .chunks(2)
+            .map(|index| u16::from_le_bytes([index[0], index[1]]))
+            .collect())
+    }
+
+    pub fn read_info(&mut self, index: u16) -> Result<ControllerInfo, MgmtError> {
+        let reply = self.send_command(MGMT_OP_READ_INFO, index, &[])?;
+
+        if reply.len() < MGMT_INFO_LEN {
+            return Err(MgmtError::BadEvent(reply));
+        }
+
+        // Addresses are sent least significant byte first
+        let mut address = BtAddr([0; 6]);
+        address.0.copy_from_slice(&reply[..6]);
+        address.0.reverse();
+
+        let u32_at =
+            |i: usize| u32::from_le_bytes([reply[i], reply[i + 1], reply[i + 2], reply[i + 3]]);
+        let name_at = |i: usize, len: usize| {
+            let name = &reply[i..i + len];
+            let end = name.iter().position(|&b| b == 0).unwrap_or(len);
+            String::from_utf8_lossy(&name[..end]).into_owned()
+        };
+
+        Ok(ControllerInfo {
+            address,
+            bluetooth_version: reply[6],
+            manufacturer: u16::from_le_bytes([reply[7], reply[8]]),
+            supported_settings: u32_at(9),
+            current_settings: u32_at(13),
+            class: u32::from_le_bytes([reply[17], reply[18], reply[19], 0]),
+            name: name_at(20, MGMT_MAX_NAME_LENGTH),
+            short_name: name_at(20 + MGMT_MAX_NAME_LENGTH, MGMT_MAX_SHORT_NAME_LENGTH),
+        })
+    }
+
+    pub fn set_powered(&mut self, index: u16, powered: bool) -> Result<(), MgmtError> {
+        self.send_command(MGMT_OP_SET_POWERED, index, &[powered as u8])?;
+        Ok(())
+    }
+
+    /// `timeout` is in seconds, 0 meaning forever. The adapter has to be connectable first.
+    pub fn set_discoverable(
+        &mut self,
+        index: u16,
+        mode: Discoverable,
+        timeout: u16,
+    ) -> Result<(), MgmtError> {
+        let mut params = vec![mode as u8];
+        params.extend_from_slice(&timeout.to_le_bytes());
+
+        self.send_command(MGMT_OP_SET_DISCOVERABLE, index, &params)?;
+        Ok(())
+    }
+
+    pub fn set_connectable(&mut self, index: u16, connectable: bool) -> Result<(), MgmtError> {
+        self.send_command(MGMT_OP_SET_CONNECTABLE, index, &[connectable as u8])?;
+        Ok(())
+    }
+
+    /// What BlueZ's D-Bus API calls pairable.
+    pub fn set_bondable(&mut self, index: u16, bondable: bool) -> Result<(), MgmtError> {
+        self.send_command(MGMT_OP_SET_BONDABLE, index, &[bondable as u8])?;
+        Ok(())
+    }
+
+    /// Sets the major and minor device class. The service class bits are managed by the kernel,
+    /// from the UUIDs of the registered services. Returns the resulting class of device.
+    pub fn set_device_class(&mut self, index: u16, major: u8, minor: u8) -> Result<u32, MgmtError> {
+        let class = self.send_command(MGMT_OP_SET_DEV_CLASS, index, &[major, minor])?;
+
+        match class.get(..3) {
+            Some(class) => Ok(u32::from_le_bytes([class[0], class[1], class[2], 0])),
+            None => Err(MgmtError::BadEvent(class)),
+        }
+    }
+
+    /// Names longer than the kernel allows are truncated. The short name is left empty.
+    pub fn set_local_name(&mut self, index: u16, name: &str) -> Result<(), MgmtError> {
+        let mut params = vec![0u8; MGMT_MAX_NAME_LENGTH + MGMT_MAX_SHORT_NAME_LENGTH];
+
+        let name = name.as_bytes();
+        let len = name.len().min(MGMT_MAX_NAME_LENGTH - 1);
+        params[..len].copy_from_slice(&name[..len]);
+
+        self.send_command(MGMT_OP_SET_LOCAL_NAME, index, &params)?;
+        Ok(())
+    }
+}
+
+/// The index of an adapter named like `hci0`.
+pub fn adapter_index(name: &str) -> Option<u16> {
+    name.strip_prefix("hci")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::VecDeque;
+
+    /// Stands in for the kernel, answering with `events` one packet per read, in order.
+    struct ScriptedSocket {
+        events: VecDeque<Vec<u8>>,
+        written: Vec<Vec<u8>>,
+    }
+
+    impl ScriptedSocket {
+        fn new(events: Vec<Vec<u8>>) -> ScriptedSocket {
+            ScriptedSocket {
+                events: events.into(),
+                written: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for ScriptedSocket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.events.pop_front() {
+                Some(event) => {
+                    buf[..event.len()].copy_from_slice(&event);
+                    Ok(event.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl Write for ScriptedSocket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn event(code: u16, index: u16, params: &[u8]) -> Vec<u8> {
+        let mut event = Vec::new();
+        event.extend_from_slice(&code.to_le_bytes());
+        event.extend_from_slice(&index.to_le_bytes());
+        event.extend_from_slice(&(params.len() as u16).to_le_bytes());
+        event.extend_from_slice(params);
+        event
+    }
+
+    fn complete(opcode: u16, index: u16, status: u8, reply: &[u8]) -> Vec<u8> {
+        let mut params = opcode.to_le_bytes().to_vec();
+        params.push(status);
+        params.extend_from_slice(reply);
+
+        event(MGMT_EV_CMD_COMPLETE, index, &params)
+    }
+
+    #[test]
+    fn send_command_writes_the_header() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![complete(
+            MGMT_OP_SET_POWERED,
+            0,
+            0,
+            &[1, 0, 0, 0],
+        )]));
+
+        assert_eq!(
+            mgmt.send_command(MGMT_OP_SET_POWERED, 0, &[1]).unwrap(),
+            vec![1, 0, 0, 0]
+        );
+        assert_eq!(
+            mgmt.into_inner().written,
+            vec![vec![0x05, 0x00, 0x00, 0x00, 0x01, 0x00, 0x01]]
+        );
+    }
+
+    #[test]
+    fn send_command_skips_unrelated_events() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![
+            // New Settings, which isn't a reply at all
+            event(0x0006, 0, &[0x01, 0x00, 0x00, 0x00]),
+            // The right command, for another adapter
+            complete(MGMT_OP_SET_CONNECTABLE, 1, 0x0D, &[]),
+            // Another command, for the right adapter
+            complete(MGMT_OP_SET_POWERED, 0, 0x0D, &[]),
+            complete(MGMT_OP_SET_CONNECTABLE, 0, 0, &[0x02, 0x00, 0x00, 0x00]),
+        ]));
+
+        assert_eq!(
+            mgmt.send_command(MGMT_OP_SET_CONNECTABLE, 0, &[1]).unwrap(),
+            vec![0x02, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn send_command_fails_on_a_status() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![event(
+            MGMT_EV_CMD_STATUS,
+            0,
+            &[0x05, 0x00, 0x14],
+        )]));
+
+        match mgmt.set_powered(0, true) {
+            Err(MgmtError::Failed { opcode, status }) => {
+                assert_eq!(opcode, MGMT_OP_SET_POWERED);
+                assert_eq!(status, 0x14);
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn send_command_fails_on_a_short_event() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![vec![0x01, 0x00, 0x00]]));
+
+        assert!(matches!(
+            mgmt.set_powered(0, true),
+            Err(MgmtError::BadEvent(_))
+        ));
+    }
+
+    #[test]
+    fn send_command_fails_when_the_socket_closes() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![]));
+
+        assert!(matches!(mgmt.set_powered(0, true), Err(MgmtError::Io(_))));
+    }
+
+    #[test]
+    fn reads_the_index_list() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![complete(
+            MGMT_OP_READ_INDEX_LIST,
+            MGMT_INDEX_NONE,
+            0,
+            &[0x02, 0x00, 0x00, 0x00, 0x03, 0x00],
+        )]));
+
+        assert_eq!(mgmt.read_index_list().unwrap(), vec![0, 3]);
+    }
+
+    #[test]
+    fn rejects_a_short_index_list() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![complete(
+            MGMT_OP_READ_INDEX_LIST,
+            MGMT_INDEX_NONE,
+            0,
+            &[0x02, 0x00, 0x00, 0x00],
+        )]));
+
+        assert!(matches!(
+            mgmt.read_index_list(),
+            Err(MgmtError::BadEvent(_))
+        ));
+    }
+
+    #[test]
+    fn reads_info() {
+        let mut info = vec![0u8; MGMT_INFO_LEN];
+        // Least significant byte first
+        info[..6].copy_from_slice(&[0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+        info[6] = 0x09;
+        info[7..9].copy_from_slice(&0x0002u16.to_le_bytes());
+        info[9..13].copy_from_slice(&0x0001_ffffu32.to_le_bytes());
+        info[13..17].copy_from_slice(&0x0000_0ad1u32.to_le_bytes());
+        info[17..20].copy_from_slice(&[0x08, 0x25, 0x00]);
+        info[20..31].copy_from_slice(b"Pro Control");
+        info[20 + MGMT_MAX_NAME_LENGTH..20 + MGMT_MAX_NAME_LENGTH + 3].copy_from_slice(b"Pro");
+
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![complete(
+            MGMT_OP_READ_INFO,
+            2,
+            0,
+            &info,
+        )]));
+
+        assert_eq!(
+            mgmt.read_info(2).unwrap(),
+            ControllerInfo {
+                address: BtAddr([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+                bluetooth_version: 0x09,
+                manufacturer: 0x0002,
+                supported_settings: 0x0001_ffff,
+                current_settings: 0x0000_0ad1,
+                class: 0x002508,
+                name: "Pro Control".to_string(),
+                short_name: "Pro".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_short_info() {
+        let mut mgmt = Mgmt::new(ScriptedSocket::new(vec![complete(
+            MGMT_OP_READ_INFO,
+            0,
+            0,
+            &[0u8; MGMT_INFO_LEN - 1],
+        )]));
+
+        assert!(matches!(mgmt.read_info(0), Err(MgmtError::BadEvent(_))));
+    }
+
+    #[test]
+    fn parses_adapter_names() {
+        assert_eq!(adapter_index("hci0"), Some(0));
+        assert_eq!(adapter_index("hci12"), Some(12));
+        assert_eq!(adapter_index("hci"), None);
+        assert_eq!(adapter_index("00:11:22:33:44:55"), None);
+    }
+}
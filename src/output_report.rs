@@ -0,0 +1,370 @@
+use crate::report::{check_len, ReportError, HIDP_DATA_OUTPUT};
+use crate::rumble::Rumble;
+use crate::subcommand::Subcommand;
+
+/// The Switch sends output reports this long, not counting the HIDP header.
+pub const OUTPUT_REPORT_LEN: usize = 49;
+/// Report id, packet counter and rumble data are common to 0x01, 0x10 and 0x11.
+const RUMBLE_HEADER_LEN: usize = 10;
+
+/// Rumble data for both sides, 4 bytes each.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct RumbleData(pub [u8; 8]);
+
+impl RumbleData {
+    /// The rumble data that keeps both actuators still.
+    pub const NEUTRAL: RumbleData = RumbleData([0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40]);
+
+    pub fn left(&self) -> [u8; 4] {
+        [self.0[0], self.0[1], self.0[2], self.0[3]]
+    }
+
+    pub fn right(&self) -> [u8; 4] {
+        [self.0[4], self.0[5], self.0[6], self.0[7]]
+    }
+
+    pub fn from_rumble(left: &Rumble, right: &Rumble) -> RumbleData {
+        let mut data = RumbleData::default();
+        data.0[..4].copy_from_slice(&left.encode());
+        data.0[4..].copy_from_slice(&right.encode());
+        data
+    }
+
+    pub fn left_rumble(&self) -> Rumble {
+        Rumble::decode(self.left())
+    }
+
+    pub fn right_rumble(&self) -> Rumble {
+        Rumble::decode(self.right())
+    }
+}
+
+/// A report sent from the Switch to the controller.
+///
+/// Subcommand arguments are decoded, so re-encoding pads reports back out to
+/// [`OUTPUT_REPORT_LEN`] with zeros rather than whatever the Switch padded them with.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum OutputReport {
+    /// Report 0x01.
+    RumbleAndSubcommand {
+        packet_counter: u8,
+        rumble: RumbleData,
+        subcommand: Subcommand,
+    },
+    /// Report 0x10.
+    Rumble {
+        packet_counter: u8,
+        rumble: RumbleData,
+    },
+    /// Report 0x11, asking the NFC/IR MCU for data.
+    McuRequest {
+        packet_counter: u8,
+        rumble: RumbleData,
+        mcu_subcommand: u8,
+        data: Vec<u8>,
+    },
+    Other {
+        id: u8,
+        data: Vec<u8>,
+    },
+}
+
+impl OutputReport {
+    /// Parses a report, starting at the report id. The HIDP header must already be stripped.
+    pub fn parse(report: &[u8]) -> Result<OutputReport, ReportError> {
+        let id = *report.first().ok_or(ReportError::Empty)?;
+
+        let rumble_header = || -> Result<(u8, RumbleData), ReportError> {
+            check_len(report, RUMBLE_HEADER_LEN)?;
+
+            let mut rumble = RumbleData::default();
+            rumble.0.copy_from_slice(&report[2..RUMBLE_HEADER_LEN]);
+
+            Ok((report[1], rumble))
+        };
+
+        match id {
+            0x01 => {
+                check_len(report, RUMBLE_HEADER_LEN + 1)?;
+                let (packet_counter, rumble) = rumble_header()?;
+
+                Ok(OutputReport::RumbleAndSubcommand {
+                    packet_counter,
+                    rumble,
+                    subcommand: Subcommand::parse(
+                        report[RUMBLE_HEADER_LEN],
+                        &report[RUMBLE_HEADER_LEN + 1..],
+                    ),
+                })
+            }
+            0x10 => {
+                let (packet_counter, rumble) = rumble_header()?;
+
+                Ok(OutputReport::Rumble {
+                    packet_counter,
+                    rumble,
+                })
+            }
+            0x11 => {
+                check_len(report, RUMBLE_HEADER_LEN + 1)?;
+                let (packet_counter, rumble) = rumble_header()?;
+
+                Ok(OutputReport::McuRequest {
+                    packet_counter,
+                    rumble,
+                    mcu_subcommand: report[RUMBLE_HEADER_LEN],
+                    data: report[RUMBLE_HEADER_LEN + 1..].to_vec(),
+                })
+            }
+            _ => Ok(OutputReport::Other {
+                id,
+                data: report[1..].to_vec(),
+            }),
+        }
+    }
+
+    /// Parses a packet as read from the itr channel, HIDP header included.
+    pub fn parse_packet(packet: &[u8]) -> Result<OutputReport, ReportError> {
+        match packet.split_first() {
+            Some((&HIDP_DATA_OUTPUT, report)) => OutputReport::parse(report),
+            Some((&header, _)) => Err(ReportError::BadHeader(header)),
+            None => Err(ReportError::Empty),
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            OutputReport::RumbleAndSubcommand { .. } => 0x01,
+            OutputReport::Rumble { .. } => 0x10,
+            OutputReport::McuRequest { .. } => 0x11,
+            OutputReport::Other { id, .. } => *id,
+        }
+    }
+
+    pub fn rumble(&self) -> Option<&RumbleData> {
+        match self {
+            OutputReport::RumbleAndSubcommand { rumble, .. }
+            | OutputReport::Rumble { rumble, .. }
+            | OutputReport::McuRequest { rumble, .. } => Some(rumble),
+            OutputReport::Other { .. } => None,
+        }
+    }
+
+    pub fn subcommand(&self) -> Option<&Subcommand> {
+        match self {
+            OutputReport::RumbleAndSubcommand { subcommand, .. } => Some(subcommand),
+            _ => None,
+        }
+    }
+
+    /// Encodes the report without the HIDP header.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(OUTPUT_REPORT_LEN);
+        out.push(self.id());
+
+        match self {
+            OutputReport::RumbleAndSubcommand {
+                packet_counter,
+                rumble,
+                subcommand,
+            } => {
+                out.push(*packet_counter);
+                out.extend_from_slice(&rumble.0);
+                out.push(subcommand.id());
+                out.extend(subcommand.encode_args());
+            }
+            OutputReport::Rumble {
+                packet_counter,
+                rumble,
+            } => {
+                out.push(*packet_counter);
+                out.extend_from_slice(&rumble.0);
+            }
+            OutputReport::McuRequest {
+                packet_counter,
+                rumble,
+                mcu_subcommand,
+                data,
+            } => {
+                out.push(*packet_counter);
+                out.extend_from_slice(&rumble.0);
+                out.push(*mcu_subcommand);
+                out.extend_from_slice(data);
+            }
+            OutputReport::Other { data, .. } => {
+                out.extend_from_slice(data);
+                return out;
+            }
+        }
+
+        if out.len() < OUTPUT_REPORT_LEN {
+            out.resize(OUTPUT_REPORT_LEN, 0);
+        }
+
+        out
+    }
+
+    /// Encodes the report with the HIDP header, ready to be sent on the itr channel.
+    pub fn encode_packet(&self) -> Vec<u8> {
+        let mut packet = vec![HIDP_DATA_OUTPUT];
+        packet.extend(self.encode());
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Something other than neutral, so it can't be mistaken for a default.
+    const RUMBLE: RumbleData = RumbleData([0x28, 0x88, 0x60, 0x61, 0x28, 0x88, 0x60, 0x61]);
+
+    /// Report `id` with `packet_counter`, [`RUMBLE`] and `rest`, padded like the Switch sends it.
+    fn report_bytes(id: u8, packet_counter: u8, rest: &[u8]) -> Vec<u8> {
+        let mut report = vec![id, packet_counter];
+        report.extend_from_slice(&RUMBLE.0);
+        report.extend_from_slice(rest);
+        report.resize(OUTPUT_REPORT_LEN, 0);
+        report
+    }
+
+    #[test]
+    fn rumble_and_subcommand_round_trips() {
+        let bytes = report_bytes(0x01, 0x0B, &[0x10, 0x20, 0x60, 0x00, 0x00, 0x18]);
+        let report = OutputReport::parse(&bytes).unwrap();
+
+        assert_eq!(
+            report,
+            OutputReport::RumbleAndSubcommand {
+                packet_counter: 0x0B,
+                rumble: RUMBLE,
+                subcommand: Subcommand::SpiFlashRead {
+                    address: 0x6020,
+                    length: 0x18,
+                },
+            }
+        );
+        assert_eq!(report.id(), 0x01);
+        assert_eq!(report.rumble(), Some(&RUMBLE));
+        assert_eq!(report.encode(), bytes);
+
+        let bytes = report_bytes(0x01, 0x0F, &[0x30, 0b0001]);
+        let report = OutputReport::parse(&bytes).unwrap();
+
+        assert_eq!(
+            report.subcommand(),
+            Some(&Subcommand::SetPlayerLights(0b0001))
+        );
+        assert_eq!(report.encode(), bytes);
+    }
+
+    #[test]
+    fn rumble_round_trips() {
+        let bytes = report_bytes(0x10, 0x05, &[]);
+        let report = OutputReport::parse(&bytes).unwrap();
+
+        assert_eq!(
+            report,
+            OutputReport::Rumble {
+                packet_counter: 0x05,
+                rumble: RUMBLE,
+            }
+        );
+        assert_eq!(report.subcommand(), None);
+        assert_eq!(report.encode(), bytes);
+
+        // Only the header is needed, the rest is padding
+        assert_eq!(
+            OutputReport::parse(&bytes[..RUMBLE_HEADER_LEN]).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn mcu_request_round_trips() {
+        let bytes = report_bytes(0x11, 0x0E, &[0x03, 0x01, 0x02]);
+        let report = OutputReport::parse(&bytes).unwrap();
+
+        match &report {
+            OutputReport::McuRequest {
+                packet_counter,
+                rumble,
+                mcu_subcommand,
+                data,
+            } => {
+                assert_eq!(*packet_counter, 0x0E);
+                assert_eq!(*rumble, RUMBLE);
+                assert_eq!(*mcu_subcommand, 0x03);
+                assert_eq!(&data[..2], &[0x01, 0x02]);
+            }
+            other => panic!("expected an MCU request, got {:?}", other),
+        }
+
+        assert_eq!(report.encode(), bytes);
+    }
+
+    #[test]
+    fn other_reports_round_trip_unpadded() {
+        let report = OutputReport::parse(&[0x80, 0x01]).unwrap();
+
+        assert_eq!(
+            report,
+            OutputReport::Other {
+                id: 0x80,
+                data: vec![0x01],
+            }
+        );
+        assert_eq!(report.rumble(), None);
+        assert_eq!(report.encode(), vec![0x80, 0x01]);
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let mut packet = vec![HIDP_DATA_OUTPUT];
+        packet.extend(report_bytes(0x01, 0x00, &[0x48, 0x01]));
+
+        let report = OutputReport::parse_packet(&packet).unwrap();
+        assert_eq!(
+            report.subcommand(),
+            Some(&Subcommand::EnableVibration(true))
+        );
+        assert_eq!(report.encode_packet(), packet);
+    }
+
+    #[test]
+    fn rejects_truncated_reports() {
+        let bytes = report_bytes(0x01, 0x00, &[0x02]);
+
+        assert_eq!(
+            OutputReport::parse(&bytes[..RUMBLE_HEADER_LEN]),
+            Err(ReportError::TooShort {
+                id: 0x01,
+                len: RUMBLE_HEADER_LEN,
+                expected: RUMBLE_HEADER_LEN + 1,
+            })
+        );
+        assert_eq!(
+            OutputReport::parse(&[0x10, 0x00, 0x00, 0x01, 0x40]),
+            Err(ReportError::TooShort {
+                id: 0x10,
+                len: 5,
+                expected: RUMBLE_HEADER_LEN,
+            })
+        );
+        assert_eq!(
+            OutputReport::parse(&report_bytes(0x11, 0x00, &[])[..RUMBLE_HEADER_LEN]),
+            Err(ReportError::TooShort {
+                id: 0x11,
+                len: RUMBLE_HEADER_LEN,
+                expected: RUMBLE_HEADER_LEN + 1,
+            })
+        );
+        assert_eq!(OutputReport::parse(&[]), Err(ReportError::Empty));
+
+        assert_eq!(
+            OutputReport::parse_packet(&[0xA1, 0x10]),
+            Err(ReportError::BadHeader(0xA1))
+        );
+        assert_eq!(OutputReport::parse_packet(&[]), Err(ReportError::Empty));
+    }
+}
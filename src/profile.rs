@@ -0,0 +1,337 @@
+//! The `org.bluez.Profile1` objects BlueZ hands the Switch's connections to.
+//!
+//! Each HID channel is registered as its own profile, since a profile only gets one PSM. BlueZ
+//! listens on the PSMs itself and passes every accepted connection to `NewConnection`, so there's
+//! no need to restart bluetoothd to get at them. bluetoothd's own input plugin claims the same
+//! PSMs, so it has to be disabled, e.g. by running bluetoothd with `--noplugin=input`.
+
+use crate::bluez::Adapter;
+use crate::dbus_profile_manager::OrgBluezProfileManager1;
+use crate::l2cap::L2CAPStream;
+use crate::relay::Channel;
+use crate::sdp::{SdpRecord, HID_UUID};
+use crate::smol_fd::libc_check_error;
+use crate::BtAddr;
+
+use dbus::arg::{OwnedFd, RefArg, Variant};
+use dbus::blocking::Connection;
+use dbus::tree::{Factory, MTSync, MethodErr, MethodInfo, MethodResult};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use tracing::{error, warn};
+
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Object path the profiles are registered under, one child per channel.
+pub const HID_PATH: &str = "/bluez/switch/hid";
+
+/// How often the D-Bus thread checks whether it should stop.
+const PROCESS_TIMEOUT: Duration = Duration::from_millis(250);
+
+macro_rules! insert {
+    ($map:ident, $key:expr, $val:expr) => {
+        $map.insert($key, Variant(Box::new($val) as Box<dyn RefArg>));
+    };
+}
+
+/// Something BlueZ told us through one of the profiles.
+#[derive(Debug)]
+pub enum ProfileEvent {
+    /// A device connected to `channel`.
+    NewConnection {
+        channel: Channel,
+        device: BtAddr,
+        stream: L2CAPStream,
+    },
+    /// BlueZ asked us to disconnect `device`. Its connections on `channel` have already been shut
+    /// down, so anything reading them sees them close.
+    Disconnected { channel: Channel, device: BtAddr },
+    /// BlueZ unregistered the profile for `channel`, usually because bluetoothd is stopping.
+    Released(Channel),
+}
+
+/// What the method handlers share.
+struct ProfileState {
+    events: UnboundedSender<ProfileEvent>,
+    /// Devices under this path are the only ones we take connections from.
+    adapter_path: String,
+    /// Duplicates of every connection handed out, to shut them down on request.
+    connections: HashMap<(Channel, BtAddr), Vec<OwnedFd>>,
+}
+
+/// The HID profiles, served from a thread of their own for as long as this is alive. Dropping it
+/// unregisters them.
+pub struct HidProfile {
+    events: UnboundedReceiver<ProfileEvent>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HidProfile {
+    /// Exports the profile objects and registers them with BlueZ, advertising `record`. Profiles
+    /// are registered with every adapter, so connections through any adapter but `adapter` are
+    /// turned away.
+    pub fn register(record: &SdpRecord, adapter: &Adapter) -> Result<HidProfile, dbus::Error> {
+        let mut conn = Connection::new_system()?;
+
+        let (sender, events) = mpsc::unbounded();
+        let state = Arc::new(Mutex::new(ProfileState {
+            events: sender,
+            adapter_path: adapter.dbus_path(),
+            connections: HashMap::new(),
+        }));
+
+        let f = Factory::new_sync::<()>();
+        let mut tree = f.tree(());
+
+        for &channel in &[Channel::Ctl, Channel::Itr] {
+            let new_connection = {
+                let state = state.clone();
+                move |m: &MethodInfo<MTSync<()>, ()>| new_connection(m, channel, &state)
+            };
+
+            let request_disconnection = {
+                let state = state.clone();
+                move |m: &MethodInfo<MTSync<()>, ()>| request_disconnection(m, channel, &state)
+            };
+
+            let release = {
+                let state = state.clone();
+                move |m: &MethodInfo<MTSync<()>, ()>| {
+                    let _ = state
+                        .lock()
+                        .unwrap()
+                        .events
+                        .unbounded_send(ProfileEvent::Released(channel));
+                    Ok(vec![m.msg.method_return()])
+                }
+            };
+
+            let interface = f
+                .interface("org.bluez.Profile1", ())
+                .add_m(f.method("NewConnection", (), new_connection))
+                .add_m(f.method("RequestDisconnection", (), request_disconnection))
+                .add_m(f.method("Release", (), release));
+
+            tree = tree.add(f.object_path(profile_path(channel), ()).add(interface));
+        }
+
+        tree.start_receive_send(&conn);
+
+        let proxy = conn.with_proxy("org.bluez", "/org/bluez", Duration::from_millis(5000));
+
+        for &channel in &[Channel::Ctl, Channel::Itr] {
+            let mut options = HashMap::new();
+            insert!(options, "Role", "server".to_string());
+            insert!(options, "PSM", channel.psm());
+            insert!(options, "RequireAuthentication", false);
+            insert!(options, "RequireAuthorization", false);
+
+            // The record covers both channels, so it only needs advertising once. itr gets an
+            // empty one rather than leaving it to BlueZ, which could publish a generic record
+            // for the itr PSM that the Switch would then find alongside ours.
+            match channel {
+                Channel::Ctl => {
+                    insert!(options, "ServiceRecord", record.to_xml());
+                    insert!(options, "Service", HID_UUID.to_string());
+                }
+                Channel::Itr => {
+                    insert!(options, "ServiceRecord", SdpRecord::new().to_xml());
+                }
+            }
+
+            let my_uuid = uuid::Uuid::new_v4().to_string();
+            let registered =
+                proxy.register_profile(dbus::Path::from(profile_path(channel)), &my_uuid, options);
+
+            if let Err(e) = registered {
+                // Don't leave ctl registered without itr
+                if channel == Channel::Itr {
+                    let _ = proxy.unregister_profile(dbus::Path::from(profile_path(Channel::Ctl)));
+                }
+
+                return Err(e);
+            }
+        }
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let running = running.clone();
+
+            thread::spawn(move || {
+                while running.load(Ordering::Relaxed) {
+                    if let Err(e) = conn.process(PROCESS_TIMEOUT) {
+                        error!(error = %e, "Lost the D-Bus connection for the HID profile");
+                        break;
+                    }
+                }
+            })
+        };
+
+        Ok(HidProfile {
+            events,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    /// Waits for the next event. `None` if the profile is no longer being served.
+    pub async fn next_event(&mut self) -> Option<ProfileEvent> {
+        self.events.next().await
+    }
+
+    /// Waits for one device to connect to both channels, and returns its address along with the
+    /// ctl and itr streams, in that order. Connections from anyone else are dropped.
+    pub async fn accept(&mut self) -> Option<(BtAddr, L2CAPStream, L2CAPStream)> {
+        let mut ctl: Option<(BtAddr, L2CAPStream)> = None;
+
+        loop {
+            let ctl_device = ctl.as_ref().map(|(device, _)| *device);
+
+            match self.next_event().await? {
+                ProfileEvent::NewConnection {
+                    channel: Channel::Ctl,
+                    device,
+                    stream,
+                } => ctl = Some((device, stream)),
+
+                // itr always comes second, from whoever connected to ctl
+                ProfileEvent::NewConnection {
+                    channel: Channel::Itr,
+                    device,
+                    stream,
+                } if ctl_device == Some(device) => {
+                    let (_, ctl_stream) = ctl.take()?;
+                    return Some((device, ctl_stream, stream));
+                }
+
+                ProfileEvent::NewConnection { device, .. } => {
+                    warn!(%device, "Dropping an itr connection without a ctl connection")
+                }
+
+                ProfileEvent::Disconnected { device, .. } if ctl_device == Some(device) => {
+                    ctl = None
+                }
+
+                ProfileEvent::Disconnected { .. } => {}
+
+                ProfileEvent::Released(_) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for HidProfile {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn profile_path(channel: Channel) -> String {
+    format!("{}/{}", HID_PATH, channel)
+}
+
+/// Turns a BlueZ device path like `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF` into its address.
+pub fn device_address(path: &str) -> Option<BtAddr> {
+    let (_, device) = path.rsplit_once('/')?;
+    device.strip_prefix("dev_")?.replace('_', ":").parse().ok()
+}
+
+fn new_connection(
+    m: &MethodInfo<MTSync<()>, ()>,
+    channel: Channel,
+    state: &Mutex<ProfileState>,
+) -> MethodResult {
+    let (device_path, fd): (dbus::Path, OwnedFd) = m.msg.read2()?;
+    let device =
+        device_address(&device_path).ok_or_else(|| MethodErr::invalid_arg(&device_path))?;
+
+    let mut state = state.lock().unwrap();
+
+    // Dropping the descriptor closes the connection
+    match device_path.strip_prefix(&state.adapter_path) {
+        Some(rest) if rest.starts_with('/') => {}
+        _ => return Err(MethodErr::failed(&"connection through another adapter")),
+    }
+
+    let copy = duplicate(&fd).map_err(|e| MethodErr::failed(&e))?;
+    let stream = unsafe { L2CAPStream::from_raw_fd(fd.into_fd()) };
+
+    state
+        .connections
+        .entry((channel, device))
+        .or_default()
+        .push(copy);
+
+    let _ = state.events.unbounded_send(ProfileEvent::NewConnection {
+        channel,
+        device,
+        stream,
+    });
+
+    Ok(vec![m.msg.method_return()])
+}
+
+fn request_disconnection(
+    m: &MethodInfo<MTSync<()>, ()>,
+    channel: Channel,
+    state: &Mutex<ProfileState>,
+) -> MethodResult {
+    let device: dbus::Path = m.msg.read1()?;
+    let device = device_address(&device).ok_or_else(|| MethodErr::invalid_arg(&device))?;
+
+    let mut state = state.lock().unwrap();
+
+    // The copies are closed when dropped, but the shutdown is what reaches the other copy
+    for fd in state
+        .connections
+        .remove(&(channel, device))
+        .unwrap_or_default()
+    {
+        unsafe { libc::shutdown(fd.as_raw_fd(), libc::SHUT_RDWR) };
+    }
+
+    let _ = state
+        .events
+        .unbounded_send(ProfileEvent::Disconnected { channel, device });
+
+    Ok(vec![m.msg.method_return()])
+}
+
+/// Keeps a second descriptor for the same socket, which stays valid whatever happens to the first.
+fn duplicate(fd: &OwnedFd) -> std::io::Result<OwnedFd> {
+    let copy = libc_check_error(unsafe { libc::dup(fd.as_raw_fd()) })?;
+
+    Ok(unsafe { OwnedFd::new(copy) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_address_from_path() {
+        assert_eq!(
+            device_address("/org/bluez/hci0/dev_98_B6_E9_0A_0B_0C"),
+            Some(BtAddr([0x98, 0xB6, 0xE9, 0x0A, 0x0B, 0x0C]))
+        );
+        assert_eq!(device_address("dev_98_b6_e9_0a_0b_0c"), None, "not a path");
+        assert_eq!(device_address("/org/bluez/hci0"), None);
+        assert_eq!(device_address("/org/bluez/hci0/dev_98_B6_E9"), None);
+        assert_eq!(
+            device_address("/org/bluez/hci0/dev_98_B6_E9_0A_0B_ZZ"),
+            None
+        );
+        assert_eq!(device_address(""), None);
+    }
+}
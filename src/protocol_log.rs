@@ -0,0 +1,555 @@
+//! A live, one line per packet account of what the Switch and controller are saying to each
+//! other, decoded as far as we understand it.
+//!
+//! Full reports come 60 or 120 times a second, so a [`LogFilter`] picks out the reports and
+//! subcommands worth seeing.
+
+use crate::input_report::{InputReport, ReportBody, StandardInputReport, StickData};
+use crate::output_report::{OutputReport, RumbleData};
+use crate::relay::{Channel, Direction};
+use crate::report::ReportError;
+use crate::subcommand::{Subcommand, SubcommandReply};
+
+use tracing::info;
+
+use std::fmt::Write;
+
+/// Which packets to show. A packet is shown if its report id or its subcommand id is listed, or
+/// if nothing is listed at all.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct LogFilter {
+    pub reports: Vec<u8>,
+    /// Matches both the Switch's subcommands and the controller's replies to them.
+    pub subcommands: Vec<u8>,
+}
+
+impl LogFilter {
+    fn matches(&self, report: Option<u8>, subcommand: Option<u8>) -> bool {
+        if self.reports.is_empty() && self.subcommands.is_empty() {
+            return true;
+        }
+
+        report.is_some_and(|id| self.reports.contains(&id))
+            || subcommand.is_some_and(|id| self.subcommands.contains(&id))
+    }
+}
+
+/// Logs every packet that gets past its filter as it goes past, at info level.
+#[derive(Debug, Clone)]
+pub struct ProtocolLog {
+    filter: LogFilter,
+}
+
+/// A packet, decoded.
+struct Entry {
+    report: Option<u8>,
+    subcommand: Option<u8>,
+    description: String,
+}
+
+impl ProtocolLog {
+    pub fn new(filter: LogFilter) -> ProtocolLog {
+        ProtocolLog { filter }
+    }
+
+    pub fn log(&self, channel: Channel, direction: Direction, packet: &[u8]) {
+        let entry = describe(channel, direction, packet);
+
+        if !self.filter.matches(entry.report, entry.subcommand) {
+            return;
+        }
+
+        let arrow = match direction {
+            Direction::SwitchToController => "S>C",
+            Direction::ControllerToSwitch => "C>S",
+        };
+
+        info!(
+            %channel,
+            ?direction,
+            report = entry.report,
+            subcommand = entry.subcommand,
+            "{} {}",
+            arrow,
+            entry.description
+        );
+    }
+}
+
+fn describe(channel: Channel, direction: Direction, packet: &[u8]) -> Entry {
+    let unparsed = |description: String| Entry {
+        report: None,
+        subcommand: None,
+        description,
+    };
+
+    // Only itr carries reports
+    if channel == Channel::Ctl {
+        return unparsed(format!("{} bytes: {:02x?}", packet.len(), packet));
+    }
+
+    let (report, subcommand, description) = match direction {
+        Direction::SwitchToController => match OutputReport::parse_packet(packet) {
+            Ok(report) => (
+                report.id(),
+                report.subcommand().map(Subcommand::id),
+                describe_output_report(&report),
+            ),
+            Err(e) => return unparsed(not_a_report(packet, e)),
+        },
+        Direction::ControllerToSwitch => match InputReport::parse_packet(packet) {
+            Ok(report) => (
+                report.id(),
+                reply(&report).map(|reply| reply.id),
+                describe_input_report(&report),
+            ),
+            Err(e) => return unparsed(not_a_report(packet, e)),
+        },
+    };
+
+    Entry {
+        report: Some(report),
+        subcommand,
+        description: format!("{:#04x} {}", report, description),
+    }
+}
+
+fn not_a_report(packet: &[u8], error: ReportError) -> String {
+    format!("{} bytes, not a report: {}", packet.len(), error)
+}
+
+fn reply(report: &InputReport) -> Option<&SubcommandReply> {
+    match report {
+        InputReport::Standard(StandardInputReport {
+            body: ReportBody::SubcommandReply(reply),
+            ..
+        }) => Some(reply),
+        _ => None,
+    }
+}
+
+fn describe_output_report(report: &OutputReport) -> String {
+    match report {
+        OutputReport::RumbleAndSubcommand {
+            packet_counter,
+            rumble,
+            subcommand,
+        } => format!(
+            "#{:<2} {} {}",
+            packet_counter,
+            describe_rumble(rumble),
+            describe_subcommand(subcommand)
+        ),
+        OutputReport::Rumble {
+            packet_counter,
+            rumble,
+        } => format!("#{:<2} {}", packet_counter, describe_rumble(rumble)),
+        OutputReport::McuRequest {
+            packet_counter,
+            rumble,
+            mcu_subcommand,
+            ..
+        } => format!(
+            "#{:<2} {} MCU request {:#04x}",
+            packet_counter,
+            describe_rumble(rumble),
+            mcu_subcommand
+        ),
+        OutputReport::Other { data, .. } => format!("{} bytes", data.len()),
+    }
+}
+
+fn describe_rumble(rumble: &RumbleData) -> String {
+    let (left, right) = (rumble.left_rumble(), rumble.right_rumble());
+
+    if left.is_silent() && right.is_silent() {
+        return "rumble off".to_string();
+    }
+
+    let mut out = String::from("rumble");
+
+    for (side, rumble) in [("L", left), ("R", right)].iter() {
+        write!(
+            out,
+            " {} {:.0}Hz {:.2} {:.0}Hz {:.2}",
+            side,
+            rumble.high_frequency,
+            rumble.high_amplitude,
+            rumble.low_frequency,
+            rumble.low_amplitude
+        )
+        .unwrap();
+    }
+
+    out
+}
+
+fn describe_subcommand(subcommand: &Subcommand) -> String {
+    match subcommand {
+        Subcommand::SpiFlashRead { address, length } => {
+            format!("SpiFlashRead {:#06x} len {:#04x}", address, length)
+        }
+        Subcommand::SpiFlashWrite { address, data } => {
+            format!("SpiFlashWrite {:#06x} len {:#04x}", address, data.len())
+        }
+        Subcommand::SpiSectorErase { address } => format!("SpiSectorErase {:#06x}", address),
+        Subcommand::SetInputReportMode(mode) => format!("SetInputReportMode {:#04x}", mode),
+        Subcommand::SetPlayerLights(lights) => format!("SetPlayerLights {:08b}", lights),
+        Subcommand::Unknown { id, .. } => format!("subcommand {:#04x}", id),
+
+        Subcommand::BluetoothManualPairing(_)
+        | Subcommand::SetNfcIrMcuConfig(_)
+        | Subcommand::SetHomeLight(_)
+        | Subcommand::SetImuSensitivity(_) => subcommand.name().to_string(),
+
+        _ => format!("{:?}", subcommand),
+    }
+}
+
+fn describe_input_report(report: &InputReport) -> String {
+    let report = match report {
+        InputReport::Standard(report) => report,
+        InputReport::SimpleHid(report) => {
+            return format!(
+                "buttons {:02x} {:02x} hat {}",
+                report.buttons[0], report.buttons[1], report.hat
+            )
+        }
+        InputReport::Other { data, .. } => return format!("{} bytes", data.len()),
+    };
+
+    let mut buttons = report
+        .buttons
+        .pressed()
+        .map(|button| format!("{:?}", button))
+        .collect::<Vec<_>>()
+        .join("+");
+
+    if buttons.is_empty() {
+        buttons.push('-');
+    }
+
+    let mut out = format!(
+        "t={:<3} {} L {} R {}",
+        report.timer,
+        buttons,
+        describe_stick(report.left_stick),
+        describe_stick(report.right_stick)
+    );
+
+    if let ReportBody::SubcommandReply(reply) = &report.body {
+        let acked = if reply.ack & 0x80 != 0 { "ACK" } else { "NACK" };
+
+        // SPI read replies start with the address and length that were read
+        let subcommand = Subcommand::parse(reply.id, &reply.data);
+
+        let subcommand = match subcommand {
+            Subcommand::SpiFlashRead { .. } if reply.ack & 0x80 != 0 => {
+                describe_subcommand(&subcommand)
+            }
+            Subcommand::Unknown { id, .. } => format!("subcommand {:#04x}", id),
+            _ => subcommand.name().to_string(),
+        };
+
+        write!(out, " {} {}", acked, subcommand).unwrap();
+    }
+
+    out
+}
+
+fn describe_stick(stick: StickData) -> String {
+    format!("{:>4},{:<4}", stick.horizontal, stick.vertical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_report::STANDARD_REPORT_LEN;
+    use crate::report::{HIDP_DATA_INPUT, HIDP_DATA_OUTPUT};
+    use crate::rumble::Rumble;
+
+    /// Output report `id` with neutral rumble, followed by `rest`.
+    fn output_packet(id: u8, packet_counter: u8, rest: &[u8]) -> Vec<u8> {
+        let mut packet = vec![HIDP_DATA_OUTPUT, id, packet_counter];
+        packet.extend_from_slice(&RumbleData::NEUTRAL.0);
+        packet.extend_from_slice(rest);
+        packet
+    }
+
+    /// Standard input report `id` with the sticks centered and nothing pressed, followed by
+    /// `rest` and padding.
+    fn input_packet(id: u8, rest: &[u8]) -> Vec<u8> {
+        let mut packet = vec![HIDP_DATA_INPUT, id, 0x0a, 0x8e, 0x00, 0x00, 0x00];
+        packet.extend_from_slice(&StickData::CENTER.to_bytes());
+        packet.extend_from_slice(&StickData::CENTER.to_bytes());
+        packet.push(0x0c);
+        packet.extend_from_slice(rest);
+        packet.resize(STANDARD_REPORT_LEN + 1, 0);
+        packet
+    }
+
+    fn describe_itr(direction: Direction, packet: &[u8]) -> (Option<u8>, Option<u8>, String) {
+        let entry = describe(Channel::Itr, direction, packet);
+        (entry.report, entry.subcommand, entry.description)
+    }
+
+    fn from_switch(packet: &[u8]) -> (Option<u8>, Option<u8>, String) {
+        describe_itr(Direction::SwitchToController, packet)
+    }
+
+    fn from_controller(packet: &[u8]) -> (Option<u8>, Option<u8>, String) {
+        describe_itr(Direction::ControllerToSwitch, packet)
+    }
+
+    #[test]
+    fn empty_filter_matches_everything() {
+        let filter = LogFilter::default();
+
+        assert!(filter.matches(None, None));
+        assert!(filter.matches(Some(0x30), None));
+        assert!(filter.matches(Some(0x21), Some(0x10)));
+    }
+
+    #[test]
+    fn filter_matches_report_or_subcommand() {
+        let reports = LogFilter {
+            reports: vec![0x30, 0x3F],
+            subcommands: vec![],
+        };
+
+        assert!(reports.matches(Some(0x30), None));
+        assert!(reports.matches(Some(0x3F), None));
+        assert!(!reports.matches(Some(0x21), Some(0x30)));
+        assert!(!reports.matches(None, None));
+
+        let subcommands = LogFilter {
+            reports: vec![],
+            subcommands: vec![0x10],
+        };
+
+        assert!(subcommands.matches(Some(0x01), Some(0x10)));
+        assert!(subcommands.matches(Some(0x21), Some(0x10)));
+        assert!(!subcommands.matches(Some(0x10), None));
+        assert!(!subcommands.matches(Some(0x01), Some(0x02)));
+
+        let both = LogFilter {
+            reports: vec![0x30],
+            subcommands: vec![0x10],
+        };
+
+        assert!(both.matches(Some(0x30), None));
+        assert!(both.matches(Some(0x01), Some(0x10)));
+        assert!(!both.matches(Some(0x01), Some(0x02)));
+    }
+
+    #[test]
+    fn subcommand_filter_matches_request_and_reply() {
+        let filter = LogFilter {
+            reports: vec![],
+            subcommands: vec![0x10],
+        };
+
+        let request = describe(
+            Channel::Itr,
+            Direction::SwitchToController,
+            &output_packet(0x01, 0, &[0x10, 0x20, 0x60, 0x00, 0x00, 0x18]),
+        );
+        let reply = describe(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &input_packet(0x21, &[0x90, 0x10, 0x20, 0x60, 0x00, 0x00, 0x18]),
+        );
+        let full = describe(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &input_packet(0x30, &[]),
+        );
+
+        assert!(filter.matches(request.report, request.subcommand));
+        assert!(filter.matches(reply.report, reply.subcommand));
+        assert!(!filter.matches(full.report, full.subcommand));
+    }
+
+    #[test]
+    fn describes_output_reports() {
+        assert_eq!(
+            from_switch(&output_packet(
+                0x01,
+                0,
+                &[0x10, 0x20, 0x60, 0x00, 0x00, 0x18]
+            )),
+            (
+                Some(0x01),
+                Some(0x10),
+                "0x01 #0  rumble off SpiFlashRead 0x6020 len 0x18".to_string()
+            )
+        );
+        assert_eq!(
+            from_switch(&output_packet(0x01, 11, &[0x30, 0b0000_0101])),
+            (
+                Some(0x01),
+                Some(0x30),
+                "0x01 #11 rumble off SetPlayerLights 00000101".to_string()
+            )
+        );
+        assert_eq!(
+            from_switch(&output_packet(0x01, 2, &[0x48, 0x01])).2,
+            "0x01 #2  rumble off EnableVibration(true)"
+        );
+        assert_eq!(
+            from_switch(&output_packet(0x01, 3, &[0x38, 0x01, 0x02])).2,
+            "0x01 #3  rumble off SetHomeLight"
+        );
+        assert_eq!(
+            from_switch(&output_packet(0x01, 4, &[0x99, 0x01])),
+            (
+                Some(0x01),
+                Some(0x99),
+                "0x01 #4  rumble off subcommand 0x99".to_string()
+            )
+        );
+        assert_eq!(
+            from_switch(&output_packet(0x11, 5, &[0x03])),
+            (
+                Some(0x11),
+                None,
+                "0x11 #5  rumble off MCU request 0x03".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn describes_rumble() {
+        let loud = Rumble {
+            high_amplitude: 1.0,
+            low_amplitude: 1.0,
+            ..Rumble::NEUTRAL
+        };
+
+        let mut packet = output_packet(0x10, 15, &[]);
+        packet[3..11].copy_from_slice(&RumbleData::from_rumble(&loud, &Rumble::NEUTRAL).0);
+
+        assert_eq!(
+            from_switch(&packet),
+            (
+                Some(0x10),
+                None,
+                "0x10 #15 rumble L 320Hz 1.00 160Hz 1.00 R 320Hz 0.00 160Hz 0.00".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn describes_input_reports() {
+        let mut full = input_packet(0x30, &[]);
+        full[2] = 66;
+        full[4..7].copy_from_slice(&[0x08, 0x02, 0x40]);
+        full[7..10].copy_from_slice(&[0x23, 0xc1, 0xab]);
+
+        assert_eq!(
+            from_controller(&full),
+            (
+                Some(0x30),
+                None,
+                "0x30 t=66  A+Plus+L L  291,2748 R 2048,2048".to_string()
+            )
+        );
+        assert_eq!(
+            from_controller(&input_packet(0x30, &[])).2,
+            "0x30 t=10  - L 2048,2048 R 2048,2048"
+        );
+        assert_eq!(
+            from_controller(&[
+                HIDP_DATA_INPUT,
+                0x3F,
+                0x08,
+                0x00,
+                0x08,
+                0x00,
+                0x80,
+                0x00,
+                0x80
+            ]),
+            (
+                None,
+                None,
+                "9 bytes, not a report: report 0x3f is 8 bytes, expected at least 12".to_string()
+            )
+        );
+
+        let mut simple = vec![HIDP_DATA_INPUT, 0x3F, 0x08, 0x00, 0x08];
+        simple.extend_from_slice(&[[0x00, 0x80]; 4].concat());
+        assert_eq!(
+            from_controller(&simple),
+            (Some(0x3F), None, "0x3f buttons 08 00 hat 8".to_string())
+        );
+    }
+
+    #[test]
+    fn describes_subcommand_replies() {
+        assert_eq!(
+            from_controller(&input_packet(
+                0x21,
+                &[0x90, 0x10, 0x20, 0x60, 0x00, 0x00, 0x18]
+            )),
+            (
+                Some(0x21),
+                Some(0x10),
+                "0x21 t=10  - L 2048,2048 R 2048,2048 ACK SpiFlashRead 0x6020 len 0x18".to_string()
+            )
+        );
+        assert_eq!(
+            from_controller(&input_packet(0x21, &[0x00, 0x10])).2,
+            "0x21 t=10  - L 2048,2048 R 2048,2048 NACK SpiFlashRead"
+        );
+        assert_eq!(
+            from_controller(&input_packet(0x21, &[0x82, 0x02, 0x03, 0x8b])),
+            (
+                Some(0x21),
+                Some(0x02),
+                "0x21 t=10  - L 2048,2048 R 2048,2048 ACK RequestDeviceInfo".to_string()
+            )
+        );
+        assert_eq!(
+            from_controller(&input_packet(0x21, &[0x80, 0x99])).2,
+            "0x21 t=10  - L 2048,2048 R 2048,2048 ACK subcommand 0x99"
+        );
+    }
+
+    #[test]
+    fn describes_anything_else_as_bytes() {
+        assert_eq!(
+            describe(Channel::Ctl, Direction::SwitchToController, &[0x71, 0x02]).description,
+            "2 bytes: [71, 02]"
+        );
+        assert_eq!(
+            describe(
+                Channel::Ctl,
+                Direction::ControllerToSwitch,
+                &input_packet(0x30, &[])
+            )
+            .report,
+            None
+        );
+        assert_eq!(
+            from_switch(&[HIDP_DATA_INPUT, 0x01]),
+            (
+                None,
+                None,
+                "2 bytes, not a report: unexpected HIDP header 0xa1".to_string()
+            )
+        );
+        assert_eq!(
+            from_controller(&[HIDP_DATA_INPUT, 0x30, 0x00]),
+            (
+                None,
+                None,
+                "3 bytes, not a report: report 0x30 is 2 bytes, expected at least 13".to_string()
+            )
+        );
+        assert_eq!(
+            from_switch(&[HIDP_DATA_OUTPUT, 0x80, 0x01]),
+            (Some(0x80), None, "0x80 1 bytes".to_string())
+        );
+    }
+}
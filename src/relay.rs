@@ -0,0 +1,550 @@
+use crate::capture::Capture;
+use crate::input_report::{InputReport, ReportBody};
+use crate::output_report::OutputReport;
+use crate::protocol_log::ProtocolLog;
+use crate::remap::Remap;
+use crate::subcommand::Subcommand;
+use crate::transport::Transport;
+use crate::BtAddr;
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use tracing::{info, trace, warn};
+
+use std::fmt::Write as FmtWrite;
+use std::io::Result;
+use std::time::Instant;
+
+/// Big enough for any packet either side sends on either channel.
+const RELAY_BUFFER_LEN: usize = 512;
+
+/// The two L2CAP channels of a HID connection.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Channel {
+    /// HID control, PSM 17. Handshakes, GET_REPORT/SET_REPORT and HID_CONTROL.
+    Ctl,
+    /// HID interrupt, PSM 19. Input and output reports.
+    Itr,
+}
+
+impl Channel {
+    pub fn psm(self) -> u16 {
+        match self {
+            Channel::Ctl => 17,
+            Channel::Itr => 19,
+        }
+    }
+}
+
+impl std::fmt::Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Ctl => f.write_str("ctl"),
+            Channel::Itr => f.write_str("itr"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Direction {
+    SwitchToController,
+    ControllerToSwitch,
+}
+
+/// What went over one channel in either direction, HIDP headers included.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct ChannelStats {
+    pub packets_from_switch: u64,
+    pub bytes_from_switch: u64,
+    pub packets_from_controller: u64,
+    pub bytes_from_controller: u64,
+}
+
+/// Forwards packets both ways on one channel until either side closes it or fails, counting them
+/// in `stats`. `inspect` sees, and may rewrite, every packet before it's forwarded.
+pub async fn relay_channel<S, C, F>(
+    channel: Channel,
+    switch: S,
+    controller: C,
+    stats: &mut ChannelStats,
+    mut inspect: F,
+) -> Result<()>
+where
+    S: Transport,
+    C: Transport,
+    F: FnMut(Direction, &mut [u8]),
+{
+    let (mut sw_r_half, mut sw_w_half) = switch.split();
+    let (mut cn_r_half, mut cn_w_half) = controller.split();
+
+    let mut controller_incoming = vec![0u8; RELAY_BUFFER_LEN];
+    let mut switch_incoming = vec![0u8; RELAY_BUFFER_LEN];
+
+    let mut sw_r = sw_r_half.read(&mut switch_incoming);
+    let mut cn_r = cn_r_half.read(&mut controller_incoming);
+
+    loop {
+        match future::select(sw_r, cn_r).await {
+            // Read successfully from switch
+            Either::Left((Ok(n), old_cn_r)) => {
+                if n == 0 {
+                    info!(%channel, "Switch closed the channel");
+                    return Ok(());
+                }
+
+                stats.packets_from_switch += 1;
+                stats.bytes_from_switch += n as u64;
+
+                inspect(Direction::SwitchToController, &mut switch_incoming[0..n]);
+
+                if let Err(e) = cn_w_half.write_all(&switch_incoming[0..n]).await {
+                    warn!(%channel, error = %e, "Write to controller failed");
+                    return Err(e);
+                }
+
+                cn_r = old_cn_r;
+                sw_r = sw_r_half.read(&mut switch_incoming);
+            }
+
+            // Read successfully from controller
+            Either::Right((Ok(n), old_sw_r)) => {
+                if n == 0 {
+                    info!(%channel, "Controller closed the channel");
+                    return Ok(());
+                }
+
+                stats.packets_from_controller += 1;
+                stats.bytes_from_controller += n as u64;
+
+                inspect(
+                    Direction::ControllerToSwitch,
+                    &mut controller_incoming[0..n],
+                );
+
+                if let Err(e) = sw_w_half.write_all(&controller_incoming[0..n]).await {
+                    warn!(%channel, error = %e, "Write to switch failed");
+                    return Err(e);
+                }
+
+                sw_r = old_sw_r;
+                cn_r = cn_r_half.read(&mut controller_incoming);
+            }
+
+            // Read failed from switch
+            Either::Left((Err(e), _old_cn_r)) => {
+                warn!(%channel, error = %e, "Read from switch failed");
+                return Err(e);
+            }
+
+            // Read failed from controller
+            Either::Right((Err(e), _old_sw_r)) => {
+                warn!(%channel, error = %e, "Read from controller failed");
+                return Err(e);
+            }
+        };
+    }
+}
+
+/// Relays both channels at once. Whichever channel ends first, cleanly or not, ends the session
+/// and the other channel is dropped along with it.
+pub async fn relay_session<F, I>(ctl: F, itr: I) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+    I: Future<Output = Result<()>>,
+{
+    futures::pin_mut!(ctl);
+    futures::pin_mut!(itr);
+
+    let (channel, result) = match future::select(ctl, itr).await {
+        Either::Left((result, _itr)) => (Channel::Ctl, result),
+        Either::Right((result, _ctl)) => (Channel::Itr, result),
+    };
+
+    info!(%channel, "Channel ended, closing the session");
+
+    result
+}
+
+/// Everything optional about how [`relay_controller`] relays.
+#[derive(Clone, Default)]
+pub struct RelayOptions {
+    /// Record every packet as it's forwarded, after any patching.
+    pub capture: Option<Capture>,
+    /// Decode every packet as it's forwarded, after any patching.
+    pub log: Option<ProtocolLog>,
+    /// Rewrite the buttons and sticks of every input report on its way to the Switch.
+    pub remap: Option<Remap>,
+}
+
+/// Relays a whole session between a real controller and the Switch. `local_address` is the
+/// address of the adapter the Switch connected to, which replaces the controller's own address in
+/// its device info reply. Every packet is dumped at trace level as it goes past, and a summary of
+/// the whole session is logged once it ends.
+pub async fn relay_controller<SC, SI, CC, CI>(
+    switch_ctl: SC,
+    switch_itr: SI,
+    controller_ctl: CC,
+    controller_itr: CI,
+    local_address: BtAddr,
+    options: RelayOptions,
+) -> Result<()>
+where
+    SC: Transport,
+    SI: Transport,
+    CC: Transport,
+    CI: Transport,
+{
+    let dump = |channel: Channel, direction: Direction, packet: &[u8]| {
+        trace!(%channel, ?direction, bytes = packet.len(), "Packet\n{}", hexdump(packet));
+    };
+
+    let remap = options.remap.clone();
+
+    let forwarded = move |channel: Channel, direction: Direction, packet: &[u8]| {
+        if let Some(capture) = &options.capture {
+            capture.record(channel, direction, packet);
+        }
+
+        if let Some(log) = &options.log {
+            log.log(channel, direction, packet);
+        }
+    };
+
+    let started = Instant::now();
+    let mut ctl_stats = ChannelStats::default();
+    let mut itr_stats = ChannelStats::default();
+
+    let result = {
+        let ctl_relay = relay_channel(
+            Channel::Ctl,
+            switch_ctl,
+            controller_ctl,
+            &mut ctl_stats,
+            |direction, packet| {
+                dump(Channel::Ctl, direction, packet);
+                forwarded(Channel::Ctl, direction, packet);
+            },
+        );
+
+        let itr_relay = relay_channel(
+            Channel::Itr,
+            switch_itr,
+            controller_itr,
+            &mut itr_stats,
+            |direction, packet| {
+                dump(Channel::Itr, direction, packet);
+
+                match direction {
+                    Direction::SwitchToController => report_switch_settings(packet),
+                    Direction::ControllerToSwitch => {
+                        patch_device_info(packet, local_address);
+
+                        if let Some(remap) = &remap {
+                            remap.apply_to_packet(packet);
+                        }
+                    }
+                }
+
+                forwarded(Channel::Itr, direction, packet);
+            },
+        );
+
+        relay_session(ctl_relay, itr_relay).await
+    };
+
+    info!(
+        duration_ms = started.elapsed().as_millis() as u64,
+        ctl.packets_from_switch = ctl_stats.packets_from_switch,
+        ctl.bytes_from_switch = ctl_stats.bytes_from_switch,
+        ctl.packets_from_controller = ctl_stats.packets_from_controller,
+        ctl.bytes_from_controller = ctl_stats.bytes_from_controller,
+        itr.packets_from_switch = itr_stats.packets_from_switch,
+        itr.bytes_from_switch = itr_stats.bytes_from_switch,
+        itr.packets_from_controller = itr_stats.packets_from_controller,
+        itr.bytes_from_controller = itr_stats.bytes_from_controller,
+        "Session ended"
+    );
+
+    result
+}
+
+/// Lets the user know when the Switch changes how the controller should behave.
+fn report_switch_settings(packet: &[u8]) {
+    if let Ok(report) = OutputReport::parse_packet(packet) {
+        match report.subcommand() {
+            Some(Subcommand::SetInputReportMode(mode)) => {
+                info!("Switch set input report mode to {:#04x}", mode)
+            }
+            Some(Subcommand::SetPlayerLights(lights)) => {
+                info!("Switch set player lights to {:08b}", lights)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Rewrites the address in a controller's device info reply to `address`, so the Switch keeps
+/// talking to us instead of the controller. Any other packet is left alone.
+pub fn patch_device_info(packet: &mut [u8], address: BtAddr) {
+    let mut report = match InputReport::parse_packet(packet) {
+        Ok(InputReport::Standard(report)) => report,
+        _ => return,
+    };
+
+    let reply = match &mut report.body {
+        // A NACK carries no address, only padding
+        ReportBody::SubcommandReply(reply)
+            if reply.id == 0x02 && reply.ack & 0x80 != 0 && reply.data.len() >= 10 =>
+        {
+            reply
+        }
+        _ => return,
+    };
+
+    let mut old_addr = BtAddr([0; 6]);
+    old_addr.0.copy_from_slice(&reply.data[4..10]);
+
+    reply.data[4..10].copy_from_slice(&address.0);
+
+    info!(old = %old_addr, new = %address, "Patched the address in a device info reply");
+
+    // Encoding only ever adds padding, which the original packet didn't have room for
+    let encoded = InputReport::Standard(report).encode_packet();
+    let len = packet.len();
+    packet.copy_from_slice(&encoded[..len]);
+}
+
+pub fn hexdump(buf: &[u8]) -> String {
+    let mut out = String::with_capacity(buf.len() * 4);
+
+    for chunk in buf.chunks(16) {
+        for byte in chunk {
+            write!(out, "{:02x} ", byte).unwrap();
+        }
+
+        for _ in 0..16 - chunk.len() {
+            out.push_str("   ");
+        }
+
+        out.push(' ');
+
+        for byte in chunk {
+            let c = *byte as char;
+
+            if c.is_alphanumeric() {
+                out.push(c);
+            } else {
+                out.push('.');
+            }
+        }
+
+        out.push('\n');
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::ControllerType;
+    use crate::input_report::StandardInputReport;
+    use crate::subcommand::SubcommandReply;
+    use crate::transport::{HidChannels, MemoryChannel};
+    use crate::virtual_controller::{ControllerCommand, Misbehavior, VirtualController};
+    use crate::virtual_switch::VirtualSwitch;
+
+    use smol::{Task, Timer};
+
+    use std::time::Duration;
+
+    const CONTROLLER: BtAddr = BtAddr([0x98, 0xb6, 0xe9, 0x0a, 0x0b, 0x0c]);
+    const LOCAL: BtAddr = BtAddr([0xdc, 0xa6, 0x32, 0x01, 0x02, 0x03]);
+
+    /// A relay between `controller` and a virtual Switch, which is returned.
+    fn start_relay(
+        controller: VirtualController,
+    ) -> (VirtualSwitch<MemoryChannel>, Task<Result<()>>) {
+        let (switch, relay_switch) = HidChannels::pair(MemoryChannel::pair).unwrap();
+        let (relay_controller_end, controller_end) =
+            HidChannels::pair(MemoryChannel::pair).unwrap();
+
+        Task::spawn(async move {
+            let _ = controller.run(controller_end).await;
+        })
+        .detach();
+
+        let relay = Task::spawn(relay_controller(
+            relay_switch.ctl,
+            relay_switch.itr,
+            relay_controller_end.ctl,
+            relay_controller_end.itr,
+            LOCAL,
+            RelayOptions::default(),
+        ));
+
+        (VirtualSwitch::new(switch), relay)
+    }
+
+    /// `future`'s output, unless it takes longer than a second.
+    async fn within_a_second<F: Future>(future: F) -> Option<F::Output> {
+        futures::pin_mut!(future);
+
+        match future::select(future, Timer::after(Duration::from_secs(1))).await {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+
+    fn device_info_packet(reply: SubcommandReply) -> Vec<u8> {
+        InputReport::Standard(StandardInputReport {
+            id: 0x21,
+            timer: 0,
+            battery_connection: 0x8E,
+            buttons: Default::default(),
+            left_stick: Default::default(),
+            right_stick: Default::default(),
+            vibrator_report: 0x80,
+            body: ReportBody::SubcommandReply(reply),
+        })
+        .encode_packet()
+    }
+
+    #[test]
+    fn patches_an_acked_device_info_reply() {
+        let mut packet = device_info_packet(SubcommandReply::device_info(
+            ControllerType::ProController,
+            CONTROLLER,
+        ));
+        let mut expected = packet.clone();
+
+        patch_device_info(&mut packet, LOCAL);
+
+        // HIDP header, 13 bytes of controller state, ACK, id and 4 bytes of device info
+        expected[20..26].copy_from_slice(&LOCAL.0);
+        assert_eq!(packet, expected);
+    }
+
+    #[test]
+    fn leaves_a_nacked_device_info_reply_alone() {
+        let mut reply = SubcommandReply::device_info(ControllerType::ProController, CONTROLLER);
+        reply.ack = 0x00;
+
+        let mut packet = device_info_packet(reply);
+        let original = packet.clone();
+
+        patch_device_info(&mut packet, LOCAL);
+        assert_eq!(packet, original);
+    }
+
+    #[test]
+    fn leaves_a_truncated_device_info_reply_alone() {
+        let full = device_info_packet(SubcommandReply::device_info(
+            ControllerType::ProController,
+            CONTROLLER,
+        ));
+
+        // Cut off partway through the address
+        let mut packet = full[..22].to_vec();
+        let original = packet.clone();
+
+        patch_device_info(&mut packet, LOCAL);
+        assert_eq!(packet, original);
+    }
+
+    #[test]
+    fn switch_sees_the_local_address() {
+        smol::run(async {
+            let controller = VirtualController::new(ControllerType::ProController, CONTROLLER);
+            let handle = controller.handle();
+            let (mut switch, relay) = start_relay(controller);
+
+            let info = switch.pair().await.unwrap();
+            assert_eq!(info.address, LOCAL);
+            assert_eq!(info.controller, Some(ControllerType::ProController));
+
+            switch
+                .expect_input_reports(10, Duration::from_millis(200))
+                .await
+                .unwrap();
+
+            handle.send(ControllerCommand::Disconnect);
+            relay.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn switch_sees_a_nack_unpatched() {
+        smol::run(async {
+            let mut controller = VirtualController::new(ControllerType::ProController, CONTROLLER);
+            let mut nack = SubcommandReply::device_info(ControllerType::ProController, CONTROLLER);
+            nack.ack = 0x00;
+            controller.set_reply(nack);
+
+            let handle = controller.handle();
+            let (mut switch, relay) = start_relay(controller);
+
+            let reply = switch
+                .host()
+                .send_subcommand(&Subcommand::RequestDeviceInfo)
+                .await
+                .unwrap();
+
+            assert_eq!(reply.ack, 0x00);
+            assert_eq!(&reply.data[4..10], &CONTROLLER.0);
+
+            handle.send(ControllerCommand::Disconnect);
+            relay.await.unwrap();
+        })
+    }
+
+    #[test]
+    fn survives_truncated_packets() {
+        smol::run(async {
+            for &len in &[0, 1, 5, 16, 22] {
+                let mut controller =
+                    VirtualController::new(ControllerType::ProController, CONTROLLER);
+                controller.set_misbehavior(Misbehavior::Truncate(len));
+
+                let handle = controller.handle();
+                let (mut switch, relay) = start_relay(controller);
+
+                // Nothing the Switch can make sense of comes back
+                let paired = within_a_second(switch.pair()).await;
+                assert!(!matches!(paired, Some(Ok(_))), "paired with {} bytes", len);
+
+                handle.send(ControllerCommand::Disconnect);
+                relay.await.unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn ends_when_the_controller_disconnects() {
+        smol::run(async {
+            let controller = VirtualController::new(ControllerType::ProController, CONTROLLER);
+            let handle = controller.handle();
+            let (mut switch, relay) = start_relay(controller);
+
+            switch.pair().await.unwrap();
+
+            handle.send(ControllerCommand::Disconnect);
+            relay.await.unwrap();
+
+            // The relay hung up on the Switch too
+            let read = within_a_second(async {
+                loop {
+                    if let Err(e) = switch.host().read_input_report().await {
+                        break e;
+                    }
+                }
+            })
+            .await;
+
+            assert_eq!(
+                read.map(|e| e.kind()),
+                Some(std::io::ErrorKind::UnexpectedEof)
+            );
+        })
+    }
+}
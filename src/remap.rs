@@ -0,0 +1,633 @@
+//! Rewrites the buttons and sticks of a controller's input reports on their way to the Switch,
+//! so a relayed controller can be rearranged without touching the console.
+//!
+//! A remap is read from a file with one rule per line, applied in order. Anything after a `#` is
+//! a comment. Buttons go by their [`Button`] names, in any case.
+//!
+//! ```text
+//! swap A B                  # A is pressed as B, and B as A
+//! map Capture Home          # Capture is pressed as Home instead
+//! disable Home              # Home is never pressed
+//! invert left vertical      # flip the left stick upside down
+//! deadzone right 0.15       # ignore the right stick until it's 15% of the way out
+//! curve left 2.0            # square the left stick's distance from the center, for finer aim
+//! ```
+//!
+//! Button rules are all about the physical button: `map X A` after `swap A B` still presses A.
+//! Stick positions are taken relative to the nominal center, not the controller's calibration.
+
+use crate::input_report::{Button, InputReport, ParseButtonError, StandardInputReport, StickData};
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Nominal center, and distance from it to either edge, of a 12-bit stick axis.
+const STICK_CENTER: f64 = 2048.0;
+const STICK_RANGE: f64 = 2047.0;
+
+/// Everything to rewrite in an input report.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remap {
+    /// What each button, in [`Button::ALL`] order, is pressed as, if anything.
+    buttons: [Option<Button>; Button::ALL.len()],
+    left_stick: StickRemap,
+    right_stick: StickRemap,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct StickRemap {
+    invert_horizontal: bool,
+    invert_vertical: bool,
+    /// How far out, from 0 to 1, the stick has to go before it leaves the center.
+    deadzone: f64,
+    /// Distance from the center is raised to this power, after the deadzone.
+    curve: f64,
+}
+
+impl Default for StickRemap {
+    fn default() -> StickRemap {
+        StickRemap {
+            invert_horizontal: false,
+            invert_vertical: false,
+            deadzone: 0.0,
+            curve: 1.0,
+        }
+    }
+}
+
+/// Returned for a line of a remap that doesn't make sense.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseRemapError {
+    /// Starting at 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseRemapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseRemapError {}
+
+impl Default for Remap {
+    /// Leaves everything as it is.
+    fn default() -> Remap {
+        let mut buttons = [None; Button::ALL.len()];
+
+        for (target, &button) in buttons.iter_mut().zip(Button::ALL.iter()) {
+            *target = Some(button);
+        }
+
+        Remap {
+            buttons,
+            left_stick: StickRemap::default(),
+            right_stick: StickRemap::default(),
+        }
+    }
+}
+
+impl Remap {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Remap> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|e: ParseRemapError| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Rewrites the buttons and sticks of a packet read from the controller's itr channel, if
+    /// it's a standard input report. Any other packet is left alone.
+    pub fn apply_to_packet(&self, packet: &mut [u8]) {
+        let mut report = match InputReport::parse_packet(packet) {
+            Ok(InputReport::Standard(report)) => report,
+            _ => return,
+        };
+
+        self.apply(&mut report);
+
+        // Encoding only ever adds padding, which the original packet didn't have room for
+        let encoded = InputReport::Standard(report).encode_packet();
+        let len = packet.len();
+        packet.copy_from_slice(&encoded[..len]);
+    }
+
+    pub fn apply(&self, report: &mut StandardInputReport) {
+        let pressed = report.buttons;
+
+        for button in Button::ALL.iter().copied() {
+            report.buttons.set(button, false);
+        }
+
+        for button in pressed.pressed() {
+            if let Some(target) = self.buttons[index(button)] {
+                report.buttons.set(target, true);
+            }
+        }
+
+        report.left_stick = self.left_stick.apply(report.left_stick);
+        report.right_stick = self.right_stick.apply(report.right_stick);
+    }
+
+    fn parse_rule(&mut self, words: &[&str]) -> Result<(), String> {
+        match words {
+            ["swap", a, b] => {
+                let (a, b) = (index(parse_button(a)?), index(parse_button(b)?));
+                self.buttons.swap(a, b);
+            }
+            ["map", from, to] => {
+                self.buttons[index(parse_button(from)?)] = Some(parse_button(to)?);
+            }
+            ["disable", button] => self.buttons[index(parse_button(button)?)] = None,
+            ["invert", stick, "horizontal"] => self.stick(stick)?.invert_horizontal ^= true,
+            ["invert", stick, "vertical"] => self.stick(stick)?.invert_vertical ^= true,
+            ["invert", _, axis] => {
+                return Err(format!(
+                    "unknown axis '{}', expected horizontal or vertical",
+                    axis
+                ))
+            }
+            ["deadzone", stick, amount] => {
+                let amount = parse_number(amount)?;
+
+                if !(0.0..1.0).contains(&amount) {
+                    return Err("deadzone has to be from 0 up to, but not including, 1".into());
+                }
+
+                self.stick(stick)?.deadzone = amount;
+            }
+            ["curve", stick, power] => {
+                let power = parse_number(power)?;
+
+                if power.is_nan() || power <= 0.0 {
+                    return Err("curve has to be more than 0".into());
+                }
+
+                self.stick(stick)?.curve = power;
+            }
+            [rule, ..] => {
+                return Err(match *rule {
+                    "swap" | "map" | "disable" | "invert" | "deadzone" | "curve" => {
+                        format!("wrong number of arguments to {}", rule)
+                    }
+                    _ => format!("unknown rule '{}'", rule),
+                })
+            }
+            [] => {}
+        }
+
+        Ok(())
+    }
+
+    fn stick(&mut self, name: &str) -> Result<&mut StickRemap, String> {
+        match name {
+            "left" => Ok(&mut self.left_stick),
+            "right" => Ok(&mut self.right_stick),
+            _ => Err(format!("unknown stick '{}', expected left or right", name)),
+        }
+    }
+}
+
+impl FromStr for Remap {
+    type Err = ParseRemapError;
+
+    fn from_str(s: &str) -> Result<Remap, ParseRemapError> {
+        let mut remap = Remap::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            remap
+                .parse_rule(&words)
+                .map_err(|message| ParseRemapError {
+                    line: i + 1,
+                    message,
+                })?;
+        }
+
+        Ok(remap)
+    }
+}
+
+impl StickRemap {
+    fn apply(&self, stick: StickData) -> StickData {
+        let mut horizontal = to_axis(stick.horizontal);
+        let mut vertical = to_axis(stick.vertical);
+
+        if self.invert_horizontal {
+            horizontal = -horizontal;
+        }
+
+        if self.invert_vertical {
+            vertical = -vertical;
+        }
+
+        if self.deadzone > 0.0 || self.curve != 1.0 {
+            let distance = horizontal.hypot(vertical);
+
+            if distance <= self.deadzone {
+                return StickData::CENTER;
+            }
+
+            // Scaled so the stick still reaches the edge
+            let scaled = ((distance - self.deadzone) / (1.0 - self.deadzone))
+                .min(1.0)
+                .powf(self.curve);
+
+            horizontal *= scaled / distance;
+            vertical *= scaled / distance;
+        }
+
+        StickData {
+            horizontal: from_axis(horizontal),
+            vertical: from_axis(vertical),
+        }
+    }
+}
+
+fn index(button: Button) -> usize {
+    Button::ALL.iter().position(|&b| b == button).unwrap()
+}
+
+fn parse_button(name: &str) -> Result<Button, String> {
+    name.parse().map_err(|e: ParseButtonError| e.to_string())
+}
+
+fn parse_number(number: &str) -> Result<f64, String> {
+    number
+        .parse()
+        .map_err(|_| format!("'{}' isn't a number", number))
+}
+
+/// A raw 12-bit axis value as -1 to 1.
+fn to_axis(value: u16) -> f64 {
+    ((value as f64 - STICK_CENTER) / STICK_RANGE).clamp(-1.0, 1.0)
+}
+
+fn from_axis(axis: f64) -> u16 {
+    (STICK_CENTER + axis * STICK_RANGE)
+        .round()
+        .clamp(0.0, 4095.0) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input_report::STANDARD_REPORT_LEN;
+
+    /// A full report, with `pressed` held and the sticks at `left` and `right`.
+    fn report(pressed: &[Button], left: StickData, right: StickData) -> StandardInputReport {
+        let mut bytes = vec![0x30, 0x00, 0x8e];
+        bytes.extend_from_slice(&[0; 3]);
+        bytes.extend_from_slice(&left.to_bytes());
+        bytes.extend_from_slice(&right.to_bytes());
+        bytes.resize(STANDARD_REPORT_LEN, 0);
+
+        let mut report = match InputReport::parse(&bytes).unwrap() {
+            InputReport::Standard(report) => report,
+            other => panic!("expected a standard report, got {:?}", other),
+        };
+
+        for &button in pressed {
+            report.buttons.set(button, true);
+        }
+
+        report
+    }
+
+    /// What the Switch sees pressed when `pressed` are held, in [`Button::ALL`] order.
+    fn pressed_as(remap: &str, pressed: &[Button]) -> Vec<Button> {
+        let remap = remap.parse::<Remap>().unwrap();
+        let mut report = report(pressed, StickData::CENTER, StickData::CENTER);
+        remap.apply(&mut report);
+        report.buttons.pressed().collect()
+    }
+
+    fn parse_error(remap: &str) -> (usize, String) {
+        let error = remap.parse::<Remap>().unwrap_err();
+        (error.line, error.message)
+    }
+
+    fn stick(horizontal: u16, vertical: u16) -> StickData {
+        StickData {
+            horizontal,
+            vertical,
+        }
+    }
+
+    fn assert_near(actual: StickData, expected: StickData) {
+        let near = |a: u16, b: u16| (a as i32 - b as i32).abs() <= 1;
+
+        assert!(
+            near(actual.horizontal, expected.horizontal)
+                && near(actual.vertical, expected.vertical),
+            "{:?} isn't near {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn empty_remap_changes_nothing() {
+        assert_eq!("".parse::<Remap>().unwrap(), Remap::default());
+        assert_eq!(
+            "  # just a comment\n\n".parse::<Remap>().unwrap(),
+            Remap::default()
+        );
+
+        for &button in Button::ALL.iter() {
+            assert_eq!(pressed_as("", &[button]), vec![button]);
+        }
+
+        let mut report = report(&[Button::A], stick(0x123, 0xabc), stick(0xfff, 0x001));
+        let original = report.clone();
+        Remap::default().apply(&mut report);
+        assert_eq!(report, original);
+    }
+
+    #[test]
+    fn swaps_buttons() {
+        let remap = "swap A B";
+
+        assert_eq!(pressed_as(remap, &[Button::A]), vec![Button::B]);
+        assert_eq!(pressed_as(remap, &[Button::B]), vec![Button::A]);
+        assert_eq!(
+            pressed_as(remap, &[Button::A, Button::B]),
+            vec![Button::B, Button::A]
+        );
+        assert_eq!(pressed_as(remap, &[Button::X]), vec![Button::X]);
+
+        // Swapping back undoes it
+        assert_eq!(
+            pressed_as("swap A B\nswap B A", &[Button::A]),
+            vec![Button::A]
+        );
+    }
+
+    #[test]
+    fn maps_buttons() {
+        let remap = "map Capture Home";
+
+        assert_eq!(pressed_as(remap, &[Button::Capture]), vec![Button::Home]);
+        assert_eq!(pressed_as(remap, &[Button::Home]), vec![Button::Home]);
+        assert_eq!(
+            pressed_as(remap, &[Button::Capture, Button::Home]),
+            vec![Button::Home]
+        );
+    }
+
+    #[test]
+    fn disables_buttons() {
+        let remap = "disable Home";
+
+        assert_eq!(pressed_as(remap, &[Button::Home]), vec![]);
+        assert_eq!(
+            pressed_as(remap, &[Button::Home, Button::A]),
+            vec![Button::A]
+        );
+
+        // Mapping a button onto a disabled one still presses it
+        assert_eq!(
+            pressed_as("disable Home\nmap Capture Home", &[Button::Capture]),
+            vec![Button::Home]
+        );
+    }
+
+    #[test]
+    fn rules_are_about_the_physical_button() {
+        let remap = "swap A B\nmap X A";
+
+        assert_eq!(pressed_as(remap, &[Button::X]), vec![Button::A]);
+        assert_eq!(pressed_as(remap, &[Button::A]), vec![Button::B]);
+        assert_eq!(pressed_as(remap, &[Button::B]), vec![Button::A]);
+
+        assert_eq!(
+            pressed_as("swap A B\nmap A X", &[Button::A]),
+            vec![Button::X]
+        );
+    }
+
+    #[test]
+    fn ignores_button_case_comments_and_whitespace() {
+        let remap = "\n  swap  a   b  # comment\n\t map zl Zr\n# map Y X\n";
+
+        assert_eq!(pressed_as(remap, &[Button::A]), vec![Button::B]);
+        assert_eq!(pressed_as(remap, &[Button::ZL]), vec![Button::ZR]);
+        assert_eq!(pressed_as(remap, &[Button::Y]), vec![Button::Y]);
+    }
+
+    #[test]
+    fn parses_stick_rules() {
+        let remap = "invert left vertical\n\
+                     invert right horizontal\n\
+                     invert right horizontal\n\
+                     deadzone right 0.15\n\
+                     curve left 2.0"
+            .parse::<Remap>()
+            .unwrap();
+
+        assert_eq!(
+            remap.left_stick,
+            StickRemap {
+                invert_vertical: true,
+                curve: 2.0,
+                ..StickRemap::default()
+            }
+        );
+        assert_eq!(
+            remap.right_stick,
+            StickRemap {
+                deadzone: 0.15,
+                ..StickRemap::default()
+            }
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_a_bad_rule() {
+        assert_eq!(
+            parse_error("frobnicate A"),
+            (1, "unknown rule 'frobnicate'".to_string())
+        );
+        assert_eq!(
+            parse_error("swap A B\n\n# comment\nmap A"),
+            (4, "wrong number of arguments to map".to_string())
+        );
+        assert_eq!(
+            parse_error("disable A B"),
+            (1, "wrong number of arguments to disable".to_string())
+        );
+        assert_eq!(
+            parse_error("swap A B\nswap A Q"),
+            (2, "unknown button 'Q'".to_string())
+        );
+        assert_eq!(
+            parse_error("invert middle vertical"),
+            (
+                1,
+                "unknown stick 'middle', expected left or right".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("invert left diagonal"),
+            (
+                1,
+                "unknown axis 'diagonal', expected horizontal or vertical".to_string()
+            )
+        );
+        assert_eq!(
+            parse_error("deadzone left lots"),
+            (1, "'lots' isn't a number".to_string())
+        );
+
+        for amount in &["1", "-0.1", "NaN"] {
+            assert_eq!(
+                parse_error(&format!("deadzone left {}", amount)),
+                (
+                    1,
+                    "deadzone has to be from 0 up to, but not including, 1".to_string()
+                )
+            );
+        }
+
+        for power in &["0", "-1", "NaN"] {
+            assert_eq!(
+                parse_error(&format!("curve right {}", power)),
+                (1, "curve has to be more than 0".to_string())
+            );
+        }
+
+        let error = "map A B\nswap".parse::<Remap>().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 2: wrong number of arguments to swap"
+        );
+    }
+
+    #[test]
+    fn rewrites_packets() {
+        let remap = "swap A B\ninvert left horizontal".parse::<Remap>().unwrap();
+
+        let mut packet =
+            InputReport::Standard(report(&[Button::A], stick(0xfff, 0x800), StickData::CENTER))
+                .encode_packet();
+        remap.apply_to_packet(&mut packet);
+
+        let expected = report(&[Button::B], stick(0x001, 0x800), StickData::CENTER);
+        assert_eq!(
+            InputReport::parse_packet(&packet).unwrap(),
+            InputReport::Standard(expected)
+        );
+
+        // Anything else, like a simple HID report, is left alone
+        let mut packet = vec![
+            0xA1, 0x3F, 0x08, 0x00, 0x08, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80, 0x00,
+        ];
+        let original = packet.clone();
+        remap.apply_to_packet(&mut packet);
+        assert_eq!(packet, original);
+    }
+
+    #[test]
+    fn default_stick_is_untouched() {
+        let remap = StickRemap::default();
+
+        for &value in &[0x001, 0x400, 0x7ff, 0x800, 0x801, 0xabc, 0xfff] {
+            assert_eq!(remap.apply(stick(value, value)), stick(value, value));
+        }
+    }
+
+    #[test]
+    fn inverts_sticks() {
+        let horizontal = StickRemap {
+            invert_horizontal: true,
+            ..StickRemap::default()
+        };
+        let vertical = StickRemap {
+            invert_vertical: true,
+            ..StickRemap::default()
+        };
+
+        assert_eq!(horizontal.apply(StickData::CENTER), StickData::CENTER);
+        assert_eq!(horizontal.apply(stick(0xfff, 0x001)), stick(0x001, 0x001));
+        assert_eq!(horizontal.apply(stick(0x001, 0xfff)), stick(0xfff, 0xfff));
+        assert_eq!(horizontal.apply(stick(0x900, 0x900)), stick(0x700, 0x900));
+
+        assert_eq!(vertical.apply(StickData::CENTER), StickData::CENTER);
+        assert_eq!(vertical.apply(stick(0xfff, 0x001)), stick(0xfff, 0xfff));
+        assert_eq!(vertical.apply(stick(0x001, 0xfff)), stick(0x001, 0x001));
+    }
+
+    #[test]
+    fn deadzone_keeps_the_center_and_the_edges() {
+        let remap = StickRemap {
+            deadzone: 0.2,
+            ..StickRemap::default()
+        };
+
+        assert_eq!(remap.apply(StickData::CENTER), StickData::CENTER);
+
+        // 0.15 and 0.2 of the way out, in different directions
+        assert_eq!(remap.apply(stick(0x800 + 307, 0x800)), StickData::CENTER);
+        assert_eq!(remap.apply(stick(0x800, 0x800 - 409)), StickData::CENTER);
+
+        // Everything past the deadzone is stretched to still reach the edges
+        assert_eq!(remap.apply(stick(0xfff, 0x800)), stick(0xfff, 0x800));
+        assert_eq!(remap.apply(stick(0x800, 0x001)), stick(0x800, 0x001));
+        assert_near(
+            remap.apply(stick(0x800 + 1228, 0x800)),
+            stick(0x800 + 1023, 0x800),
+        );
+
+        // Corners are pulled in to the edge of the circle
+        assert_near(
+            remap.apply(stick(0xfff, 0xfff)),
+            stick(0x800 + 1447, 0x800 + 1447),
+        );
+    }
+
+    #[test]
+    fn curve_keeps_the_center_and_the_edges() {
+        let remap = StickRemap {
+            curve: 2.0,
+            ..StickRemap::default()
+        };
+
+        assert_eq!(remap.apply(StickData::CENTER), StickData::CENTER);
+        assert_eq!(remap.apply(stick(0xfff, 0x800)), stick(0xfff, 0x800));
+        assert_eq!(remap.apply(stick(0x001, 0x800)), stick(0x001, 0x800));
+        assert_eq!(remap.apply(stick(0x800, 0xfff)), stick(0x800, 0xfff));
+
+        // Halfway out ends up a quarter of the way, in the same direction
+        assert_near(
+            remap.apply(stick(0x800 + 1024, 0x800)),
+            stick(0x800 + 512, 0x800),
+        );
+        assert_near(
+            remap.apply(stick(0x800, 0x800 - 1024)),
+            stick(0x800, 0x800 - 512),
+        );
+
+        let diagonal = remap.apply(stick(0x800 + 724, 0x800 + 724));
+        assert_eq!(diagonal.horizontal, diagonal.vertical);
+        assert_near(diagonal, stick(0x800 + 362, 0x800 + 362));
+    }
+
+    #[test]
+    fn inverts_before_the_deadzone_and_curve() {
+        let remap = StickRemap {
+            invert_vertical: true,
+            deadzone: 0.2,
+            curve: 2.0,
+            ..StickRemap::default()
+        };
+
+        assert_eq!(remap.apply(StickData::CENTER), StickData::CENTER);
+        assert_eq!(remap.apply(stick(0x800, 0x800 + 300)), StickData::CENTER);
+        assert_eq!(remap.apply(stick(0x800, 0xfff)), stick(0x800, 0x001));
+        assert_eq!(remap.apply(stick(0x800, 0x001)), stick(0x800, 0xfff));
+    }
+}
@@ -0,0 +1,388 @@
+//! Plays the controller's side of a capture back to a Switch, for reproducing whatever the
+//! Switch made of it.
+//!
+//! Input reports go out on the capture's timeline, sped up or slowed down if asked. Subcommand
+//! replies don't, since the Switch won't ask at quite the same moments twice: each subcommand is
+//! answered with the next recorded reply to the same subcommand id instead, and SPI flash reads
+//! with the next one for the same address and length.
+
+use crate::capture::CapturedPacket;
+use crate::controller::ControllerType;
+use crate::input_report::{InputReport, ReportBody};
+use crate::output_report::OutputReport;
+use crate::relay::{patch_device_info, Channel, Direction};
+use crate::subcommand::{Subcommand, SubcommandReply};
+use crate::transport::{HidChannels, Transport};
+use crate::BtAddr;
+
+use futures::prelude::*;
+use smol::Timer;
+use tracing::{info, warn};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::time::Instant;
+
+/// Slowest the timeline can be played back. Any slower and a long capture's timestamps could
+/// overflow once they're scaled.
+pub const MIN_SPEED: f64 = 0.001;
+
+/// What a recorded reply answers: the subcommand id, and for SPI flash reads the address and
+/// length that were read.
+type ReplyKey = (u8, Option<(u32, u8)>);
+
+/// Whatever woke the replay up.
+enum Event {
+    Read(usize),
+    Due,
+}
+
+/// The controller's side of a capture, ready to be played back.
+pub struct Replay {
+    /// Everything but subcommand replies, in the order it was sent.
+    timeline: VecDeque<CapturedPacket>,
+    /// Subcommand replies still to be given, by what they answer.
+    replies: BTreeMap<ReplyKey, VecDeque<Vec<u8>>>,
+    /// The last reply given to each subcommand, for when the Switch asks more often than it did
+    /// in the capture.
+    last_replies: BTreeMap<ReplyKey, Vec<u8>>,
+    controller: Option<ControllerType>,
+    address: BtAddr,
+    speed: f64,
+}
+
+impl Replay {
+    /// Plays back `packets` as read from a capture, with the controller's address in its device
+    /// info replaced by `address`, like the relay does.
+    pub fn new(packets: Vec<CapturedPacket>, address: BtAddr) -> Replay {
+        let mut timeline = VecDeque::new();
+        let mut replies: BTreeMap<ReplyKey, VecDeque<Vec<u8>>> = BTreeMap::new();
+        let mut controller = None;
+
+        for captured in packets {
+            if captured.direction != Direction::ControllerToSwitch {
+                continue;
+            }
+
+            if let Ok(InputReport::Standard(report)) = InputReport::parse_packet(&captured.packet) {
+                if let ReportBody::SubcommandReply(reply) = &report.body {
+                    if reply.id == 0x02 && reply.data.len() > 2 {
+                        controller = controller.or_else(|| ControllerType::from_id(reply.data[2]));
+                    }
+
+                    replies
+                        .entry(reply_key(reply))
+                        .or_default()
+                        .push_back(captured.packet);
+                    continue;
+                }
+            }
+
+            timeline.push_back(captured);
+        }
+
+        Replay {
+            timeline,
+            replies,
+            last_replies: BTreeMap::new(),
+            controller,
+            address,
+            speed: 1.0,
+        }
+    }
+
+    /// How much faster than recorded to play the timeline back, 2.0 being twice as fast. Fails
+    /// with [`ErrorKind::InvalidInput`] if `speed` is below [`MIN_SPEED`], or NaN.
+    pub fn set_speed(&mut self, speed: f64) -> Result<()> {
+        if speed.is_nan() || speed < MIN_SPEED {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("speed has to be at least {}, not {}", MIN_SPEED, speed),
+            ));
+        }
+
+        self.speed = speed;
+
+        Ok(())
+    }
+
+    /// What the recorded controller said it was in its device info, if it was asked.
+    pub fn controller(&self) -> Option<ControllerType> {
+        self.controller
+    }
+
+    /// Plays the capture back until the timeline runs out, or until the Switch hangs up on itr.
+    pub async fn run<T: Transport>(mut self, channels: HidChannels<T>) -> Result<()> {
+        let HidChannels { mut ctl, itr } = channels;
+        let (mut itr_r, mut itr_w) = itr.split();
+
+        let mut incoming = [0u8; 128];
+        let start = Instant::now();
+
+        loop {
+            let due = match self.timeline.front() {
+                Some(next) => Timer::at(start + next.time.div_f64(self.speed)),
+                None => {
+                    info!("Reached the end of the capture");
+                    return Ok(());
+                }
+            };
+
+            let event = futures::select! {
+                read = itr_r.read(&mut incoming).fuse() => Event::Read(read?),
+                _ = due.fuse() => Event::Due,
+            };
+
+            match event {
+                Event::Read(0) => {
+                    info!("Switch closed itr");
+                    return Ok(());
+                }
+                Event::Read(n) => {
+                    if let Some(reply) = self.reply(&incoming[..n]) {
+                        itr_w.write_all(&reply).await?;
+                    }
+                }
+                Event::Due => {
+                    if let Some(next) = self.timeline.pop_front() {
+                        match next.channel {
+                            Channel::Ctl => ctl.write_all(&next.packet).await?,
+                            Channel::Itr => itr_w.write_all(&next.packet).await?,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The recorded reply to the subcommand in `packet`, if it has one.
+    fn reply(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let subcommand = match OutputReport::parse_packet(packet) {
+            Ok(OutputReport::RumbleAndSubcommand { subcommand, .. }) => subcommand,
+            _ => return None,
+        };
+
+        let key = match subcommand {
+            Subcommand::SpiFlashRead { address, length } => {
+                (subcommand.id(), Some((address, length)))
+            }
+            _ => (subcommand.id(), None),
+        };
+
+        let mut reply = match self.replies.get_mut(&key).and_then(VecDeque::pop_front) {
+            Some(reply) => reply,
+            None => match self.last_replies.get(&key) {
+                Some(reply) => reply.clone(),
+                None => {
+                    warn!(?subcommand, "No recorded reply, leaving it unanswered");
+                    return None;
+                }
+            },
+        };
+
+        if let Subcommand::RequestDeviceInfo = subcommand {
+            patch_device_info(&mut reply, self.address);
+        }
+
+        self.last_replies.insert(key, reply.clone());
+
+        Some(reply)
+    }
+}
+
+/// What `reply` answers. SPI flash read replies start with the address and length read, unless
+/// they're a NACK.
+fn reply_key(reply: &SubcommandReply) -> ReplyKey {
+    match reply.data.get(0..5) {
+        Some(read) if reply.id == 0x10 && reply.ack & 0x80 != 0 => {
+            let address = u32::from_le_bytes([read[0], read[1], read[2], read[3]]);
+            (reply.id, Some((address, read[4])))
+        }
+        _ => (reply.id, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{self, Capture};
+    use crate::input_report::{ImuFrame, StandardInputReport};
+    use crate::output_report::RumbleData;
+
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Lets a test read back what a capture wrote.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn input_report(id: u8, body: ReportBody) -> Vec<u8> {
+        InputReport::Standard(StandardInputReport {
+            id,
+            timer: 0,
+            battery_connection: 0x8E,
+            buttons: Default::default(),
+            left_stick: Default::default(),
+            right_stick: Default::default(),
+            vibrator_report: 0x80,
+            body,
+        })
+        .encode_packet()
+    }
+
+    fn spi_reply(address: u32, length: u8) -> Vec<u8> {
+        let mut data = address.to_le_bytes().to_vec();
+        data.push(length);
+        data.resize(5 + length as usize, address as u8);
+
+        input_report(
+            0x21,
+            ReportBody::SubcommandReply(SubcommandReply::with_data(0x90, 0x10, data)),
+        )
+    }
+
+    fn spi_read(address: u32, length: u8) -> Vec<u8> {
+        OutputReport::RumbleAndSubcommand {
+            packet_counter: 0,
+            rumble: RumbleData::NEUTRAL,
+            subcommand: Subcommand::SpiFlashRead { address, length },
+        }
+        .encode_packet()
+    }
+
+    /// The address a replayed SPI flash read reply is for.
+    fn replied_address(reply: Option<Vec<u8>>) -> Option<u32> {
+        match InputReport::parse_packet(&reply?) {
+            Ok(InputReport::Standard(StandardInputReport {
+                body: ReportBody::SubcommandReply(reply),
+                ..
+            })) => Some(u32::from_le_bytes([
+                reply.data[0],
+                reply.data[1],
+                reply.data[2],
+                reply.data[3],
+            ])),
+            _ => None,
+        }
+    }
+
+    fn record_capture() -> Vec<CapturedPacket> {
+        let buffer = SharedBuffer::default();
+        let capture = Capture::new(buffer.clone()).unwrap();
+
+        // Like waiting for the Switch to pair
+        thread::sleep(Duration::from_millis(50));
+
+        let imu = ReportBody::Imu {
+            frames: [ImuFrame::default(); 3],
+            extra: vec![],
+        };
+
+        capture.record(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &input_report(0x30, imu),
+        );
+        capture.record(
+            Channel::Itr,
+            Direction::SwitchToController,
+            &spi_read(0x6020, 0x18),
+        );
+        capture.record(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &spi_reply(0x6020, 0x18),
+        );
+        capture.record(
+            Channel::Itr,
+            Direction::SwitchToController,
+            &spi_read(0x603d, 0x19),
+        );
+        capture.record(
+            Channel::Itr,
+            Direction::ControllerToSwitch,
+            &spi_reply(0x603d, 0x19),
+        );
+        drop(capture);
+
+        let data = buffer.0.lock().unwrap().clone();
+        capture::read(&data[..]).unwrap()
+    }
+
+    #[test]
+    fn rejects_speeds_that_cant_be_played() {
+        let mut replay = Replay::new(vec![], BtAddr([0; 6]));
+
+        for &speed in &[
+            0.0,
+            -1.0,
+            f64::NAN,
+            f64::NEG_INFINITY,
+            1e-300,
+            MIN_SPEED / 2.0,
+        ] {
+            let error = replay.set_speed(speed).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+
+        assert_eq!(replay.speed, 1.0);
+
+        for &speed in &[MIN_SPEED, 0.5, 2.0, f64::INFINITY] {
+            replay.set_speed(speed).unwrap();
+            assert_eq!(replay.speed, speed);
+        }
+    }
+
+    #[test]
+    fn timeline_starts_at_the_first_packet() {
+        let packets = record_capture();
+
+        assert_eq!(packets.len(), 5);
+        assert_eq!(packets[0].time, Duration::from_secs(0));
+        assert!(packets[4].time < Duration::from_millis(50));
+
+        let replay = Replay::new(packets, BtAddr([0; 6]));
+
+        // Only the input report, the replies are kept aside
+        assert_eq!(replay.timeline.len(), 1);
+        assert_eq!(replay.timeline[0].time, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn spi_reads_get_the_reply_for_their_address() {
+        let mut replay = Replay::new(record_capture(), BtAddr([0; 6]));
+
+        // Asked the other way around from the capture
+        assert_eq!(
+            replied_address(replay.reply(&spi_read(0x603d, 0x19))),
+            Some(0x603d)
+        );
+        assert_eq!(
+            replied_address(replay.reply(&spi_read(0x6020, 0x18))),
+            Some(0x6020)
+        );
+
+        // Asked again, after the recorded replies ran out
+        assert_eq!(
+            replied_address(replay.reply(&spi_read(0x603d, 0x19))),
+            Some(0x603d)
+        );
+
+        // Never recorded, or not with that length
+        assert_eq!(replay.reply(&spi_read(0x8010, 0x18)), None);
+        assert_eq!(replay.reply(&spi_read(0x6020, 0x10)), None);
+    }
+}
@@ -0,0 +1,46 @@
+use std::fmt;
+
+/// HIDP transaction header for DATA | Input, prefixed to every report a controller sends on itr.
+pub const HIDP_DATA_INPUT: u8 = 0xA1;
+/// HIDP transaction header for DATA | Output, prefixed to every report the Switch sends on itr.
+pub const HIDP_DATA_OUTPUT: u8 = 0xA2;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ReportError {
+    Empty,
+    /// The packet didn't start with the HIDP header we expected.
+    BadHeader(u8),
+    TooShort {
+        id: u8,
+        len: usize,
+        expected: usize,
+    },
+}
+
+impl fmt::Display for ReportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReportError::Empty => write!(f, "empty report"),
+            ReportError::BadHeader(header) => write!(f, "unexpected HIDP header {:#04x}", header),
+            ReportError::TooShort { id, len, expected } => write!(
+                f,
+                "report {:#04x} is {} bytes, expected at least {}",
+                id, len, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReportError {}
+
+pub(crate) fn check_len(report: &[u8], expected: usize) -> Result<(), ReportError> {
+    if report.len() < expected {
+        Err(ReportError::TooShort {
+            id: report[0],
+            len: report.len(),
+            expected,
+        })
+    } else {
+        Ok(())
+    }
+}
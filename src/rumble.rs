@@ -0,0 +1,236 @@
+//! HD Rumble, as sent in output reports 0x01, 0x10 and 0x11.
+//!
+//! Each side gets 4 bytes holding a high band and a low band, each with a frequency and an
+//! amplitude. Frequencies are stored on a log2 scale, 32 steps per octave. Amplitudes are stored
+//! as a 0 to 100 code on a piecewise log2 scale. Only the standard single-pulse format is
+//! understood, which is what the Switch sends for the vast majority of games.
+
+/// Lowest and highest frequencies, in Hz, each band can represent.
+pub const HIGH_BAND_RANGE: (f32, f32) = (80.0, 1252.0);
+pub const LOW_BAND_RANGE: (f32, f32) = (40.0, 626.0);
+
+/// Amplitude codes go up to this, which is an amplitude of 1.0.
+const MAX_AMPLITUDE_CODE: u8 = 100;
+/// Below this code amplitudes use the steeper of the two log2 scales.
+const LOW_AMPLITUDE_CODES: u8 = 32;
+
+/// Decoded rumble for one side. Frequencies are in Hz, amplitudes go from 0.0 to 1.0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rumble {
+    pub high_frequency: f32,
+    pub high_amplitude: f32,
+    pub low_frequency: f32,
+    pub low_amplitude: f32,
+}
+
+impl Rumble {
+    /// Both bands at their default frequencies (320Hz and 160Hz), doing nothing.
+    pub const NEUTRAL: Rumble = Rumble {
+        high_frequency: 320.0,
+        high_amplitude: 0.0,
+        low_frequency: 160.0,
+        low_amplitude: 0.0,
+    };
+
+    pub fn decode(bytes: [u8; 4]) -> Rumble {
+        let high_frequency_code = (bytes[0] as u16 | (bytes[1] as u16 & 0x01) << 8) >> 2;
+        let high_amplitude_code = bytes[1] >> 1;
+        let low_frequency_code = bytes[2] & 0x7F;
+        let low_amplitude_code = (bytes[3].saturating_sub(0x40) << 1) | (bytes[2] >> 7);
+
+        Rumble {
+            high_frequency: decode_frequency(high_frequency_code as u8 + 0x60),
+            high_amplitude: decode_amplitude(high_amplitude_code),
+            low_frequency: decode_frequency(low_frequency_code + 0x40),
+            low_amplitude: decode_amplitude(low_amplitude_code),
+        }
+    }
+
+    /// Frequencies and amplitudes outside of what can be represented are clamped. Frequencies
+    /// that aren't finite are taken as the lowest in their band.
+    pub fn encode(&self) -> [u8; 4] {
+        let high_frequency =
+            (encode_frequency(self.high_frequency, HIGH_BAND_RANGE) - 0x60) as u16 * 4;
+        let high_amplitude = encode_amplitude(self.high_amplitude);
+        let low_frequency = encode_frequency(self.low_frequency, LOW_BAND_RANGE) - 0x40;
+        let low_amplitude = encode_amplitude(self.low_amplitude);
+
+        [
+            (high_frequency & 0xFF) as u8,
+            (high_amplitude << 1) | (high_frequency >> 8) as u8,
+            low_frequency | (low_amplitude & 0x01) << 7,
+            (low_amplitude >> 1) + 0x40,
+        ]
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.high_amplitude == 0.0 && self.low_amplitude == 0.0
+    }
+}
+
+impl Default for Rumble {
+    fn default() -> Rumble {
+        Rumble::NEUTRAL
+    }
+}
+
+fn decode_frequency(code: u8) -> f32 {
+    10.0 * 2f32.powf(code as f32 / 32.0)
+}
+
+fn encode_frequency(hz: f32, (min, max): (f32, f32)) -> u8 {
+    // NaN would otherwise come out as code 0, below the band
+    let hz = if hz.is_finite() {
+        hz.clamp(min, max)
+    } else {
+        min
+    };
+
+    ((hz / 10.0).log2() * 32.0).round() as u8
+}
+
+fn decode_amplitude(code: u8) -> f32 {
+    match code.min(MAX_AMPLITUDE_CODE) {
+        0 => 0.0,
+        code if code < LOW_AMPLITUDE_CODES => 2f32.powf(code as f32 / 16.0) / 17.0,
+        // The top code works out just over 1.0
+        code => (2f32.powf(code as f32 / 32.0) / 8.7).min(1.0),
+    }
+}
+
+fn encode_amplitude(amplitude: f32) -> u8 {
+    let amplitude = amplitude.clamp(0.0, 1.0);
+
+    // The first code on the upper scale belongs to it, or it would come back one lower
+    let code = if amplitude >= decode_amplitude(LOW_AMPLITUDE_CODES) {
+        (amplitude * 8.7).log2() * 32.0
+    } else {
+        (amplitude * 17.0).log2() * 16.0
+    };
+
+    // Amplitudes too small to represent come out negative (or -inf for 0.0), which is silence
+    code.round().clamp(0.0, MAX_AMPLITUDE_CODE as f32) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output_report::RumbleData;
+
+    /// The bytes for one side, from its four codes.
+    fn encoded(
+        high_frequency: u16,
+        high_amplitude: u8,
+        low_frequency: u8,
+        low_amplitude: u8,
+    ) -> [u8; 4] {
+        let high_frequency = high_frequency * 4;
+
+        [
+            (high_frequency & 0xFF) as u8,
+            (high_amplitude << 1) | (high_frequency >> 8) as u8,
+            low_frequency | (low_amplitude & 0x01) << 7,
+            (low_amplitude >> 1) + 0x40,
+        ]
+    }
+
+    #[test]
+    fn neutral_is_silent_at_the_default_frequencies() {
+        let data = RumbleData::NEUTRAL;
+
+        for rumble in [data.left_rumble(), data.right_rumble()].iter() {
+            assert_eq!(*rumble, Rumble::NEUTRAL);
+            assert!(rumble.is_silent());
+        }
+
+        assert_eq!(Rumble::NEUTRAL.encode(), data.left());
+        assert_eq!(
+            RumbleData::from_rumble(&Rumble::NEUTRAL, &Rumble::NEUTRAL),
+            data
+        );
+    }
+
+    #[test]
+    fn every_code_round_trips() {
+        for code in 0..=0x7F {
+            let bytes = encoded(code, 0, 0x20, 0);
+            assert_eq!(
+                Rumble::decode(bytes).encode(),
+                bytes,
+                "high frequency {}",
+                code
+            );
+
+            let bytes = encoded(0x20, 0, code as u8, 0);
+            assert_eq!(
+                Rumble::decode(bytes).encode(),
+                bytes,
+                "low frequency {}",
+                code
+            );
+        }
+
+        for code in 0..=MAX_AMPLITUDE_CODE {
+            let bytes = encoded(0x20, code, 0x20, 0);
+            assert_eq!(
+                Rumble::decode(bytes).encode(),
+                bytes,
+                "high amplitude {}",
+                code
+            );
+
+            let bytes = encoded(0x20, 0, 0x20, code);
+            assert_eq!(
+                Rumble::decode(bytes).encode(),
+                bytes,
+                "low amplitude {}",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn band_edges() {
+        let lowest = Rumble::decode(encoded(0, 0, 0, 0));
+        let highest = Rumble::decode(encoded(0x7F, 0, 0x7F, 0));
+
+        assert!((lowest.high_frequency - HIGH_BAND_RANGE.0).abs() < 1.0);
+        assert!((lowest.low_frequency - LOW_BAND_RANGE.0).abs() < 1.0);
+        assert!((highest.high_frequency - HIGH_BAND_RANGE.1).abs() < 1.0);
+        assert!((highest.low_frequency - LOW_BAND_RANGE.1).abs() < 1.0);
+
+        let loudest = Rumble::decode(encoded(0x20, MAX_AMPLITUDE_CODE, 0x20, MAX_AMPLITUDE_CODE));
+        assert_eq!(loudest.high_amplitude, 1.0);
+        assert_eq!(loudest.low_amplitude, 1.0);
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let too_much = Rumble {
+            high_frequency: 5000.0,
+            high_amplitude: 3.0,
+            low_frequency: 1.0,
+            low_amplitude: -1.0,
+        };
+
+        assert_eq!(too_much.encode(), encoded(0x7F, MAX_AMPLITUDE_CODE, 0, 0));
+    }
+
+    #[test]
+    fn non_finite_frequencies_are_the_lowest() {
+        for &hz in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let rumble = Rumble {
+                high_frequency: hz,
+                high_amplitude: f32::NAN,
+                low_frequency: hz,
+                low_amplitude: 0.5,
+            };
+
+            let bytes = rumble.encode();
+            assert_eq!(bytes[0], 0);
+            assert_eq!(bytes[1] & 0x01, 0);
+            assert_eq!(bytes[2] & 0x7F, 0);
+            assert!(Rumble::decode(bytes).high_amplitude == 0.0);
+        }
+    }
+}
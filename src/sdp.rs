@@ -0,0 +1,381 @@
+//! SDP records, built from typed data elements and serialized to the XML BlueZ takes for
+//! `org.bluez.ProfileManager1.RegisterProfile`.
+
+use crate::controller::ControllerType;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+pub const HID_UUID: &str = "00001124-0000-1000-8000-00805f9b34fb";
+
+pub const SERVICE_CLASS_ID_LIST: u16 = 0x0001;
+pub const PROTOCOL_DESCRIPTOR_LIST: u16 = 0x0004;
+pub const BROWSE_GROUP_LIST: u16 = 0x0005;
+pub const LANGUAGE_BASE_ATTRIBUTE_ID_LIST: u16 = 0x0006;
+pub const BLUETOOTH_PROFILE_DESCRIPTOR_LIST: u16 = 0x0009;
+pub const ADDITIONAL_PROTOCOL_DESCRIPTOR_LISTS: u16 = 0x000D;
+pub const SERVICE_NAME: u16 = 0x0100;
+pub const SERVICE_DESCRIPTION: u16 = 0x0101;
+pub const PROVIDER_NAME: u16 = 0x0102;
+pub const HID_DEVICE_RELEASE_NUMBER: u16 = 0x0200;
+pub const HID_PARSER_VERSION: u16 = 0x0201;
+pub const HID_DEVICE_SUBCLASS: u16 = 0x0202;
+pub const HID_COUNTRY_CODE: u16 = 0x0203;
+pub const HID_VIRTUAL_CABLE: u16 = 0x0204;
+pub const HID_RECONNECT_INITIATE: u16 = 0x0205;
+pub const HID_DESCRIPTOR_LIST: u16 = 0x0206;
+pub const HID_LANGID_BASE_LIST: u16 = 0x0207;
+pub const HID_PROFILE_VERSION: u16 = 0x020B;
+pub const HID_SUPERVISION_TIMEOUT: u16 = 0x020C;
+pub const HID_NORMALLY_CONNECTABLE: u16 = 0x020D;
+pub const HID_BOOT_DEVICE: u16 = 0x020E;
+pub const HID_SSR_HOST_MAX_LATENCY: u16 = 0x020F;
+pub const HID_SSR_HOST_MIN_TIMEOUT: u16 = 0x0210;
+
+const UUID_L2CAP: u16 = 0x0100;
+const UUID_HIDP: u16 = 0x0011;
+const UUID_HID: u16 = 0x1124;
+const UUID_PUBLIC_BROWSE_ROOT: u16 = 0x1002;
+
+/// Class descriptor type of a report descriptor in [`HID_DESCRIPTOR_LIST`].
+const HID_REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+
+/// The HID report descriptor every Switch controller advertises.
+pub const SWITCH_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, 0x15, 0x00, 0x09, 0x04, 0xA1, 0x01, 0x85, 0x30, 0x05, 0x01, 0x05, 0x09, 0x19, 0x01,
+    0x29, 0x0A, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x0A, 0x55, 0x00, 0x65, 0x00, 0x81, 0x02,
+    0x05, 0x09, 0x19, 0x0B, 0x29, 0x0E, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x04, 0x81, 0x02,
+    0x75, 0x01, 0x95, 0x02, 0x81, 0x03, 0x0B, 0x01, 0x00, 0x01, 0x00, 0xA1, 0x00, 0x0B, 0x30, 0x00,
+    0x01, 0x00, 0x0B, 0x31, 0x00, 0x01, 0x00, 0x0B, 0x32, 0x00, 0x01, 0x00, 0x0B, 0x35, 0x00, 0x01,
+    0x00, 0x15, 0x00, 0x27, 0xFF, 0xFF, 0x00, 0x00, 0x75, 0x10, 0x95, 0x04, 0x81, 0x02, 0xC0, 0x0B,
+    0x39, 0x00, 0x01, 0x00, 0x15, 0x00, 0x25, 0x07, 0x35, 0x00, 0x46, 0x3B, 0x01, 0x65, 0x14, 0x75,
+    0x04, 0x95, 0x01, 0x81, 0x02, 0x05, 0x09, 0x19, 0x0F, 0x29, 0x12, 0x15, 0x00, 0x25, 0x01, 0x75,
+    0x01, 0x95, 0x04, 0x81, 0x02, 0x75, 0x08, 0x95, 0x34, 0x81, 0x03, 0x06, 0x00, 0xFF, 0x85, 0x21,
+    0x09, 0x01, 0x75, 0x08, 0x95, 0x3F, 0x81, 0x03, 0x85, 0x81, 0x09, 0x02, 0x75, 0x08, 0x95, 0x3F,
+    0x81, 0x03, 0x85, 0x01, 0x09, 0x03, 0x75, 0x08, 0x95, 0x3F, 0x91, 0x83, 0x85, 0x10, 0x09, 0x04,
+    0x75, 0x08, 0x95, 0x3F, 0x91, 0x83, 0x85, 0x80, 0x09, 0x05, 0x75, 0x08, 0x95, 0x3F, 0x91, 0x83,
+    0x85, 0x82, 0x09, 0x06, 0x75, 0x08, 0x95, 0x3F, 0x91, 0x83, 0xC0,
+];
+
+/// A value in an SDP record.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum DataElement {
+    Bool(bool),
+    Uint8(u8),
+    Uint16(u16),
+    Uint32(u32),
+    /// A 16 bit UUID, relative to the Bluetooth base UUID.
+    Uuid16(u16),
+    Uuid128(uuid::Uuid),
+    Text(String),
+    /// A text element holding binary data, written out as hex.
+    Bytes(Vec<u8>),
+    Sequence(Vec<DataElement>),
+}
+
+impl DataElement {
+    fn write_xml(&self, out: &mut String, depth: usize) {
+        let indent = "    ".repeat(depth);
+
+        match self {
+            DataElement::Bool(value) => writeln!(out, "{}<boolean value=\"{}\"/>", indent, value),
+            DataElement::Uint8(value) => {
+                writeln!(out, "{}<uint8 value=\"{:#04x}\"/>", indent, value)
+            }
+            DataElement::Uint16(value) => {
+                writeln!(out, "{}<uint16 value=\"{:#06x}\"/>", indent, value)
+            }
+            DataElement::Uint32(value) => {
+                writeln!(out, "{}<uint32 value=\"{:#010x}\"/>", indent, value)
+            }
+            DataElement::Uuid16(value) => {
+                writeln!(out, "{}<uuid value=\"{:#06x}\"/>", indent, value)
+            }
+            DataElement::Uuid128(value) => writeln!(out, "{}<uuid value=\"{}\"/>", indent, value),
+            DataElement::Text(value) => {
+                writeln!(out, "{}<text value=\"{}\"/>", indent, escape_xml(value))
+            }
+            DataElement::Bytes(value) => {
+                write!(out, "{}<text encoding=\"hex\" value=\"", indent).unwrap();
+
+                for byte in value {
+                    write!(out, "{:02x}", byte).unwrap();
+                }
+
+                writeln!(out, "\"/>")
+            }
+            DataElement::Sequence(elements) => {
+                writeln!(out, "{}<sequence>", indent).unwrap();
+
+                for element in elements {
+                    element.write_xml(out, depth + 1);
+                }
+
+                writeln!(out, "{}</sequence>", indent)
+            }
+        }
+        .unwrap();
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// An SDP service record, as attribute ids and their values.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SdpRecord {
+    attributes: BTreeMap<u16, DataElement>,
+}
+
+impl SdpRecord {
+    pub fn new() -> SdpRecord {
+        SdpRecord::default()
+    }
+
+    /// Sets attribute `id`, replacing any value it had.
+    pub fn with_attribute(mut self, id: u16, value: DataElement) -> SdpRecord {
+        self.set_attribute(id, value);
+        self
+    }
+
+    pub fn set_attribute(&mut self, id: u16, value: DataElement) {
+        self.attributes.insert(id, value);
+    }
+
+    pub fn remove_attribute(&mut self, id: u16) -> Option<DataElement> {
+        self.attributes.remove(&id)
+    }
+
+    pub fn attribute(&self, id: u16) -> Option<&DataElement> {
+        self.attributes.get(&id)
+    }
+
+    /// Serializes the record into BlueZ's XML format, attributes in ascending order.
+    pub fn to_xml(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n<record>\n");
+
+        for (id, value) in &self.attributes {
+            writeln!(out, "    <attribute id=\"{:#06x}\">", id).unwrap();
+            value.write_xml(&mut out, 2);
+            out.push_str("    </attribute>\n");
+        }
+
+        out.push_str("</record>\n");
+        out
+    }
+}
+
+/// The settings that go into a HID device's SDP record.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HidRecord {
+    pub service_name: String,
+    pub service_description: String,
+    pub provider_name: String,
+    /// The low byte of the class of device.
+    pub device_subclass: u8,
+    pub country_code: u8,
+    pub report_descriptor: Vec<u8>,
+    pub virtual_cable: bool,
+    pub reconnect_initiate: bool,
+    pub normally_connectable: bool,
+    pub boot_device: bool,
+    /// In baseband slots of 0.625ms.
+    pub supervision_timeout: u16,
+    pub ssr_host_max_latency: u16,
+    pub ssr_host_min_timeout: u16,
+}
+
+impl HidRecord {
+    /// The record the real `controller` advertises.
+    pub fn for_controller(controller: ControllerType) -> HidRecord {
+        // Joy-Cons and Pro Controllers all advertise the same record, descriptor included
+        match controller {
+            ControllerType::JoyConL | ControllerType::JoyConR | ControllerType::ProController => {
+                HidRecord {
+                    service_name: "Wireless Gamepad".to_string(),
+                    service_description: "Gamepad".to_string(),
+                    provider_name: "Nintendo".to_string(),
+                    device_subclass: 0x08,
+                    country_code: 0x00,
+                    report_descriptor: SWITCH_REPORT_DESCRIPTOR.to_vec(),
+                    virtual_cable: true,
+                    reconnect_initiate: true,
+                    normally_connectable: false,
+                    boot_device: true,
+                    supervision_timeout: 0x0C80,
+                    ssr_host_max_latency: 0x0640,
+                    ssr_host_min_timeout: 0x0320,
+                }
+            }
+        }
+    }
+
+    pub fn to_record(&self) -> SdpRecord {
+        use DataElement::*;
+
+        let l2cap_psm = |psm: u16| {
+            Sequence(vec![
+                Sequence(vec![Uuid16(UUID_L2CAP), Uint16(psm)]),
+                Sequence(vec![Uuid16(UUID_HIDP)]),
+            ])
+        };
+
+        SdpRecord::new()
+            .with_attribute(SERVICE_CLASS_ID_LIST, Sequence(vec![Uuid16(UUID_HID)]))
+            .with_attribute(PROTOCOL_DESCRIPTOR_LIST, l2cap_psm(0x0011))
+            .with_attribute(
+                BROWSE_GROUP_LIST,
+                Sequence(vec![Uuid16(UUID_PUBLIC_BROWSE_ROOT)]),
+            )
+            // English, UTF-8, attributes start at 0x0100
+            .with_attribute(
+                LANGUAGE_BASE_ATTRIBUTE_ID_LIST,
+                Sequence(vec![Uint16(0x656E), Uint16(0x006A), Uint16(0x0100)]),
+            )
+            // HID 1.0
+            .with_attribute(
+                BLUETOOTH_PROFILE_DESCRIPTOR_LIST,
+                Sequence(vec![Sequence(vec![Uuid16(UUID_HID), Uint16(0x0100)])]),
+            )
+            .with_attribute(
+                ADDITIONAL_PROTOCOL_DESCRIPTOR_LISTS,
+                Sequence(vec![l2cap_psm(0x0013)]),
+            )
+            .with_attribute(SERVICE_NAME, Text(self.service_name.clone()))
+            .with_attribute(SERVICE_DESCRIPTION, Text(self.service_description.clone()))
+            .with_attribute(PROVIDER_NAME, Text(self.provider_name.clone()))
+            .with_attribute(HID_DEVICE_RELEASE_NUMBER, Uint16(0x0100))
+            .with_attribute(HID_PARSER_VERSION, Uint16(0x0111))
+            .with_attribute(HID_DEVICE_SUBCLASS, Uint8(self.device_subclass))
+            .with_attribute(HID_COUNTRY_CODE, Uint8(self.country_code))
+            .with_attribute(HID_VIRTUAL_CABLE, Bool(self.virtual_cable))
+            .with_attribute(HID_RECONNECT_INITIATE, Bool(self.reconnect_initiate))
+            .with_attribute(
+                HID_DESCRIPTOR_LIST,
+                Sequence(vec![Sequence(vec![
+                    Uint8(HID_REPORT_DESCRIPTOR_TYPE),
+                    Bytes(self.report_descriptor.clone()),
+                ])]),
+            )
+            // US English
+            .with_attribute(
+                HID_LANGID_BASE_LIST,
+                Sequence(vec![Sequence(vec![Uint16(0x0409), Uint16(0x0100)])]),
+            )
+            .with_attribute(HID_PROFILE_VERSION, Uint16(0x0100))
+            .with_attribute(HID_SUPERVISION_TIMEOUT, Uint16(self.supervision_timeout))
+            .with_attribute(HID_NORMALLY_CONNECTABLE, Bool(self.normally_connectable))
+            .with_attribute(HID_BOOT_DEVICE, Bool(self.boot_device))
+            .with_attribute(HID_SSR_HOST_MAX_LATENCY, Uint16(self.ssr_host_max_latency))
+            .with_attribute(HID_SSR_HOST_MIN_TIMEOUT, Uint16(self.ssr_host_min_timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `xml` without comments or the whitespace between tags and attributes.
+    fn normalize_xml(xml: &str) -> String {
+        let mut rest = xml;
+        let mut out = String::new();
+
+        while let Some(start) = rest.find("<!--") {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + rest[start..].find("-->").unwrap() + 3..];
+        }
+
+        out.push_str(rest);
+
+        out.split('<')
+            .map(|tag| tag.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join("\n<")
+    }
+
+    #[test]
+    fn every_controller_matches_the_old_xml_record() {
+        let expected = normalize_xml(include_str!("../testdata/sdp_record_hid.xml"));
+
+        for &controller in ControllerType::ALL.iter() {
+            let xml = HidRecord::for_controller(controller).to_record().to_xml();
+            assert_eq!(normalize_xml(&xml), expected, "{}", controller);
+        }
+    }
+
+    #[test]
+    fn writes_every_element() {
+        let record = SdpRecord::new()
+            .with_attribute(0x0300, DataElement::Uint32(0x1234))
+            .with_attribute(0x0001, DataElement::Uuid128(HID_UUID.parse().unwrap()))
+            .with_attribute(
+                0x0100,
+                DataElement::Sequence(vec![
+                    DataElement::Text("<\"a\" & 'b'>".to_string()),
+                    DataElement::Bytes(vec![0x00, 0xAB]),
+                    DataElement::Sequence(vec![]),
+                ]),
+            );
+
+        assert_eq!(
+            record.to_xml(),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" ?>\n\
+             <record>\n    \
+                 <attribute id=\"0x0001\">\n        \
+                     <uuid value=\"00001124-0000-1000-8000-00805f9b34fb\"/>\n    \
+                 </attribute>\n    \
+                 <attribute id=\"0x0100\">\n        \
+                     <sequence>\n            \
+                         <text value=\"&lt;&quot;a&quot; &amp; &apos;b&apos;&gt;\"/>\n            \
+                         <text encoding=\"hex\" value=\"00ab\"/>\n            \
+                         <sequence>\n            \
+                         </sequence>\n        \
+                     </sequence>\n    \
+                 </attribute>\n    \
+                 <attribute id=\"0x0300\">\n        \
+                     <uint32 value=\"0x00001234\"/>\n    \
+                 </attribute>\n\
+             </record>\n"
+        );
+    }
+
+    #[test]
+    fn attributes_can_be_replaced_and_removed() {
+        let mut record = HidRecord {
+            supervision_timeout: 0x1F40,
+            ..HidRecord::for_controller(ControllerType::ProController)
+        }
+        .to_record();
+
+        assert_eq!(
+            record.attribute(HID_SUPERVISION_TIMEOUT),
+            Some(&DataElement::Uint16(0x1F40))
+        );
+
+        record.set_attribute(HID_BOOT_DEVICE, DataElement::Bool(false));
+        assert_eq!(
+            record.attribute(HID_BOOT_DEVICE),
+            Some(&DataElement::Bool(false))
+        );
+
+        assert_eq!(
+            record.remove_attribute(HID_SSR_HOST_MIN_TIMEOUT),
+            Some(DataElement::Uint16(0x0320))
+        );
+        assert!(!record.to_xml().contains("0x0210"));
+    }
+}
@@ -0,0 +1,343 @@
+use crate::controller::ControllerType;
+
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Every controller has 512KiB of SPI flash.
+pub const SPI_FLASH_SIZE: usize = 0x80000;
+/// Erasing works on whole 4KiB sectors.
+pub const SPI_SECTOR_SIZE: usize = 0x1000;
+
+pub const DEVICE_TYPE: u32 = 0x6012;
+pub const COLOR_INFO: u32 = 0x601B;
+pub const FACTORY_IMU_CALIBRATION: u32 = 0x6020;
+pub const FACTORY_STICK_CALIBRATION: u32 = 0x603D;
+pub const COLORS: u32 = 0x6050;
+pub const IMU_HORIZONTAL_OFFSETS: u32 = 0x6080;
+pub const STICK_PARAMETERS: u32 = 0x6086;
+
+/// 12-bit stick values every generated calibration is centered on.
+const STICK_CENTER: u16 = 0x800;
+/// How far the generated calibration says sticks travel from the center.
+const STICK_RANGE: u16 = 0x600;
+
+/// Deadzone and range ratio parameters as found on real Pro Controllers.
+const DEFAULT_STICK_PARAMETERS: [u8; 18] = [
+    0x0F, 0x30, 0x61, 0x96, 0x30, 0xF3, 0xD4, 0x14, 0x54, 0x41, 0x15, 0x54, 0xC7, 0x79, 0x9C, 0x33,
+    0x36, 0x63,
+];
+
+/// An in-memory copy of a controller's SPI flash, optionally backed by a dump file.
+pub struct SpiFlash {
+    data: Vec<u8>,
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+impl SpiFlash {
+    /// Generates the flash of a fresh controller: factory calibration, colors and no user
+    /// calibration.
+    pub fn new(controller: ControllerType) -> SpiFlash {
+        let mut flash = SpiFlash {
+            data: vec![0xFF; SPI_FLASH_SIZE],
+            path: None,
+            dirty: false,
+        };
+
+        flash.fill(DEVICE_TYPE, &[controller.id()]);
+        // Colors are set, grip colors aren't
+        flash.fill(COLOR_INFO, &[0x01]);
+
+        // Accel origin and sensitivity, then gyro origin and sensitivity
+        let mut imu_calibration = Vec::with_capacity(24);
+        for &value in &[
+            0i16, 0, 0, 0x4000, 0x4000, 0x4000, 0, 0, 0, 0x343B, 0x343B, 0x343B,
+        ] {
+            imu_calibration.extend_from_slice(&value.to_le_bytes());
+        }
+        flash.fill(FACTORY_IMU_CALIBRATION, &imu_calibration);
+
+        // The left stick stores max above center, center, min below center. The right stick
+        // stores center, min below center, max above center.
+        let center = pack_stick_pair(STICK_CENTER, STICK_CENTER);
+        let range = pack_stick_pair(STICK_RANGE, STICK_RANGE);
+        let mut stick_calibration = Vec::with_capacity(18);
+        for part in &[range, center, range, center, range, range] {
+            stick_calibration.extend_from_slice(part);
+        }
+        flash.fill(FACTORY_STICK_CALIBRATION, &stick_calibration);
+
+        // Body, buttons, left grip, right grip
+        flash.fill(
+            COLORS,
+            &[
+                0x32, 0x32, 0x32, 0xFF, 0xFF, 0xFF, 0x32, 0x32, 0x32, 0x32, 0x32, 0x32,
+            ],
+        );
+
+        flash.fill(
+            IMU_HORIZONTAL_OFFSETS,
+            &[0x50, 0xFD, 0x00, 0x00, 0xC6, 0x0F],
+        );
+        flash.fill(STICK_PARAMETERS, &DEFAULT_STICK_PARAMETERS);
+        flash.fill(
+            STICK_PARAMETERS + DEFAULT_STICK_PARAMETERS.len() as u32,
+            &DEFAULT_STICK_PARAMETERS,
+        );
+
+        flash
+    }
+
+    /// Wraps a raw dump. It has to be exactly [`SPI_FLASH_SIZE`] bytes long.
+    pub fn from_bytes(data: Vec<u8>) -> Result<SpiFlash> {
+        if data.len() != SPI_FLASH_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "SPI flash dump is {} bytes, expected {}",
+                    data.len(),
+                    SPI_FLASH_SIZE
+                ),
+            ));
+        }
+
+        Ok(SpiFlash {
+            data,
+            path: None,
+            dirty: false,
+        })
+    }
+
+    /// Loads a raw dump taken from a real controller. Writes made by the Switch are saved back
+    /// to the same file on [`SpiFlash::flush`].
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SpiFlash> {
+        let mut flash = SpiFlash::from_bytes(fs::read(path.as_ref())?)?;
+        flash.path = Some(path.as_ref().to_path_buf());
+
+        Ok(flash)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, &self.data)
+    }
+
+    /// Saves to the file we were loaded from, if anything changed since the last flush.
+    pub fn flush(&mut self) -> Result<()> {
+        if let (true, Some(path)) = (self.dirty, &self.path) {
+            self.save(path)?;
+            self.dirty = false;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `None` if the range runs past the end of flash.
+    pub fn read(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let start = address as usize;
+        self.data.get(start..start.checked_add(len)?)
+    }
+
+    /// Returns false, changing nothing, if the range runs past the end of flash.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> bool {
+        let start = address as usize;
+
+        match start
+            .checked_add(data.len())
+            .and_then(|end| self.data.get_mut(start..end))
+        {
+            Some(dest) => {
+                dest.copy_from_slice(data);
+                self.dirty = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Resets the sector containing `address` to 0xFF.
+    pub fn erase_sector(&mut self, address: u32) -> bool {
+        let start = address as usize / SPI_SECTOR_SIZE * SPI_SECTOR_SIZE;
+
+        if start >= SPI_FLASH_SIZE {
+            return false;
+        }
+
+        for byte in &mut self.data[start..start + SPI_SECTOR_SIZE] {
+            *byte = 0xFF;
+        }
+        self.dirty = true;
+
+        true
+    }
+
+    fn fill(&mut self, address: u32, data: &[u8]) {
+        let start = address as usize;
+        self.data[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+/// Packs two 12-bit values into 3 bytes, the way calibration data and stick positions are stored.
+fn pack_stick_pair(x: u16, y: u16) -> [u8; 3] {
+    [
+        (x & 0xFF) as u8,
+        ((x >> 8) & 0x0F) as u8 | ((y & 0x0F) << 4) as u8,
+        (y >> 4) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file in the temp directory, removed again when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let file_name = format!("joycontrolrs-{}-{}.bin", std::process::id(), name);
+            TempFile(std::env::temp_dir().join(file_name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn dump() -> Vec<u8> {
+        (0..SPI_FLASH_SIZE).map(|i| (i * 7) as u8).collect()
+    }
+
+    #[test]
+    fn generates_a_fresh_controller() {
+        for &controller in ControllerType::ALL.iter() {
+            let flash = SpiFlash::new(controller);
+
+            assert_eq!(flash.read(DEVICE_TYPE, 1), Some(&[controller.id()][..]));
+            assert_eq!(flash.read(COLOR_INFO, 1), Some(&[0x01][..]));
+            assert_eq!(
+                flash.read(COLORS, 6),
+                Some(&[0x32, 0x32, 0x32, 0xFF, 0xFF, 0xFF][..])
+            );
+
+            // Accel sensitivity follows the accel origin
+            assert_eq!(
+                flash.read(FACTORY_IMU_CALIBRATION + 6, 6),
+                Some(&[0x00, 0x40, 0x00, 0x40, 0x00, 0x40][..])
+            );
+
+            // Both sticks are centered, though they store the center in different places
+            let center = pack_stick_pair(STICK_CENTER, STICK_CENTER);
+            let stick_calibration = flash.read(FACTORY_STICK_CALIBRATION, 18).unwrap();
+            assert_eq!(stick_calibration[3..6], center);
+            assert_eq!(stick_calibration[9..12], center);
+
+            assert_eq!(
+                flash.read(STICK_PARAMETERS, 18),
+                Some(&DEFAULT_STICK_PARAMETERS[..])
+            );
+
+            // No user calibration
+            assert!(flash
+                .read(0x8000, 0x1000)
+                .unwrap()
+                .iter()
+                .all(|&b| b == 0xFF));
+        }
+    }
+
+    #[test]
+    fn packs_stick_pairs() {
+        assert_eq!(pack_stick_pair(0x123, 0xABC), [0x23, 0xC1, 0xAB]);
+        assert_eq!(pack_stick_pair(0x800, 0x800), [0x00, 0x08, 0x80]);
+        assert_eq!(pack_stick_pair(0xFFF, 0x000), [0xFF, 0x0F, 0x00]);
+    }
+
+    #[test]
+    fn rejects_dumps_of_the_wrong_size() {
+        for &len in &[0, SPI_FLASH_SIZE - 1, SPI_FLASH_SIZE + 1] {
+            let error = SpiFlash::from_bytes(vec![0; len]).err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+
+        let file = TempFile::new("short");
+        fs::write(&file.0, [0xFF; 0x1000]).unwrap();
+        assert_eq!(
+            SpiFlash::load(&file.0).err().unwrap().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let file = TempFile::new("round-trip");
+
+        let flash = SpiFlash::from_bytes(dump()).unwrap();
+        flash.save(&file.0).unwrap();
+
+        let loaded = SpiFlash::load(&file.0).unwrap();
+        assert_eq!(loaded.read(0, SPI_FLASH_SIZE), Some(&dump()[..]));
+    }
+
+    #[test]
+    fn flushes_only_changes() {
+        let file = TempFile::new("flush");
+        fs::write(&file.0, dump()).unwrap();
+
+        let mut flash = SpiFlash::load(&file.0).unwrap();
+
+        // Nothing changed, so the file isn't touched
+        fs::remove_file(&file.0).unwrap();
+        flash.flush().unwrap();
+        assert!(!file.0.exists());
+
+        assert!(flash.write(0x8010, &[0xB2, 0xA1]));
+        flash.flush().unwrap();
+
+        let mut expected = dump();
+        expected[0x8010..0x8012].copy_from_slice(&[0xB2, 0xA1]);
+        assert_eq!(fs::read(&file.0).unwrap(), expected);
+
+        // A generated flash has nowhere to go
+        let mut generated = SpiFlash::new(ControllerType::ProController);
+        assert!(generated.write(0x8010, &[0xB2, 0xA1]));
+        generated.flush().unwrap();
+    }
+
+    #[test]
+    fn reads_and_writes_stay_in_bounds() {
+        let mut flash = SpiFlash::from_bytes(dump()).unwrap();
+        let end = SPI_FLASH_SIZE as u32;
+
+        assert_eq!(flash.read(end - 2, 2), Some(&dump()[SPI_FLASH_SIZE - 2..]));
+        assert_eq!(flash.read(end - 2, 3), None);
+        assert_eq!(flash.read(u32::MAX, 1), None);
+
+        assert!(flash.write(end - 2, &[0x01, 0x02]));
+        assert_eq!(flash.read(end - 2, 2), Some(&[0x01, 0x02][..]));
+
+        assert!(!flash.write(end - 2, &[0x03, 0x04, 0x05]));
+        assert!(!flash.write(u32::MAX, &[0x03]));
+        assert_eq!(flash.read(end - 2, 2), Some(&[0x01, 0x02][..]));
+    }
+
+    #[test]
+    fn erases_whole_sectors() {
+        let mut flash = SpiFlash::from_bytes(dump()).unwrap();
+
+        assert!(flash.erase_sector(0x8010));
+
+        assert!(flash
+            .read(0x8000, SPI_SECTOR_SIZE)
+            .unwrap()
+            .iter()
+            .all(|&b| b == 0xFF));
+        assert_eq!(flash.read(0x7FFF, 1), Some(&dump()[0x7FFF..0x8000]));
+        assert_eq!(flash.read(0x9000, 1), Some(&dump()[0x9000..0x9001]));
+
+        assert!(flash.erase_sector(SPI_FLASH_SIZE as u32 - 1));
+        assert!(!flash.erase_sector(SPI_FLASH_SIZE as u32));
+    }
+}
@@ -0,0 +1,396 @@
+use crate::controller::ControllerType;
+use crate::spi_flash::SpiFlash;
+use crate::BtAddr;
+
+/// Largest amount of reply data that fits in a 0x21 input report.
+pub const REPLY_DATA_LEN: usize = 34;
+
+/// Largest SPI flash read or write a single subcommand can carry.
+pub const SPI_MAX_TRANSFER: u8 = 0x1D;
+
+/// Subcommands the Switch sends in output report 0x01.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Subcommand {
+    GetControllerState,
+    BluetoothManualPairing(Vec<u8>),
+    RequestDeviceInfo,
+    SetInputReportMode(u8),
+    TriggerButtonsElapsedTime,
+    SetHciState(u8),
+    SetShipmentState(bool),
+    SpiFlashRead { address: u32, length: u8 },
+    SpiFlashWrite { address: u32, data: Vec<u8> },
+    SpiSectorErase { address: u32 },
+    SetNfcIrMcuConfig(Vec<u8>),
+    SetNfcIrMcuState(u8),
+    SetPlayerLights(u8),
+    SetHomeLight(Vec<u8>),
+    EnableImu(bool),
+    SetImuSensitivity(Vec<u8>),
+    EnableVibration(bool),
+    Unknown { id: u8, args: Vec<u8> },
+}
+
+impl Subcommand {
+    /// Parses a subcommand from its id and the bytes that follow it. Output reports are padded,
+    /// so `args` is usually longer than the subcommand needs.
+    pub fn parse(id: u8, args: &[u8]) -> Subcommand {
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0);
+        let address = || u32::from_le_bytes([arg(0), arg(1), arg(2), arg(3)]);
+
+        match id {
+            0x00 => Subcommand::GetControllerState,
+            0x01 => Subcommand::BluetoothManualPairing(args.to_vec()),
+            0x02 => Subcommand::RequestDeviceInfo,
+            0x03 => Subcommand::SetInputReportMode(arg(0)),
+            0x04 => Subcommand::TriggerButtonsElapsedTime,
+            0x06 => Subcommand::SetHciState(arg(0)),
+            0x08 => Subcommand::SetShipmentState(arg(0) != 0),
+            0x10 => Subcommand::SpiFlashRead {
+                address: address(),
+                length: arg(4),
+            },
+            0x11 => {
+                let data = args.get(5..).unwrap_or(&[]);
+                let len = (arg(4) as usize).min(data.len());
+
+                Subcommand::SpiFlashWrite {
+                    address: address(),
+                    data: data[..len].to_vec(),
+                }
+            }
+            0x12 => Subcommand::SpiSectorErase { address: address() },
+            0x21 => Subcommand::SetNfcIrMcuConfig(args.to_vec()),
+            0x22 => Subcommand::SetNfcIrMcuState(arg(0)),
+            0x30 => Subcommand::SetPlayerLights(arg(0)),
+            0x38 => Subcommand::SetHomeLight(args.to_vec()),
+            0x40 => Subcommand::EnableImu(arg(0) != 0),
+            0x41 => Subcommand::SetImuSensitivity(args.get(..4).unwrap_or(args).to_vec()),
+            0x48 => Subcommand::EnableVibration(arg(0) != 0),
+            _ => Subcommand::Unknown {
+                id,
+                args: args.to_vec(),
+            },
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        match self {
+            Subcommand::GetControllerState => 0x00,
+            Subcommand::BluetoothManualPairing(_) => 0x01,
+            Subcommand::RequestDeviceInfo => 0x02,
+            Subcommand::SetInputReportMode(_) => 0x03,
+            Subcommand::TriggerButtonsElapsedTime => 0x04,
+            Subcommand::SetHciState(_) => 0x06,
+            Subcommand::SetShipmentState(_) => 0x08,
+            Subcommand::SpiFlashRead { .. } => 0x10,
+            Subcommand::SpiFlashWrite { .. } => 0x11,
+            Subcommand::SpiSectorErase { .. } => 0x12,
+            Subcommand::SetNfcIrMcuConfig(_) => 0x21,
+            Subcommand::SetNfcIrMcuState(_) => 0x22,
+            Subcommand::SetPlayerLights(_) => 0x30,
+            Subcommand::SetHomeLight(_) => 0x38,
+            Subcommand::EnableImu(_) => 0x40,
+            Subcommand::SetImuSensitivity(_) => 0x41,
+            Subcommand::EnableVibration(_) => 0x48,
+            Subcommand::Unknown { id, .. } => *id,
+        }
+    }
+
+    /// The variant's name, without its arguments. `Subcommand::parse(id, &[]).name()` names any
+    /// subcommand id.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subcommand::GetControllerState => "GetControllerState",
+            Subcommand::BluetoothManualPairing(_) => "BluetoothManualPairing",
+            Subcommand::RequestDeviceInfo => "RequestDeviceInfo",
+            Subcommand::SetInputReportMode(_) => "SetInputReportMode",
+            Subcommand::TriggerButtonsElapsedTime => "TriggerButtonsElapsedTime",
+            Subcommand::SetHciState(_) => "SetHciState",
+            Subcommand::SetShipmentState(_) => "SetShipmentState",
+            Subcommand::SpiFlashRead { .. } => "SpiFlashRead",
+            Subcommand::SpiFlashWrite { .. } => "SpiFlashWrite",
+            Subcommand::SpiSectorErase { .. } => "SpiSectorErase",
+            Subcommand::SetNfcIrMcuConfig(_) => "SetNfcIrMcuConfig",
+            Subcommand::SetNfcIrMcuState(_) => "SetNfcIrMcuState",
+            Subcommand::SetPlayerLights(_) => "SetPlayerLights",
+            Subcommand::SetHomeLight(_) => "SetHomeLight",
+            Subcommand::EnableImu(_) => "EnableImu",
+            Subcommand::SetImuSensitivity(_) => "SetImuSensitivity",
+            Subcommand::EnableVibration(_) => "EnableVibration",
+            Subcommand::Unknown { .. } => "Unknown",
+        }
+    }
+
+    /// The inverse of [`Subcommand::parse`], without any padding.
+    pub fn encode_args(&self) -> Vec<u8> {
+        match self {
+            Subcommand::GetControllerState
+            | Subcommand::RequestDeviceInfo
+            | Subcommand::TriggerButtonsElapsedTime => vec![],
+
+            Subcommand::SetInputReportMode(value)
+            | Subcommand::SetHciState(value)
+            | Subcommand::SetNfcIrMcuState(value)
+            | Subcommand::SetPlayerLights(value) => vec![*value],
+
+            Subcommand::SetShipmentState(enabled)
+            | Subcommand::EnableImu(enabled)
+            | Subcommand::EnableVibration(enabled) => vec![*enabled as u8],
+
+            Subcommand::SpiFlashRead { address, length } => {
+                let mut args = address.to_le_bytes().to_vec();
+                args.push(*length);
+                args
+            }
+
+            Subcommand::SpiFlashWrite { address, data } => {
+                let mut args = address.to_le_bytes().to_vec();
+                args.push(data.len() as u8);
+                args.extend_from_slice(data);
+                args
+            }
+
+            Subcommand::SpiSectorErase { address } => address.to_le_bytes().to_vec(),
+
+            Subcommand::BluetoothManualPairing(args)
+            | Subcommand::SetNfcIrMcuConfig(args)
+            | Subcommand::SetHomeLight(args)
+            | Subcommand::SetImuSensitivity(args)
+            | Subcommand::Unknown { args, .. } => args.clone(),
+        }
+    }
+}
+
+/// The subcommand specific part of a 0x21 input report.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct SubcommandReply {
+    /// 0x00 is a NACK. Otherwise the high bit is set and the rest says what kind of data follows.
+    pub ack: u8,
+    pub id: u8,
+    pub data: Vec<u8>,
+}
+
+impl SubcommandReply {
+    /// A plain ACK with no data.
+    pub fn ack(id: u8) -> SubcommandReply {
+        SubcommandReply::with_data(0x80, id, vec![])
+    }
+
+    pub fn nack(id: u8) -> SubcommandReply {
+        SubcommandReply::with_data(0x00, id, vec![])
+    }
+
+    pub fn with_data(ack: u8, id: u8, data: Vec<u8>) -> SubcommandReply {
+        debug_assert!(data.len() <= REPLY_DATA_LEN);
+
+        SubcommandReply { ack, id, data }
+    }
+
+    /// What a `controller` at `address` answers [`Subcommand::RequestDeviceInfo`] with.
+    pub fn device_info(controller: ControllerType, address: BtAddr) -> SubcommandReply {
+        // Firmware version, controller type, then the address
+        let mut data = vec![0x03, 0x8B, controller.id(), 0x02];
+        data.extend_from_slice(&address.0);
+        // Use the colors in SPI flash
+        data.extend_from_slice(&[0x01, 0x01]);
+
+        SubcommandReply::with_data(0x82, 0x02, data)
+    }
+
+    /// What a controller with `spi_flash` answers [`Subcommand::SpiFlashRead`] with. Reads past
+    /// the end are refused, and reads longer than [`SPI_MAX_TRANSFER`] cut short.
+    pub fn spi_flash_read(spi_flash: &SpiFlash, address: u32, length: u8) -> SubcommandReply {
+        let length = length.min(SPI_MAX_TRANSFER);
+
+        match spi_flash.read(address, length as usize) {
+            Some(bytes) => {
+                let mut data = address.to_le_bytes().to_vec();
+                data.push(length);
+                data.extend_from_slice(bytes);
+
+                SubcommandReply::with_data(0x90, 0x10, data)
+            }
+            None => SubcommandReply::nack(0x10),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spi_flash::SPI_FLASH_SIZE;
+
+    /// Subcommands that always take the same number of argument bytes.
+    fn fixed_size_subcommands() -> Vec<Subcommand> {
+        vec![
+            Subcommand::GetControllerState,
+            Subcommand::RequestDeviceInfo,
+            Subcommand::SetInputReportMode(0x30),
+            Subcommand::TriggerButtonsElapsedTime,
+            Subcommand::SetHciState(0x01),
+            Subcommand::SetShipmentState(true),
+            Subcommand::SetShipmentState(false),
+            Subcommand::SpiFlashRead {
+                address: 0x6020,
+                length: 0x18,
+            },
+            Subcommand::SpiFlashWrite {
+                address: 0x8010,
+                data: vec![0xB2, 0xA1, 0x00],
+            },
+            Subcommand::SpiSectorErase { address: 0x7F000 },
+            Subcommand::SetNfcIrMcuState(0x01),
+            Subcommand::SetPlayerLights(0b1001),
+            Subcommand::EnableImu(true),
+            Subcommand::EnableVibration(true),
+            Subcommand::EnableVibration(false),
+        ]
+    }
+
+    #[test]
+    fn subcommands_round_trip() {
+        for subcommand in fixed_size_subcommands() {
+            let args = subcommand.encode_args();
+            assert_eq!(Subcommand::parse(subcommand.id(), &args), subcommand);
+
+            // Output reports pad whatever comes after the arguments
+            let mut padded = args.clone();
+            padded.resize(38, 0);
+            assert_eq!(Subcommand::parse(subcommand.id(), &padded), subcommand);
+        }
+
+        for subcommand in [
+            Subcommand::BluetoothManualPairing(vec![0x04, 0x3C]),
+            Subcommand::SetNfcIrMcuConfig(vec![0x21, 0x00, 0x01]),
+            Subcommand::SetHomeLight(vec![0x0F, 0xF0]),
+            Subcommand::SetImuSensitivity(vec![0x03, 0x00, 0x00, 0x01]),
+            Subcommand::Unknown {
+                id: 0x99,
+                args: vec![0x01, 0x02],
+            },
+        ] {
+            let args = subcommand.encode_args();
+            assert_eq!(Subcommand::parse(subcommand.id(), &args), subcommand);
+        }
+    }
+
+    #[test]
+    fn parses_arguments() {
+        assert_eq!(
+            Subcommand::parse(0x10, &[0x20, 0x60, 0x00, 0x00, 0x18, 0xAA]),
+            Subcommand::SpiFlashRead {
+                address: 0x6020,
+                length: 0x18,
+            }
+        );
+        assert_eq!(
+            Subcommand::parse(0x12, &[0x00, 0xF0, 0x07, 0x00]),
+            Subcommand::SpiSectorErase { address: 0x7F000 }
+        );
+        assert_eq!(
+            Subcommand::parse(0x08, &[0x02]),
+            Subcommand::SetShipmentState(true)
+        );
+        assert_eq!(
+            Subcommand::parse(0x41, &[0x03, 0x00, 0x00, 0x01, 0xFF]),
+            Subcommand::SetImuSensitivity(vec![0x03, 0x00, 0x00, 0x01])
+        );
+
+        // Missing arguments are taken as 0
+        assert_eq!(
+            Subcommand::parse(0x03, &[]),
+            Subcommand::SetInputReportMode(0)
+        );
+        assert_eq!(
+            Subcommand::parse(0x10, &[0x20]),
+            Subcommand::SpiFlashRead {
+                address: 0x20,
+                length: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn spi_flash_write_is_clamped_to_the_data_given() {
+        let write = |args: &[u8]| match Subcommand::parse(0x11, args) {
+            Subcommand::SpiFlashWrite { address, data } => (address, data),
+            other => panic!("expected SpiFlashWrite, got {:?}", other),
+        };
+
+        assert_eq!(
+            write(&[0x10, 0x80, 0x00, 0x00, 0x02, 0xAA, 0xBB, 0xCC]),
+            (0x8010, vec![0xAA, 0xBB])
+        );
+        assert_eq!(
+            write(&[0x10, 0x80, 0x00, 0x00, 0x10, 0xAA, 0xBB]),
+            (0x8010, vec![0xAA, 0xBB])
+        );
+        assert_eq!(write(&[0x10, 0x80, 0x00, 0x00, 0x10]), (0x8010, vec![]));
+        assert_eq!(write(&[]), (0, vec![]));
+    }
+
+    #[test]
+    fn names_every_id() {
+        assert_eq!(Subcommand::parse(0x48, &[]).name(), "EnableVibration");
+        assert_eq!(Subcommand::parse(0x10, &[]).name(), "SpiFlashRead");
+        assert_eq!(Subcommand::parse(0x99, &[]).name(), "Unknown");
+
+        for subcommand in fixed_size_subcommands() {
+            assert_eq!(
+                format!("{:?}", subcommand).split(['(', ' ']).next(),
+                Some(subcommand.name())
+            );
+        }
+    }
+
+    #[test]
+    fn device_info_reply() {
+        let address = BtAddr([0x98, 0xB6, 0xE9, 0x0A, 0x0B, 0x0C]);
+
+        assert_eq!(
+            SubcommandReply::device_info(ControllerType::JoyConL, address),
+            SubcommandReply::with_data(
+                0x82,
+                0x02,
+                vec![0x03, 0x8B, 0x01, 0x02, 0x98, 0xB6, 0xE9, 0x0A, 0x0B, 0x0C, 0x01, 0x01]
+            )
+        );
+    }
+
+    #[test]
+    fn spi_flash_read_reply() {
+        let flash = SpiFlash::new(ControllerType::ProController);
+
+        let reply = SubcommandReply::spi_flash_read(&flash, 0x6050, 3);
+        assert_eq!(
+            reply,
+            SubcommandReply::with_data(
+                0x90,
+                0x10,
+                vec![0x50, 0x60, 0x00, 0x00, 0x03, 0x32, 0x32, 0x32]
+            )
+        );
+
+        // Cut short to what fits in a reply
+        let reply = SubcommandReply::spi_flash_read(&flash, 0x6000, 0xFF);
+        assert_eq!(reply.data[4], SPI_MAX_TRANSFER);
+        assert_eq!(reply.data.len(), 5 + SPI_MAX_TRANSFER as usize);
+        assert!(reply.data.len() <= REPLY_DATA_LEN);
+
+        // Right up to the end is fine, past it isn't
+        let end = SPI_FLASH_SIZE as u32;
+        assert_eq!(
+            SubcommandReply::spi_flash_read(&flash, end - 4, 4).ack,
+            0x90
+        );
+        assert_eq!(
+            SubcommandReply::spi_flash_read(&flash, end - 4, 8),
+            SubcommandReply::nack(0x10)
+        );
+        assert_eq!(
+            SubcommandReply::spi_flash_read(&flash, u32::MAX, 1),
+            SubcommandReply::nack(0x10)
+        );
+    }
+}
@@ -0,0 +1,279 @@
+//! What the Switch and controllers are reached over.
+//!
+//! Everything that speaks HID relies on the semantics of an L2CAP seqpacket socket, and nothing
+//! more, so it runs just the same over an AF_UNIX seqpacket socketpair or an in-process channel.
+//! Neither of those needs a radio.
+
+use crate::l2cap::AsyncL2CAPStream;
+use crate::smol_fd::libc_check_error;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use futures::stream::FusedStream;
+use smol::Async;
+
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixStream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A reliable packet channel, like an L2CAP seqpacket socket. Every write sends one whole packet
+/// and every read returns one whole packet, cut short if the buffer is too small for it. Reading
+/// 0 bytes means the other end closed.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin {}
+
+impl Transport for AsyncL2CAPStream {}
+impl Transport for SeqPacket {}
+impl Transport for MemoryChannel {}
+
+/// Both channels of a HID connection, as seen from one end.
+#[derive(Debug)]
+pub struct HidChannels<T> {
+    pub ctl: T,
+    pub itr: T,
+}
+
+impl<T: Transport> HidChannels<T> {
+    /// Makes both ends of a connection out of two pairs from `pair`, like [`SeqPacket::pair`].
+    pub fn pair<F>(pair: F) -> Result<(HidChannels<T>, HidChannels<T>)>
+    where
+        F: Fn() -> Result<(T, T)>,
+    {
+        let (ctl_a, ctl_b) = pair()?;
+        let (itr_a, itr_b) = pair()?;
+
+        Ok((
+            HidChannels {
+                ctl: ctl_a,
+                itr: itr_a,
+            },
+            HidChannels {
+                ctl: ctl_b,
+                itr: itr_b,
+            },
+        ))
+    }
+}
+
+/// One end of an AF_UNIX seqpacket socketpair, which keeps packets apart the same way L2CAP
+/// does.
+#[derive(Debug)]
+pub struct SeqPacket {
+    // Only used to own the descriptor and read and write it, which works on any socket
+    io: Async<UnixStream>,
+}
+
+impl SeqPacket {
+    pub fn pair() -> Result<(SeqPacket, SeqPacket)> {
+        let mut fds = [0; 2];
+
+        libc_check_error(unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        })?;
+
+        let (a, b) = unsafe {
+            (
+                UnixStream::from_raw_fd(fds[0]),
+                UnixStream::from_raw_fd(fds[1]),
+            )
+        };
+
+        Ok((
+            SeqPacket { io: Async::new(a)? },
+            SeqPacket { io: Async::new(b)? },
+        ))
+    }
+}
+
+impl AsyncRead for SeqPacket {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SeqPacket {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.io).poll_close(cx)
+    }
+}
+
+/// One end of an in-process channel, for when both sides live in the same program.
+#[derive(Debug)]
+pub struct MemoryChannel {
+    incoming: UnboundedReceiver<Vec<u8>>,
+    outgoing: UnboundedSender<Vec<u8>>,
+}
+
+impl MemoryChannel {
+    /// Never fails, but returns a `Result` like [`SeqPacket::pair`] so either can be given to
+    /// [`HidChannels::pair`].
+    pub fn pair() -> Result<(MemoryChannel, MemoryChannel)> {
+        let (a_outgoing, b_incoming) = mpsc::unbounded();
+        let (b_outgoing, a_incoming) = mpsc::unbounded();
+
+        Ok((
+            MemoryChannel {
+                incoming: a_incoming,
+                outgoing: a_outgoing,
+            },
+            MemoryChannel {
+                incoming: b_incoming,
+                outgoing: b_outgoing,
+            },
+        ))
+    }
+}
+
+impl AsyncRead for MemoryChannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        // Polling the receiver again once it's ended panics, but reads should keep returning 0
+        if self.incoming.is_terminated() {
+            return Poll::Ready(Ok(0));
+        }
+
+        match self.incoming.poll_next_unpin(cx) {
+            Poll::Ready(Some(packet)) => {
+                // Like seqpacket, whatever doesn't fit is lost
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+
+                Poll::Ready(Ok(len))
+            }
+            Poll::Ready(None) => Poll::Ready(Ok(0)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for MemoryChannel {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        match self.outgoing.unbounded_send(buf.to_vec()) {
+            Ok(()) => Poll::Ready(Ok(buf.len())),
+            Err(_) => Poll::Ready(Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "the other end is gone",
+            ))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.outgoing.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks `pair` keeps to the semantics [`Transport`] promises.
+    fn check_transport<T, F>(pair: F)
+    where
+        T: Transport,
+        F: Fn() -> Result<(T, T)>,
+    {
+        smol::run(async {
+            let (mut a, mut b) = pair().unwrap();
+            let mut buf = [0u8; 64];
+
+            // Every write is read back on its own, even when they queue up
+            a.write_all(&[1, 2, 3]).await.unwrap();
+            a.write_all(&[4, 5]).await.unwrap();
+            assert_eq!(b.read(&mut buf).await.unwrap(), 3);
+            assert_eq!(&buf[..3], &[1, 2, 3]);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 2);
+            assert_eq!(&buf[..2], &[4, 5]);
+
+            // Both ways
+            b.write_all(&[6]).await.unwrap();
+            assert_eq!(a.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(buf[0], 6);
+
+            // A packet that doesn't fit is cut short, and the rest of it is lost
+            a.write_all(&[7, 8, 9, 10]).await.unwrap();
+            a.write_all(&[11]).await.unwrap();
+            assert_eq!(b.read(&mut buf[..2]).await.unwrap(), 2);
+            assert_eq!(&buf[..2], &[7, 8]);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(buf[0], 11);
+
+            // Whatever was sent before closing still arrives, then reads come back empty
+            a.write_all(&[12]).await.unwrap();
+            drop(a);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+        })
+    }
+
+    #[test]
+    fn seqpacket_keeps_packets_apart() {
+        check_transport(SeqPacket::pair);
+    }
+
+    #[test]
+    fn memory_channel_keeps_packets_apart() {
+        check_transport(MemoryChannel::pair);
+    }
+
+    #[test]
+    fn memory_channel_close_ends_the_other_side() {
+        smol::run(async {
+            let (mut a, mut b) = MemoryChannel::pair().unwrap();
+            let mut buf = [0u8; 8];
+
+            a.close().await.unwrap();
+            assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+            assert_eq!(
+                a.write(&[1]).await.unwrap_err().kind(),
+                ErrorKind::BrokenPipe
+            );
+        })
+    }
+
+    #[test]
+    fn hid_channels_pair_up() {
+        smol::run(async {
+            let (mut switch, mut controller) = HidChannels::pair(MemoryChannel::pair).unwrap();
+            let mut buf = [0u8; 8];
+
+            switch.ctl.write_all(&[1]).await.unwrap();
+            switch.itr.write_all(&[2]).await.unwrap();
+
+            assert_eq!(controller.itr.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(buf[0], 2);
+            assert_eq!(controller.ctl.read(&mut buf).await.unwrap(), 1);
+            assert_eq!(buf[0], 1);
+        })
+    }
+}
@@ -0,0 +1,255 @@
+//! A scripted stand-in for a real controller, for putting the relay through its paces without
+//! one.
+//!
+//! Unlike the emulator, which tries its best to please the Switch, this one does exactly what
+//! it's told: canned replies, a fixed report rate, and misbehaving or hanging up on demand.
+
+use crate::controller::ControllerType;
+use crate::input_report::{
+    Buttons, ImuFrame, InputReport, ReportBody, StandardInputReport, StickData,
+};
+use crate::output_report::OutputReport;
+use crate::spi_flash::SpiFlash;
+use crate::subcommand::{Subcommand, SubcommandReply};
+use crate::transport::{HidChannels, Transport};
+use crate::BtAddr;
+
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::prelude::*;
+use smol::Timer;
+
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::time::Duration;
+
+/// How often full reports are streamed once the Switch asks for them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReportRate {
+    Hz60,
+    Hz120,
+}
+
+impl ReportRate {
+    pub fn period(self) -> Duration {
+        match self {
+            ReportRate::Hz60 => Duration::from_micros(16_666),
+            ReportRate::Hz120 => Duration::from_micros(8_333),
+        }
+    }
+}
+
+/// Ways the controller can be told to misbehave.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Misbehavior {
+    /// Subcommands go unanswered.
+    IgnoreSubcommands,
+    /// Every subcommand is refused.
+    NackSubcommands,
+    /// Every packet is cut down to this many bytes, HIDP header included.
+    Truncate(usize),
+    /// No more full reports, though subcommands are still answered.
+    StopReports,
+}
+
+/// What the controller can be told while it's running.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ControllerCommand {
+    /// Report these buttons as held from now on.
+    SetButtons(Buttons),
+    Misbehave(Misbehavior),
+    /// Back to normal after misbehaving.
+    Behave,
+    /// Hang up on both channels.
+    Disconnect,
+}
+
+/// Whatever woke the controller up.
+enum Event {
+    Read(usize),
+    Command(ControllerCommand),
+    Report,
+}
+
+/// Tells a running [`VirtualController`] what to do. Commands sent after it's stopped are
+/// dropped.
+#[derive(Debug, Clone)]
+pub struct ControllerHandle {
+    commands: UnboundedSender<ControllerCommand>,
+}
+
+impl ControllerHandle {
+    pub fn send(&self, command: ControllerCommand) {
+        let _ = self.commands.unbounded_send(command);
+    }
+}
+
+/// Plays a controller's end of a connection, answering subcommands from canned replies and
+/// streaming full reports once asked to.
+pub struct VirtualController {
+    controller: ControllerType,
+    address: BtAddr,
+    spi_flash: SpiFlash,
+    replies: BTreeMap<u8, SubcommandReply>,
+    rate: ReportRate,
+    streaming: bool,
+    timer: u8,
+    buttons: Buttons,
+    misbehavior: Option<Misbehavior>,
+    commands: UnboundedReceiver<ControllerCommand>,
+    handle: ControllerHandle,
+}
+
+impl VirtualController {
+    /// A controller at `address` with a freshly generated SPI flash, streaming at 60Hz.
+    pub fn new(controller: ControllerType, address: BtAddr) -> VirtualController {
+        let (sender, commands) = mpsc::unbounded();
+
+        VirtualController {
+            controller,
+            address,
+            spi_flash: SpiFlash::new(controller),
+            replies: BTreeMap::new(),
+            rate: ReportRate::Hz60,
+            streaming: false,
+            timer: 0,
+            buttons: Buttons::default(),
+            misbehavior: None,
+            commands,
+            handle: ControllerHandle { commands: sender },
+        }
+    }
+
+    pub fn set_report_rate(&mut self, rate: ReportRate) {
+        self.rate = rate;
+    }
+
+    /// Answers every subcommand with `reply.id` with `reply`, instead of the usual answer.
+    pub fn set_reply(&mut self, reply: SubcommandReply) {
+        self.replies.insert(reply.id, reply);
+    }
+
+    /// Starts out misbehaving.
+    pub fn set_misbehavior(&mut self, misbehavior: Misbehavior) {
+        self.misbehavior = Some(misbehavior);
+    }
+
+    pub fn handle(&self) -> ControllerHandle {
+        self.handle.clone()
+    }
+
+    /// Runs until told to disconnect, or until the other end hangs up on itr. Both channels are
+    /// closed on the way out.
+    pub async fn run<T: Transport>(mut self, channels: HidChannels<T>) -> Result<()> {
+        let HidChannels { ctl: _ctl, itr } = channels;
+        let (mut itr_r, mut itr_w) = itr.split();
+
+        let mut incoming = [0u8; 128];
+        let mut next_report = Timer::after(self.rate.period()).fuse();
+
+        loop {
+            let event = futures::select! {
+                read = itr_r.read(&mut incoming).fuse() => Event::Read(read?),
+                // Never runs dry, since we hold a handle ourselves
+                command = self.commands.select_next_some() => Event::Command(command),
+                _ = next_report => Event::Report,
+            };
+
+            let packet = match event {
+                Event::Read(0) => return Ok(()),
+                Event::Read(n) => self.handle_output_report(&incoming[..n]),
+
+                Event::Command(ControllerCommand::Disconnect) => return Ok(()),
+                Event::Command(command) => {
+                    self.handle_command(command);
+                    None
+                }
+
+                Event::Report => {
+                    next_report = Timer::after(self.rate.period()).fuse();
+
+                    if self.streaming && self.misbehavior != Some(Misbehavior::StopReports) {
+                        let imu = ReportBody::Imu {
+                            frames: [ImuFrame::default(); 3],
+                            extra: vec![],
+                        };
+
+                        Some(self.input_report(0x30, imu))
+                    } else {
+                        None
+                    }
+                }
+            };
+
+            if let Some(mut packet) = packet {
+                if let Some(Misbehavior::Truncate(len)) = self.misbehavior {
+                    packet.truncate(len);
+                }
+
+                itr_w.write_all(&packet).await?;
+            }
+        }
+    }
+
+    fn handle_command(&mut self, command: ControllerCommand) {
+        match command {
+            ControllerCommand::SetButtons(buttons) => self.buttons = buttons,
+            ControllerCommand::Misbehave(misbehavior) => self.misbehavior = Some(misbehavior),
+            ControllerCommand::Behave => self.misbehavior = None,
+            ControllerCommand::Disconnect => {}
+        }
+    }
+
+    fn handle_output_report(&mut self, packet: &[u8]) -> Option<Vec<u8>> {
+        let subcommand = match OutputReport::parse_packet(packet) {
+            Ok(OutputReport::RumbleAndSubcommand { subcommand, .. }) => subcommand,
+            _ => return None,
+        };
+
+        let reply = match self.misbehavior {
+            Some(Misbehavior::IgnoreSubcommands) => return None,
+            Some(Misbehavior::NackSubcommands) => SubcommandReply::nack(subcommand.id()),
+            _ => self.reply(&subcommand),
+        };
+
+        if let (Subcommand::SetInputReportMode(mode), true) = (&subcommand, reply.ack != 0) {
+            self.streaming = *mode == 0x30;
+        }
+
+        Some(self.input_report(0x21, ReportBody::SubcommandReply(reply)))
+    }
+
+    fn reply(&self, subcommand: &Subcommand) -> SubcommandReply {
+        let id = subcommand.id();
+
+        if let Some(reply) = self.replies.get(&id) {
+            return reply.clone();
+        }
+
+        match *subcommand {
+            Subcommand::RequestDeviceInfo => {
+                SubcommandReply::device_info(self.controller, self.address)
+            }
+            Subcommand::SpiFlashRead { address, length } => {
+                SubcommandReply::spi_flash_read(&self.spi_flash, address, length)
+            }
+            _ => SubcommandReply::ack(id),
+        }
+    }
+
+    fn input_report(&mut self, id: u8, body: ReportBody) -> Vec<u8> {
+        let report = InputReport::Standard(StandardInputReport {
+            id,
+            timer: self.timer,
+            battery_connection: 0x8E,
+            buttons: self.buttons,
+            left_stick: StickData::CENTER,
+            right_stick: StickData::CENTER,
+            vibrator_report: 0x80,
+            body,
+        });
+
+        self.timer = self.timer.wrapping_add(1);
+
+        report.encode_packet()
+    }
+}
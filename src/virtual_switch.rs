@@ -0,0 +1,323 @@
+//! A stand-in for the Switch, for driving the emulator or a relay without a console.
+//!
+//! It pairs the way the Switch does, then checks the input reports that follow: that they're
+//! full 0x30 reports, and that they keep coming at the rate they should.
+
+use crate::controller::ControllerType;
+use crate::host::Host;
+use crate::input_report::{InputReport, ReportBody, StandardInputReport};
+use crate::spi_flash::{
+    COLORS, FACTORY_IMU_CALIBRATION, FACTORY_STICK_CALIBRATION, IMU_HORIZONTAL_OFFSETS,
+};
+use crate::subcommand::{Subcommand, SubcommandReply};
+use crate::transport::{HidChannels, Transport};
+use crate::BtAddr;
+
+use futures::future::{self, Either};
+use futures::io::{ReadHalf, WriteHalf};
+use futures::prelude::*;
+use smol::Timer;
+
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+/// SPI flash the Switch reads while pairing, before asking for full reports.
+const IDENTITY_READS: &[(u32, u8)] = &[
+    // Serial number
+    (0x6000, 0x10),
+    (COLORS, 0x0D),
+];
+
+/// SPI flash the Switch reads while pairing, once it's asked for full reports.
+const CALIBRATION_READS: &[(u32, u8)] = &[
+    (IMU_HORIZONTAL_OFFSETS, 0x18),
+    // The rest of the stick parameters
+    (0x6098, 0x12),
+    // User stick calibration
+    (0x8010, 0x18),
+    (FACTORY_STICK_CALIBRATION, 0x12),
+    (FACTORY_IMU_CALIBRATION, 0x18),
+    // User IMU calibration
+    (0x8028, 0x18),
+];
+
+/// What a controller told us while pairing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PairingInfo {
+    /// Major and minor firmware version.
+    pub firmware: (u8, u8),
+    /// `None` if the controller claims to be something we don't know about.
+    pub controller: Option<ControllerType>,
+    pub address: BtAddr,
+    /// Everything read from SPI flash, by address.
+    pub spi: BTreeMap<u32, Vec<u8>>,
+}
+
+/// An input report, and how long after the one before it it arrived.
+#[derive(Debug, Clone)]
+pub struct ReceivedReport {
+    pub interval: Duration,
+    pub report: StandardInputReport,
+}
+
+/// Talks to a controller over `T` the way the Switch does.
+pub struct VirtualSwitch<T: Transport> {
+    // Nothing goes over ctl, but the controller expects it to stay open
+    _ctl: T,
+    host: Host<ReadHalf<T>, WriteHalf<T>>,
+    player: u8,
+}
+
+impl<T: Transport> VirtualSwitch<T> {
+    /// Plays the Switch's end of `channels`, as player 1.
+    pub fn new(channels: HidChannels<T>) -> VirtualSwitch<T> {
+        let (itr_r, itr_w) = channels.itr.split();
+
+        VirtualSwitch {
+            _ctl: channels.ctl,
+            host: Host::new(itr_r, itr_w),
+            player: 1,
+        }
+    }
+
+    /// Which player the controller is told it is, from 1 to 4.
+    pub fn set_player(&mut self, player: u8) {
+        self.player = player;
+    }
+
+    /// For anything the pairing sequence doesn't cover.
+    pub fn host(&mut self) -> &mut Host<ReadHalf<T>, WriteHalf<T>> {
+        &mut self.host
+    }
+
+    /// Goes through the pairing sequence: device info, calibration, full reports, IMU, vibration
+    /// and finally the player lights. Every subcommand has to be acknowledged.
+    pub async fn pair(&mut self) -> Result<PairingInfo> {
+        let reply = self.send_subcommand(&Subcommand::RequestDeviceInfo).await?;
+
+        let data = &reply.data;
+
+        if data.len() < 10 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("device info reply is only {} bytes", data.len()),
+            ));
+        }
+
+        let mut address = BtAddr([0; 6]);
+        address.0.copy_from_slice(&data[4..10]);
+
+        let mut info = PairingInfo {
+            firmware: (data[0], data[1]),
+            controller: ControllerType::from_id(data[2]),
+            address,
+            spi: BTreeMap::new(),
+        };
+
+        self.send_subcommand(&Subcommand::SetShipmentState(false))
+            .await?;
+
+        self.read_spi(IDENTITY_READS, &mut info).await?;
+
+        self.send_subcommand(&Subcommand::SetInputReportMode(0x30))
+            .await?;
+        self.send_subcommand(&Subcommand::TriggerButtonsElapsedTime)
+            .await?;
+
+        self.read_spi(CALIBRATION_READS, &mut info).await?;
+
+        self.send_subcommand(&Subcommand::EnableImu(true)).await?;
+        self.send_subcommand(&Subcommand::EnableVibration(true))
+            .await?;
+
+        let lights = 1u8 << (self.player.clamp(1, 4) - 1);
+        self.send_subcommand(&Subcommand::SetPlayerLights(lights))
+            .await?;
+
+        Ok(info)
+    }
+
+    /// Reads `count` full input reports, each of which has to show up within `max_interval` of
+    /// the one before, and have its timer move on. Subcommand replies still on their way are
+    /// skipped.
+    pub async fn expect_input_reports(
+        &mut self,
+        count: usize,
+        max_interval: Duration,
+    ) -> Result<Vec<ReceivedReport>> {
+        let mut reports: Vec<ReceivedReport> = Vec::with_capacity(count);
+        let mut last_report = Instant::now();
+
+        while reports.len() < count {
+            let report = {
+                let read = self.host.read_input_report();
+                futures::pin_mut!(read);
+
+                let remaining = max_interval
+                    .checked_sub(last_report.elapsed())
+                    .unwrap_or_default();
+
+                match future::select(read, Timer::after(remaining)).await {
+                    Either::Left((report, _)) => report?,
+                    Either::Right(_) => {
+                        return Err(Error::new(
+                            ErrorKind::TimedOut,
+                            format!(
+                                "no input report for {:?} after {} reports",
+                                max_interval,
+                                reports.len()
+                            ),
+                        ))
+                    }
+                }
+            };
+
+            let report = match report {
+                InputReport::Standard(report) => report,
+                other => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("expected a full report, got report {:#04x}", other.id()),
+                    ))
+                }
+            };
+
+            match report.body {
+                ReportBody::SubcommandReply(_) => continue,
+                ReportBody::Imu { .. } if report.id == 0x30 => {}
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("expected report 0x30, got report {:#04x}", report.id),
+                    ))
+                }
+            }
+
+            if let Some(previous) = reports.last() {
+                if report.timer == previous.report.timer {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("timer stuck at {:#04x}", report.timer),
+                    ));
+                }
+            }
+
+            reports.push(ReceivedReport {
+                interval: last_report.elapsed(),
+                report,
+            });
+
+            last_report = Instant::now();
+        }
+
+        Ok(reports)
+    }
+
+    async fn send_subcommand(&mut self, subcommand: &Subcommand) -> Result<SubcommandReply> {
+        let reply = self.host.send_subcommand(subcommand).await?;
+
+        if reply.ack & 0x80 == 0 {
+            return Err(Error::other(format!(
+                "controller refused subcommand {:?}",
+                subcommand
+            )));
+        }
+
+        Ok(reply)
+    }
+
+    async fn read_spi(&mut self, reads: &[(u32, u8)], info: &mut PairingInfo) -> Result<()> {
+        for &(address, length) in reads {
+            let data = self.host.read_spi(address, length).await?;
+            info.spi.insert(address, data);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+    use crate::spi_flash::SpiFlash;
+    use crate::transport::MemoryChannel;
+
+    const ADDRESS: BtAddr = BtAddr([0x98, 0xb6, 0xe9, 0x01, 0x02, 0x03]);
+
+    /// Pairs a virtual Switch with an emulated `controller`, then reads `count` input reports.
+    fn pair_with_emulator(
+        controller: ControllerType,
+        count: usize,
+    ) -> Result<(PairingInfo, Vec<ReceivedReport>)> {
+        smol::run(async {
+            let (switch, controller_channels) = HidChannels::pair(MemoryChannel::pair)?;
+            let HidChannels { ctl: _ctl, itr } = controller_channels;
+            let (itr_r, itr_w) = itr.split();
+
+            let mut emulator = Emulator::new(controller, ADDRESS, SpiFlash::new(controller));
+            let emulating = emulator.run(itr_r, itr_w);
+
+            let mut switch = VirtualSwitch::new(switch);
+            let testing = async {
+                let info = switch.pair().await?;
+                // Full reports come every 16ms, so this only allows for a slow test machine. An
+                // emulator still on its idle period would take up to a second.
+                let reports = switch
+                    .expect_input_reports(count, Duration::from_millis(200))
+                    .await?;
+
+                Ok((info, reports))
+            };
+
+            futures::pin_mut!(emulating, testing);
+
+            match future::select(emulating, testing).await {
+                Either::Left((result, _)) => {
+                    result?;
+                    Err(Error::other(
+                        "the emulator stopped before the Switch was done",
+                    ))
+                }
+                Either::Right((result, _)) => result,
+            }
+        })
+    }
+
+    #[test]
+    fn pairs_with_the_emulator() {
+        let (info, _) = pair_with_emulator(ControllerType::ProController, 1).unwrap();
+
+        assert_eq!(info.controller, Some(ControllerType::ProController));
+        assert_eq!(info.address, ADDRESS);
+
+        for &(address, length) in IDENTITY_READS.iter().chain(CALIBRATION_READS) {
+            assert_eq!(info.spi[&address].len(), length as usize);
+        }
+    }
+
+    #[test]
+    fn pairs_with_every_controller_type() {
+        for &controller in ControllerType::ALL.iter() {
+            let (info, _) = pair_with_emulator(controller, 1).unwrap();
+            assert_eq!(info.controller, Some(controller));
+        }
+    }
+
+    #[test]
+    fn emulator_sends_full_reports_once_paired() {
+        let (_, reports) = pair_with_emulator(ControllerType::ProController, 30).unwrap();
+
+        assert_eq!(reports.len(), 30);
+
+        for pair in reports.windows(2) {
+            assert_eq!(pair[0].report.id, 0x30);
+            assert_eq!(
+                pair[1].report.timer,
+                pair[0].report.timer.wrapping_add(1),
+                "timer should go up by one every report"
+            );
+        }
+    }
+}